@@ -1,9 +1,14 @@
 use bytes::Bytes;
-use std::{collections::VecDeque, io::Cursor};
+use std::{
+    collections::VecDeque,
+    io::Cursor,
+    time::{Duration, Instant},
+};
 use symphonia::{
     core::{
-        codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
-        formats::{FormatOptions, FormatReader},
+        audio::{AsAudioBufferRef, AudioBufferRef, SignalSpec},
+        codecs::{DecoderOptions, CODEC_TYPE_NULL},
+        formats::FormatOptions,
         io::MediaSourceStream,
         meta::MetadataOptions,
         probe::Hint,
@@ -13,7 +18,12 @@ use symphonia::{
 use tokio::sync::mpsc::{error::TryRecvError, UnboundedReceiver, UnboundedSender};
 use tracing::{debug, error, info, warn};
 
-use crate::plm::PlmCommand;
+use crate::{
+    decode::{self, DecodeAhead, Decoded},
+    output::{AudioOutput, OutputPath, OutputSelection, SampleFormat},
+    plm::PlmCommand,
+    resample::{self, Resampler},
+};
 
 pub enum AudioCommand {
     EnqueueTrackData { track_id: String, data: Bytes },
@@ -21,6 +31,7 @@ pub enum AudioCommand {
     Pause,
     Play,
     Next,
+    SetOutput(OutputSelection),
 }
 
 #[derive(Eq, PartialEq, Debug, Copy, Clone, Default)]
@@ -32,18 +43,44 @@ pub enum AudioState {
     Paused,
 }
 
+/// How long commands can wait while playing. Output writes block until
+/// the device has room, so between them there's nothing to do but
+/// decode; checking for commands after every packet would be a waste.
+const COMMAND_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How far decoding gets ahead of playback. It's what the output has to
+/// play if decoding stalls, and how long the next track has to get going
+/// before this one ends.
+const DECODE_AHEAD: Duration = Duration::from_secs(3);
+
 pub struct AudioThread {
     rx: UnboundedReceiver<AudioCommand>,
     plm_tx: UnboundedSender<PlmCommand>,
     state: AudioState,
     queue: VecDeque<(String, Bytes)>,
     playing_state_data: Option<PlayingStateData>,
+    /// Off the front of the queue, and decoding, once the track that's
+    /// playing has been decoded
+    up_next: Option<PlayingStateData>,
+    /// Kept from one track to the next, so it's only reopened when the
+    /// audio changes
+    sink: Sink,
+}
+
+/// What the playlist manager has been told, so it's only told again
+/// when something changes
+#[derive(Default)]
+struct Reported {
+    state: AudioState,
+    track_id: Option<String>,
+    path: Option<OutputPath>,
 }
 
 impl AudioThread {
     pub fn new(
         rx: UnboundedReceiver<AudioCommand>,
         notify_tx: UnboundedSender<PlmCommand>,
+        output: OutputSelection,
     ) -> Self {
         Self {
             rx,
@@ -51,155 +88,245 @@ impl AudioThread {
             state: AudioState::Stopped,
             queue: Default::default(),
             playing_state_data: None,
+            up_next: None,
+            sink: Sink::new(output),
         }
     }
 
     pub fn run(mut self) {
         info!("Starting Audio Thread");
-        let mut last_state = self.state;
+        let mut reported = Reported::default();
 
         loop {
-            if last_state != self.state {
-                debug!(
-                    old_state = format!("{:?}", last_state).as_str(),
-                    new_state = format!("{:?}", self.state).as_str(),
-                    "Audio thread state transition"
-                );
-                last_state = self.state;
-
-                if self.state == AudioState::Playing {
-                    if let Some(psd) = &self.playing_state_data {
-                        self.plm_tx
-                            .send(PlmCommand::AudioPlayingTrack {
-                                track_id: psd.track_id.clone(),
-                            })
-                            .unwrap();
-                    }
+            self.report(&mut reported);
+
+            if self.state != AudioState::Playing {
+                match self.rx.blocking_recv() {
+                    Some(cmd) => self.handle(cmd),
+                    None => return,
+                }
+                continue;
+            }
+
+            // Whatever came in while the last lot was playing
+            loop {
+                match self.rx.try_recv() {
+                    Ok(cmd) => self.handle(cmd),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
                 }
+            }
+            if self.state == AudioState::Playing {
+                self.play_for(COMMAND_INTERVAL);
+            }
+        }
+    }
+
+    /// Tell the playlist manager about anything that's changed since
+    /// the last time
+    fn report(&mut self, reported: &mut Reported) {
+        let state_changed = reported.state != self.state;
+        if state_changed {
+            debug!(
+                old_state = ?reported.state,
+                new_state = ?self.state,
+                "Audio thread state transition"
+            );
+            reported.state = self.state;
+        }
+
+        // Tracks can change without the state doing so
+        if self.state == AudioState::Playing {
+            let track_id = self.playing_state_data.as_ref().map(|psd| &psd.track_id);
+            if track_id.is_some() && track_id != reported.track_id.as_ref() {
+                reported.track_id = track_id.cloned();
                 self.plm_tx
-                    .send(PlmCommand::AudioState { state: self.state })
+                    .send(PlmCommand::AudioPlayingTrack {
+                        track_id: reported.track_id.clone().unwrap(),
+                    })
                     .unwrap();
             }
+        } else if self.playing_state_data.is_none() {
+            reported.track_id = None;
+        }
 
-            // do a non-blocking recieve when we're in a playing state
-            let recv_res = if self.state == AudioState::Playing {
-                self.rx.try_recv()
-            } else {
-                match self.rx.blocking_recv() {
-                    Some(cmd) => Ok(cmd),
-                    None => Err(TryRecvError::Disconnected),
+        if state_changed {
+            self.plm_tx
+                .send(PlmCommand::AudioState { state: self.state })
+                .unwrap();
+        }
+
+        let path = match &self.playing_state_data {
+            // A track's output opens with its first packet. Until
+            // then, it's whatever the last track's was.
+            Some(_) => self.sink.path().or(reported.path),
+            None => None,
+        };
+        if path != reported.path {
+            reported.path = path;
+            self.plm_tx
+                .send(PlmCommand::AudioOutputPath { path })
+                .unwrap();
+        }
+    }
+
+    fn handle(&mut self, cmd: AudioCommand) {
+        match cmd {
+            AudioCommand::EnqueueTrackData { track_id, data } => {
+                self.queue.push_back((track_id, data));
+                if self.state == AudioState::WillPlayWhenDataArrives {
+                    self.state = self.play_next_track();
                 }
-            };
+            }
 
-            match recv_res {
-                Ok(cmd) => match cmd {
-                    AudioCommand::EnqueueTrackData { track_id, data } => {
-                        self.queue.push_back((track_id, data));
-                        if self.state == AudioState::WillPlayWhenDataArrives {
-                            self.state = self.play_next_track();
-                        }
-                    }
+            AudioCommand::Stop => {
+                self.queue.clear();
+                self.up_next = None;
+                self.playing_state_data = None;
+                self.sink.close();
+                self.state = AudioState::Stopped;
+            }
 
-                    AudioCommand::Stop => {
-                        self.queue.clear();
-                        self.playing_state_data = None;
-                        self.state = AudioState::Stopped;
-                    }
+            AudioCommand::Pause => match self.state {
+                AudioState::Stopped => (),
+                AudioState::Playing | AudioState::WillPlayWhenDataArrives | AudioState::Paused => {
+                    self.state = AudioState::Paused
+                }
+            },
 
-                    AudioCommand::Pause => match self.state {
-                        AudioState::Stopped => (),
-                        AudioState::Playing
-                        | AudioState::WillPlayWhenDataArrives
-                        | AudioState::Paused => self.state = AudioState::Paused,
-                    },
-
-                    AudioCommand::Play => match self.state {
-                        AudioState::Stopped => {
-                            self.state = self.play_next_track();
-                        }
-                        AudioState::Playing => (),
-                        AudioState::WillPlayWhenDataArrives => (),
-                        AudioState::Paused => {
-                            self.state = AudioState::Playing;
-                        }
-                    },
-
-                    AudioCommand::Next => {
-                        // TODO update playing state data
-                        // self.playing_state_data = None;
-                        self.queue.pop_front();
-                        self.play_next_track();
-                        // if self.queue.is_empty() {
-                        //     self.playing_state_data = None;
-                        //     self.state = AudioState::Stopped;
-                        // }
-                    }
-                },
-
-                Err(TryRecvError::Disconnected) => return,
-
-                // No commands? play some audio.
-                Err(TryRecvError::Empty) => {
-                    let _psd = match self.playing_state_data {
-                        Some(ref mut psd) => {
-                            if !psd.process() {
-                                self.plm_tx
-                                    .send(PlmCommand::AudioFinishedTrack {
-                                        track_id: psd.track_id.clone(),
-                                    })
-                                    .unwrap();
-                                // TODO preserve the audio output from psd
-                                self.state = self.play_next_track();
-                            }
-                        }
-                        None => {
-                            error!(
-                                "Somehow we're in the playing state, with no \
-                                 audio player data. Stopping."
-                            );
-                            self.state = AudioState::Stopped;
-                        }
+            AudioCommand::Play => match self.state {
+                AudioState::Stopped => {
+                    self.state = self.play_next_track();
+                }
+                AudioState::Playing => (),
+                AudioState::WillPlayWhenDataArrives => (),
+                AudioState::Paused => {
+                    self.state = AudioState::Playing;
+                }
+            },
+
+            AudioCommand::Next => {
+                if self.state != AudioState::Stopped {
+                    // Whatever's playing was popped off the queue
+                    // when it started, so the next track is first.
+                    self.playing_state_data = None;
+                    self.state = match (self.state, self.play_next_track()) {
+                        (AudioState::Paused, AudioState::Playing) => AudioState::Paused,
+                        (_, next) => next,
                     };
                 }
             }
+
+            AudioCommand::SetOutput(output) => {
+                // The next packet opens the new one
+                self.sink.set_selection(output);
+            }
+        }
+    }
+
+    /// Play until `interval` has gone by, or the track's over, so
+    /// whoever's listening hears about the next one.
+    fn play_for(&mut self, interval: Duration) {
+        let until = Instant::now() + interval;
+        while Instant::now() < until {
+            let psd = match &mut self.playing_state_data {
+                Some(psd) => psd,
+                None => {
+                    error!(
+                        "Somehow we're in the playing state, with no \
+                         audio player data. Stopping."
+                    );
+                    self.state = AudioState::Stopped;
+                    return;
+                }
+            };
+
+            match psd.process(&mut self.sink, until) {
+                Processed::Continue => {
+                    if psd.decoded.is_finished() && self.up_next.is_none() {
+                        self.decode_next();
+                    }
+                }
+                Processed::Finished => {
+                    self.plm_tx
+                        .send(PlmCommand::AudioFinishedTrack {
+                            track_id: psd.track_id.clone(),
+                        })
+                        .unwrap();
+                    self.state = self.play_next_track();
+                    if self.state != AudioState::Playing {
+                        // Nothing follows straight on, so play out the end
+                        self.sink.finish();
+                    }
+                    return;
+                }
+                Processed::Failed(reason) => {
+                    self.plm_tx
+                        .send(PlmCommand::AudioSkippedTrack {
+                            track_id: psd.track_id.clone(),
+                            reason,
+                        })
+                        .unwrap();
+                    // Don't keep a broken output around
+                    self.playing_state_data = None;
+                    self.sink.close();
+                    self.state = self.play_next_track();
+                    return;
+                }
+            }
         }
     }
 
     fn play_next_track(&mut self) -> AudioState {
-        loop {
+        self.decode_next();
+        match self.up_next.take() {
+            Some(psd) => {
+                self.playing_state_data = Some(psd);
+                AudioState::Playing
+            }
+            None => AudioState::WillPlayWhenDataArrives,
+        }
+    }
+
+    /// Start decoding the next playable track in the queue, if there
+    /// isn't one going already
+    fn decode_next(&mut self) {
+        while self.up_next.is_none() {
             let (track_id, buf) = match self.queue.pop_front() {
                 Some(buf) => buf,
-                None => {
-                    return AudioState::WillPlayWhenDataArrives;
-                }
+                None => return,
             };
             match PlayingStateData::new_for_track(track_id.clone(), buf) {
-                Some(psd) => {
-                    self.playing_state_data = Some(psd);
-                    return AudioState::Playing;
-                }
-                None => {
-                    warn!("Skipped unplayable track");
-                    self.plm_tx.send(PlmCommand::AudioSkippedTrack { track_id }).unwrap();
+                Ok(psd) => self.up_next = Some(psd),
+                Err(reason) => {
+                    warn!("Skipped unplayable track: {reason}");
+                    self.plm_tx
+                        .send(PlmCommand::AudioSkippedTrack { track_id, reason })
+                        .unwrap();
                 }
             }
         }
     }
 }
 
-/// All the state needed for actually playing audio, configured to
-/// work against a single buffer at a time.
+/// What happened when a packet was played
+enum Processed {
+    Continue,
+    /// The end of the track (or something we can't read past).
+    Finished,
+    /// Playback can't go on. The track should be skipped.
+    Failed(String),
+}
+
+/// A track being decoded, to play through the sink.
 struct PlayingStateData {
     track_id: String,
-    reader: Box<dyn FormatReader>,
-    audio_track_id: u32,
-    decoder: Box<dyn Decoder>,
-    audio_output: Option<Box<dyn crate::output::AudioOutput>>,
+    decoded: DecodeAhead,
 }
 
 impl PlayingStateData {
-    /// Returns none if the track can't be played
-    fn new_for_track(track_id: String, data: Bytes) -> Option<Self> {
+    /// Returns the reason if the track can't be played
+    fn new_for_track(track_id: String, data: Bytes) -> Result<Self, String> {
         let mss = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
         let format_opts = FormatOptions {
             enable_gapless: true,
@@ -209,89 +336,216 @@ impl PlayingStateData {
         let hint = Hint::new();
         let probe_res = match get_probe().format(&hint, mss, &format_opts, &metadata_opts) {
             Ok(probed) => probed,
-            Err(err) => {
-                warn!("file not supported. reason? {}", err);
-                return None;
-            }
+            Err(err) => return Err(format!("file not supported: {err}")),
         };
 
         let reader = probe_res.format;
         let audio_track = match first_supported_track(reader.tracks()) {
             Some(t) => t,
-            None => {
-                warn!("no supported tracks");
-                return None;
-            }
+            None => return Err("no supported tracks".to_string()),
         };
         let audio_track_id = audio_track.id;
 
         let decode_opts = DecoderOptions::default();
         let decoder = get_codecs()
             .make(&audio_track.codec_params, &decode_opts)
-            .unwrap();
+            .map_err(|e| format!("can't decode: {e}"))?;
 
-        Some(PlayingStateData {
+        Ok(PlayingStateData {
             track_id,
-            reader,
-            audio_output: None,
-            audio_track_id,
-            decoder,
+            decoded: DecodeAhead::spawn(reader, decoder, audio_track_id, DECODE_AHEAD),
         })
     }
 
-    /// Process one packet of audio. Return true if everything is fine enough,
-    /// or false is something is wrong and we can't play this track
-    /// anymore.
-    fn process(&mut self) -> bool {
-        // No command; run audio.
-        let packet = loop {
-            match self.reader.next_packet() {
-                Ok(p) => {
-                    // If the packet does not belong to the selected track, skip it.
-                    if p.track_id() == self.audio_track_id {
-                        break p;
-                    }
-                }
-                Err(e) => {
-                    // TODO don't print the end of stream error
-                    warn!("reader error: {e}");
-                    return false;
-                }
-            };
+    /// Play one packet of audio into `sink`, if the decoder has one by
+    /// `until`.
+    fn process(&mut self, sink: &mut Sink, until: Instant) -> Processed {
+        let decoded = match self.decoded.next(until) {
+            Some(Decoded::Audio(decoded)) => decoded,
+            Some(Decoded::End) => {
+                return match sink.finish_track() {
+                    Ok(()) => Processed::Finished,
+                    Err(reason) => Processed::Failed(reason),
+                };
+            }
+            None => {
+                debug!(track_id = self.track_id.as_str(), "Decoding fell behind");
+                return Processed::Continue;
+            }
         };
 
-        let decoded = match self.decoder.decode(&packet) {
-            Ok(d) => d,
-            Err(e) => {
-                warn!("decode error: {}", e);
-                return true;
+        let written = sink.write(decode::borrowed(&decoded));
+        self.decoded.recycle(decoded);
+        match written {
+            Ok(()) => Processed::Continue,
+            Err(reason) => Processed::Failed(reason),
+        }
+    }
+}
+
+/// The audio output, and what it takes to get decoded audio into it.
+/// The output's opened for a particular spec and packet size, so when
+/// the audio changes, it's reopened to match, unless the rate is fixed,
+/// in which case the audio's resampled to that. For bit-perfect
+/// playback, it's opened in the audio's own sample format too.
+struct Sink {
+    selection: OutputSelection,
+    open: Option<OpenOutput>,
+}
+
+struct OpenOutput {
+    output: Box<dyn AudioOutput>,
+    /// What it was opened with
+    spec: SignalSpec,
+    format: SampleFormat,
+    capacity: u64,
+    /// From the audio's rate, when that isn't the output's
+    resampler: Option<(u32, Resampler)>,
+    /// Whether the output takes the audio as it was decoded, and
+    /// passes it on untouched
+    bit_perfect: bool,
+}
+
+impl Sink {
+    fn new(selection: OutputSelection) -> Sink {
+        Sink {
+            selection,
+            open: None,
+        }
+    }
+
+    /// Play to `selection` from the next packet on. The old output
+    /// plays out what it has, so nothing's skipped.
+    fn set_selection(&mut self, selection: OutputSelection) {
+        self.finish();
+        self.selection = selection;
+    }
+
+    /// Play out what the output has, and close it
+    fn finish(&mut self) {
+        if let Some(old) = self.open.take() {
+            if let Err(reason) = old.finish() {
+                warn!("Lost the end of the old output: {reason}");
             }
+        }
+    }
+
+    /// Close the output straight away, dropping whatever it has left
+    fn close(&mut self) {
+        self.open = None;
+    }
+
+    /// Returns the reason if playback can't go on
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<(), String> {
+        let spec = *decoded.spec();
+        let bit_perfect = self.selection.bit_perfect();
+        let fixed_rate = self.selection.fixed_rate().filter(|_| !bit_perfect);
+        let rate = fixed_rate.unwrap_or(spec.rate);
+        let wanted = SignalSpec::new(rate, spec.channels);
+        // Resampling's done in floats, and some outputs only take those
+        let format = if bit_perfect {
+            SampleFormat::of(&decoded)
+        } else {
+            SampleFormat::F32
+        };
+        let capacity = if rate == spec.rate {
+            decoded.capacity() as u64
+        } else {
+            resample::output_frames(decoded.capacity() as u64, spec.rate, rate)
         };
 
-        // If the audio output is not open, try to open it.
-        if self.audio_output.is_none() {
-            // Get the audio buffer specification. This is a description of the decoded
-            // audio buffer's sample format and sample rate.
-            let spec = *decoded.spec();
+        let fits = self.open.as_ref().is_some_and(|open| {
+            open.spec == wanted && open.format == format && capacity <= open.capacity
+        });
+        if !fits {
+            if let Some(old) = self.open.take() {
+                debug!(
+                    old = ?old.spec,
+                    new = ?wanted,
+                    "Audio changed, reopening the output"
+                );
+                old.finish()?;
+            }
+            // At a fixed rate, leave room for packets of other rates
+            // so the output can stay open.
+            let capacity = match fixed_rate {
+                Some(_) => capacity * 2,
+                None => capacity,
+            };
+            let output = crate::output::open(&self.selection, wanted, format, capacity)
+                .map_err(|e| format!("can't open audio output: {e:?}"))?;
+            self.open = Some(OpenOutput {
+                bit_perfect: output.is_bit_perfect() && format == SampleFormat::of(&decoded),
+                output,
+                spec: wanted,
+                format,
+                capacity,
+                resampler: None,
+            });
+        }
 
-            // Get the capacity of the decoded buffer. Note that this is capacity, not
-            // length! The capacity of the decoded buffer is constant for the life of the
-            // decoder, but the length is not.
-            let duration = decoded.capacity() as u64;
+        self.open.as_mut().unwrap().write(decoded)
+    }
 
-            // Try to open the audio output.
-            self.audio_output
-                .replace(crate::output::try_open(spec, duration).unwrap());
+    /// What's happening to what's written, once there's an output
+    fn path(&self) -> Option<OutputPath> {
+        let open = self.open.as_ref()?;
+        if open.bit_perfect && open.resampler.is_none() {
+            Some(OutputPath::BitPerfect)
         } else {
-            // TODO: Check the audio spec. and duration hasn't changed.
+            Some(OutputPath::Converted)
         }
+    }
 
-        if let Some(audio_output) = &mut self.audio_output {
-            // TODO error here?
-            audio_output.write(decoded).unwrap();
+    /// The track's over; let out anything that's held back
+    fn finish_track(&mut self) -> Result<(), String> {
+        match &mut self.open {
+            Some(open) => open.finish_resampling(),
+            None => Ok(()),
         }
+    }
+}
+
+impl OpenOutput {
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<(), String> {
+        let rate = decoded.spec().rate;
+        if rate == self.spec.rate {
+            self.finish_resampling()?;
+            return self
+                .output
+                .write(decoded)
+                .map_err(|e| format!("audio output failed: {e:?}"));
+        }
+
+        let reusable = self.resampler.as_ref().is_some_and(|(from, resampler)| {
+            *from == rate && resampler.input_capacity() >= decoded.capacity()
+        });
+        if !reusable {
+            self.finish_resampling()?;
+            let resampler = Resampler::new(rate, self.spec, decoded.capacity() as u64);
+            self.resampler = Some((rate, resampler));
+        }
+
+        let (_, resampler) = self.resampler.as_mut().unwrap();
+        self.output
+            .write(resampler.process(decoded).as_audio_buffer_ref())
+            .map_err(|e| format!("audio output failed: {e:?}"))
+    }
+
+    fn finish_resampling(&mut self) -> Result<(), String> {
+        match self.resampler.take() {
+            Some((_, mut resampler)) => self
+                .output
+                .write(resampler.flush().as_audio_buffer_ref())
+                .map_err(|e| format!("audio output failed: {e:?}")),
+            None => Ok(()),
+        }
+    }
 
-        true
+    fn finish(mut self) -> Result<(), String> {
+        self.finish_resampling()?;
+        self.output.flush();
+        Ok(())
     }
 }
 
@@ -302,3 +556,198 @@ fn first_supported_track(
         .iter()
         .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::{test::ramp, Capture};
+    use symphonia::core::audio::{AudioBuffer, Channels, SampleBuffer, Signal};
+
+    const STEREO: Channels = Channels::FRONT_LEFT.union(Channels::FRONT_RIGHT);
+
+    #[test]
+    fn reopens_when_the_audio_changes() {
+        let capture = Capture::new();
+        let mut sink = Sink::new(OutputSelection::Capture(capture.clone()));
+
+        for (rate, channels, frames) in [
+            (44100, STEREO, 4),
+            (44100, STEREO, 2),
+            (48000, STEREO, 4),
+            (48000, Channels::FRONT_LEFT, 4),
+            // A bigger packet than the output was opened for
+            (48000, Channels::FRONT_LEFT, 8),
+        ] {
+            let spec = SignalSpec::new(rate, channels);
+            sink.write(ramp(spec, frames).as_audio_buffer_ref())
+                .unwrap();
+        }
+        sink.finish_track().unwrap();
+
+        let streams: Vec<_> = capture
+            .streams()
+            .iter()
+            .map(|s| (s.rate, s.channels, s.samples.len() / s.channels))
+            .collect();
+        assert_eq!(
+            streams,
+            [(44100, 2, 6), (48000, 2, 4), (48000, 1, 4), (48000, 1, 8)]
+        );
+    }
+
+    #[test]
+    fn fixed_rate_resamples_instead() {
+        let capture = Capture::new().at_fixed_rate(48000);
+        let mut sink = Sink::new(OutputSelection::Capture(capture.clone()));
+
+        let at_48k = ramp(SignalSpec::new(48000, STEREO), 480);
+        sink.write(at_48k.as_audio_buffer_ref()).unwrap();
+        for _ in 0..10 {
+            let at_44k = ramp(SignalSpec::new(44100, STEREO), 441);
+            sink.write(at_44k.as_audio_buffer_ref()).unwrap();
+        }
+        sink.finish_track().unwrap();
+
+        let streams = capture.streams();
+        assert_eq!(streams.len(), 1);
+        assert_eq!((streams[0].rate, streams[0].channels), (48000, 2));
+        // What's at the right rate already goes through untouched
+        let mut untouched = SampleBuffer::<f32>::new(480, *at_48k.spec());
+        untouched.copy_interleaved_ref(at_48k.as_audio_buffer_ref());
+        assert!(streams[0].samples[..960] == *untouched.samples());
+        let frames = streams[0].samples.len() / 2;
+        assert!((frames as i64 - 480 - 4800).abs() <= 1, "{frames}");
+    }
+
+    #[test]
+    fn bit_perfect_plays_the_audio_as_it_is() {
+        let spec = SignalSpec::new(44100, STEREO);
+        let mut pcm = AudioBuffer::<i16>::new(4, spec);
+        pcm.render_reserved(Some(4));
+        pcm.chan_mut(0).copy_from_slice(&[0, 1, -1, i16::MAX]);
+
+        // Everything's converted to floats, and nothing claims otherwise
+        let capture = Capture::new();
+        let mut sink = Sink::new(OutputSelection::Capture(capture.clone()));
+        assert_eq!(sink.path(), None);
+        sink.write(pcm.as_audio_buffer_ref()).unwrap();
+        assert_eq!(sink.path(), Some(OutputPath::Converted));
+        assert_eq!(capture.streams()[0].format, SampleFormat::F32);
+
+        // The fixed rate doesn't apply
+        let capture = Capture::new().at_fixed_rate(48000).bit_perfect();
+        let mut sink = Sink::new(OutputSelection::Capture(capture.clone()));
+        sink.write(pcm.as_audio_buffer_ref()).unwrap();
+        assert_eq!(sink.path(), Some(OutputPath::BitPerfect));
+        sink.write(ramp(spec, 4).as_audio_buffer_ref()).unwrap();
+        assert_eq!(sink.path(), Some(OutputPath::BitPerfect));
+        sink.finish_track().unwrap();
+
+        let streams: Vec<_> = capture
+            .streams()
+            .iter()
+            .map(|s| (s.rate, s.format, s.samples.len() / s.channels))
+            .collect();
+        assert_eq!(
+            streams,
+            [(44100, SampleFormat::S16, 4), (44100, SampleFormat::F32, 4)]
+        );
+    }
+
+    #[test]
+    fn tracks_share_the_output() {
+        let capture = Capture::new();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (plm_tx, mut plm_rx) = tokio::sync::mpsc::unbounded_channel();
+        let thread = std::thread::spawn({
+            let output = OutputSelection::Capture(capture.clone());
+            move || AudioThread::new(rx, plm_tx, output).run()
+        });
+
+        let library = subsonic_mock::Library::fixture();
+        let data = library.song("tr-8").unwrap().data();
+        for track_id in ["1", "2"] {
+            tx.send(AudioCommand::EnqueueTrackData {
+                track_id: track_id.to_string(),
+                data: Bytes::from_static(data),
+            })
+            .unwrap();
+        }
+        tx.send(AudioCommand::Play).unwrap();
+        let mut finished = vec![];
+        while let Some(cmd) = plm_rx.blocking_recv() {
+            match cmd {
+                PlmCommand::AudioFinishedTrack { track_id } => finished.push(track_id),
+                PlmCommand::AudioState {
+                    state: AudioState::WillPlayWhenDataArrives,
+                } => break,
+                _ => (),
+            }
+        }
+        drop(tx);
+        thread.join().unwrap();
+
+        // Both of them, one after the other, without reopening
+        assert_eq!(finished, ["1", "2"]);
+        let streams = capture.streams();
+        assert_eq!(streams.len(), 1);
+        let (first, second) = streams[0].samples.split_at(streams[0].samples.len() / 2);
+        assert!(!first.is_empty() && first == second);
+    }
+
+    /// CPU time the whole process has had so far
+    fn cpu_time() -> Duration {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+        let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+        time(usage.ru_utime) + time(usage.ru_stime)
+    }
+
+    /// How much CPU playback takes, with the output holding it to the
+    /// speed a sound card would. Only worth running in release:
+    /// `cargo test --release cpu_per_minute -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn cpu_per_minute_of_playback() {
+        // Half a second each
+        const TRACKS: usize = 20;
+
+        let capture = Capture::new().paced();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (plm_tx, mut plm_rx) = tokio::sync::mpsc::unbounded_channel();
+        let thread = std::thread::spawn({
+            let output = OutputSelection::Capture(capture.clone());
+            move || AudioThread::new(rx, plm_tx, output).run()
+        });
+
+        let library = subsonic_mock::Library::fixture();
+        let data = library.song("tr-2").unwrap().data();
+        for n in 0..TRACKS {
+            tx.send(AudioCommand::EnqueueTrackData {
+                track_id: n.to_string(),
+                data: Bytes::from_static(data),
+            })
+            .unwrap();
+        }
+
+        let started = cpu_time();
+        tx.send(AudioCommand::Play).unwrap();
+        while let Some(cmd) = plm_rx.blocking_recv() {
+            if let PlmCommand::AudioState {
+                state: AudioState::WillPlayWhenDataArrives,
+            } = cmd
+            {
+                break;
+            }
+        }
+        let used = cpu_time() - started;
+        drop(tx);
+        thread.join().unwrap();
+
+        let played = capture.frames() as f64 / capture.streams()[0].rate as f64;
+        let per_minute = used.as_secs_f64() * 60.0 / played;
+        println!("{per_minute:.3}s of CPU per minute of playback, over {played:.1}s");
+        // Waiting on the output busily would take most of the minute
+        assert!(per_minute < 6.0, "{per_minute}");
+    }
+}
@@ -0,0 +1,197 @@
+//! Tracks kept on disk once they've been downloaded, so playing them
+//! again doesn't mean downloading them again. When there's too much,
+//! whatever was played longest ago goes.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use tracing::warn;
+
+/// Where a track's data came from, and how. The same id on another
+/// server, or transcoded differently, is different data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheKey {
+    /// Who's asking, and where, as in `alice@https://music.example.com`
+    pub server: String,
+    pub track_id: String,
+    /// What the server transcodes to, if anything
+    pub format: Option<String>,
+    pub max_bit_rate: Option<usize>,
+}
+
+impl CacheKey {
+    fn file_name(&self) -> String {
+        file_name(&format!(
+            "{}\n{}\n{}\n{}",
+            self.server,
+            self.track_id,
+            self.format.as_deref().unwrap_or_default(),
+            self.max_bit_rate.unwrap_or_default()
+        ))
+    }
+}
+
+/// A file name for `key`. Ids and urls can be any length, and have
+/// anything in them, so it's a hash of them.
+pub fn file_name(key: &str) -> String {
+    // 64 bit FNV-1a. It's stable, unlike std's hasher.
+    let hash = key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// Write `data` to `path`, making its directory if need be. It's
+/// moved into place once it's all there, so nothing ever reads half a
+/// file.
+pub async fn write_whole(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let partial = path.with_extension("part");
+    tokio::fs::write(&partial, data).await?;
+    tokio::fs::rename(&partial, path).await
+}
+
+/// A directory of track files, up to a size. When a file was last
+/// modified is when it was last used.
+pub struct TrackCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl TrackCache {
+    /// The cache in the usual place for this system, if there is one
+    pub fn open_default(max_size: u64) -> Option<TrackCache> {
+        let dir = dirs::cache_dir()?.join("tinysonic").join("tracks");
+        Some(TrackCache::new(dir, max_size))
+    }
+
+    /// Files in `dir`, adding up to at most `max_size` bytes
+    pub fn new(dir: PathBuf, max_size: u64) -> TrackCache {
+        TrackCache { dir, max_size }
+    }
+
+    /// The track's data, if it's been kept. That counts as using it.
+    pub async fn get(&self, key: &CacheKey) -> Option<Bytes> {
+        let path = self.dir.join(key.file_name());
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Can't read {} from the cache: {e}", path.display());
+                return None;
+            }
+        };
+
+        let touched = match tokio::fs::OpenOptions::new().write(true).open(&path).await {
+            Ok(file) => file.into_std().await.set_modified(SystemTime::now()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = touched {
+            warn!("Can't mark {} as used: {e}", path.display());
+        }
+        Some(Bytes::from(data))
+    }
+
+    /// Keep the track's data for next time, making room for it
+    pub async fn put(&self, key: &CacheKey, data: &[u8]) -> io::Result<()> {
+        if data.len() as u64 > self.max_size {
+            return Ok(());
+        }
+
+        write_whole(&self.dir.join(key.file_name()), data).await?;
+        self.make_room().await
+    }
+
+    /// Remove the least recently used files, until the rest fit
+    async fn make_room(&self) -> io::Result<()> {
+        let mut files = vec![];
+        let mut total = 0;
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let metadata = entry.metadata().await?;
+            // Anything still being written is left alone
+            if !metadata.is_file() || path.extension().is_some() {
+                continue;
+            }
+            total += metadata.len();
+            files.push((metadata.modified()?, metadata.len(), path));
+        }
+
+        files.sort();
+        for (_, size, path) in files {
+            if total <= self.max_size {
+                break;
+            }
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => total -= size,
+                Err(e) => warn!("Can't remove {} from the cache: {e}", path.display()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn key(track_id: &str) -> CacheKey {
+        CacheKey {
+            server: "alice@http://localhost:4533".to_string(),
+            track_id: track_id.to_string(),
+            format: None,
+            max_bit_rate: None,
+        }
+    }
+
+    #[test]
+    fn keys_differ_by_everything() {
+        let transcoded = CacheKey {
+            format: Some("opus".to_string()),
+            ..key("tr-1")
+        };
+        let elsewhere = CacheKey {
+            server: "bob@http://localhost:4533".to_string(),
+            ..key("tr-1")
+        };
+        let names = [&key("tr-1"), &key("tr-2"), &transcoded, &elsewhere].map(CacheKey::file_name);
+        for (i, name) in names.iter().enumerate() {
+            assert!(!names[i + 1..].contains(name), "{names:?}");
+        }
+        assert_eq!(key("tr-1").file_name(), key("tr-1").file_name());
+    }
+
+    #[tokio::test]
+    async fn forgets_the_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TrackCache::new(dir.path().join("tracks"), 10);
+        // File times can be coarser than you'd think
+        let tick = || tokio::time::sleep(Duration::from_millis(20));
+
+        assert_eq!(cache.get(&key("a")).await, None);
+        cache.put(&key("a"), b"aaaa").await.unwrap();
+        tick().await;
+        cache.put(&key("b"), b"bbbb").await.unwrap();
+        tick().await;
+        assert_eq!(cache.get(&key("a")).await.as_deref(), Some(&b"aaaa"[..]));
+        tick().await;
+        cache.put(&key("c"), b"cccc").await.unwrap();
+
+        assert_eq!(cache.get(&key("b")).await, None);
+        assert!(cache.get(&key("a")).await.is_some());
+        assert!(cache.get(&key("c")).await.is_some());
+
+        // Too big to keep at all, and nothing's thrown out trying
+        cache.put(&key("d"), &[0; 11]).await.unwrap();
+        assert_eq!(cache.get(&key("d")).await, None);
+        assert!(cache.get(&key("a")).await.is_some());
+    }
+}
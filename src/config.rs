@@ -0,0 +1,483 @@
+use std::path::PathBuf;
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+
+use crate::keyring::KeyringEntry;
+
+/// What a config file from before profiles existed is migrated to
+const LEGACY_PROFILE_NAME: &str = "default";
+
+/// The rates `fixed_rate` can be, from telephone quality to the most
+/// any DAC does
+const FIXED_RATES: std::ops::RangeInclusive<u32> = 8_000..=768_000;
+
+/// The config file: any number of server profiles, one of which is
+/// opened at startup.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Config {
+    /// The profile to open at startup. Without one, it's the first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+
+    #[serde(default, rename = "profile", skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<Profile>,
+
+    #[serde(default, skip_serializing_if = "AudioSettings::is_default")]
+    pub audio: AudioSettings,
+
+    #[serde(default, skip_serializing_if = "CacheSettings::is_default")]
+    pub cache: CacheSettings,
+}
+
+/// Where playback goes
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AudioSettings {
+    /// The outputs to try, in order, until one opens. Empty means the
+    /// sound servers this was built with support for, in the order of
+    /// [`OutputKind::SOUND_SERVERS`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<OutputKind>,
+
+    /// Where the `file` output writes to, one file per track. Without
+    /// one, it can't open.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    pub file_format: FileFormat,
+
+    /// The device to play to, by the name the output knows it by:
+    /// a sink for the sound servers, a pcm for ALSA. None is the
+    /// system default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+
+    /// Play everything at this rate, resampling what isn't, for devices
+    /// that glitch when the rate changes. None plays each track at its
+    /// own rate.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "fixed_rate"
+    )]
+    pub fixed_rate: Option<u32>,
+
+    /// Hand the device each track's samples as they are: at its own
+    /// rate, which overrides `fixed_rate`, and in its own format.
+    /// Whether they get there untouched is up to the output.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bit_perfect: bool,
+}
+
+fn fixed_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    match Option::<u32>::deserialize(deserializer)? {
+        Some(rate) if !FIXED_RATES.contains(&rate) => Err(de::Error::custom(format!(
+            "fixed_rate should be between {} and {}, not {rate}",
+            FIXED_RATES.start(),
+            FIXED_RATES.end()
+        ))),
+        rate => Ok(rate),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputKind {
+    Pipewire,
+    Pulseaudio,
+    Alsa,
+    Cpal,
+    /// Files in `file_dir`, written in real time
+    File,
+    /// Nowhere, in real time
+    Null,
+}
+
+impl AudioSettings {
+    fn is_default(&self) -> bool {
+        *self == AudioSettings::default()
+    }
+}
+
+impl OutputKind {
+    pub const SOUND_SERVERS: [OutputKind; 4] = [
+        OutputKind::Pipewire,
+        OutputKind::Pulseaudio,
+        OutputKind::Alsa,
+        OutputKind::Cpal,
+    ];
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// 32 bit float
+    #[default]
+    Wav,
+    /// 24 bit, uncompressed
+    Flac,
+}
+
+/// Tracks kept on disk after they're downloaded
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CacheSettings {
+    /// How much to keep, in megabytes. 0 keeps nothing.
+    #[serde(default = "CacheSettings::default_max_size_mb")]
+    pub max_size_mb: u64,
+}
+
+impl CacheSettings {
+    fn default_max_size_mb() -> u64 {
+        2048
+    }
+
+    fn is_default(&self) -> bool {
+        *self == CacheSettings::default()
+    }
+
+    /// The most to keep, in bytes
+    pub fn max_size(&self) -> u64 {
+        self.max_size_mb * 1024 * 1024
+    }
+}
+
+impl Default for CacheSettings {
+    fn default() -> CacheSettings {
+        CacheSettings {
+            max_size_mb: CacheSettings::default_max_size_mb(),
+        }
+    }
+}
+
+/// Everything needed to talk to one server
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Profile {
+    pub name: String,
+    pub url: String,
+    pub username: String,
+
+    #[serde(default, skip_serializing_if = "AuthMethod::is_token")]
+    pub auth: AuthMethod,
+
+    /// A plaintext password. This is only read when the profile is
+    /// opened, to move it into the system keyring.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    /// Where the password is kept in the system keyring.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyring: Option<KeyringEntry>,
+
+    /// The music folders that browsing is scoped to. Empty means all
+    /// of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub music_folder_ids: Vec<i64>,
+
+    /// How the album list was sorted last time, as it names the order:
+    /// "random", "by_year" and so on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_sort: Option<String>,
+
+    /// The years the album list was last browsed by, from and to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_years: Option<(u16, u16)>,
+
+    #[serde(flatten)]
+    pub connection: ConnectionSettings,
+}
+
+/// How the password is sent to the server
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    /// A salted hash of it. If the server can't check those, the
+    /// password itself is sent instead.
+    #[default]
+    Token,
+    /// The password itself
+    Password,
+    /// The password is really an OpenSubsonic API key, and there's no
+    /// need for a username.
+    ApiKey,
+}
+
+impl AuthMethod {
+    fn is_token(&self) -> bool {
+        *self == AuthMethod::Token
+    }
+}
+
+/// For servers that take more than plain HTTP(S) to reach: a
+/// self-signed certificate, a client certificate, or a proxy. These sit
+/// alongside the rest of the profile in the config file.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ConnectionSettings {
+    /// A PEM file of certificates to trust, as well as the system's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_certificate: Option<PathBuf>,
+
+    /// Don't check the server's certificate at all.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub accept_invalid_certificates: bool,
+
+    /// PEM files of a client certificate and its PKCS#8 key, for when
+    /// the server asks for one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+
+    /// Like `http://proxy.lan:3128` or `socks5h://localhost:1080`.
+    /// Without one, the usual environment variables are followed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl std::fmt::Debug for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Profile")
+            .field("name", &self.name)
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .field("auth", &self.auth)
+            .field("password", &self.password.as_ref().map(|_| "REDACTED"))
+            .field("keyring", &self.keyring)
+            .field("music_folder_ids", &self.music_folder_ids)
+            .field("album_sort", &self.album_sort)
+            .field("album_years", &self.album_years)
+            .field("connection", &self.connection)
+            .finish()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Can't read or write the config file")]
+    Io(#[from] std::io::Error),
+
+    #[error("Bad config file format")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("Can't serialize the config")]
+    Serialize(#[from] toml::ser::Error),
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        dirs::config_dir()
+            .expect("Can't resolve system config dir")
+            .join("tinysonic")
+            .join("config.toml")
+    }
+
+    pub fn load() -> Result<Config, ConfigError> {
+        Self::parse(&std::fs::read_to_string(Self::path())?)
+    }
+
+    /// Like `load`, but a missing config file is just an empty one.
+    pub fn load_or_default() -> Result<Config, ConfigError> {
+        match Self::load() {
+            Err(ConfigError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Config::default())
+            }
+            res => res,
+        }
+    }
+
+    fn parse(s: &str) -> Result<Config, ConfigError> {
+        let mut value: toml::Value = toml::from_str(s)?;
+
+        // Before profiles, the whole file was a single server's settings.
+        if value.get("url").is_some() {
+            if let Some(table) = value.as_table_mut() {
+                table.insert("name".to_string(), LEGACY_PROFILE_NAME.into());
+            }
+            let profile: Profile = value.try_into()?;
+            return Ok(Config {
+                default_profile: Some(profile.name.clone()),
+                profiles: vec![profile],
+                audio: Default::default(),
+                cache: Default::default(),
+            });
+        }
+
+        Ok(value.try_into()?)
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// The profile to open at startup, if there are any.
+    pub fn default_profile(&self) -> Option<&Profile> {
+        self.default_profile
+            .as_deref()
+            .and_then(|name| self.profile(name))
+            .or_else(|| self.profiles.first())
+    }
+
+    /// `name`, or if a profile's already called that, `name (2)` or
+    /// whichever number's free.
+    pub fn unused_profile_name(&self, name: &str) -> String {
+        std::iter::once(name.to_string())
+            .chain((2..).map(|n| format!("{name} ({n})")))
+            .find(|name| self.profile(name).is_none())
+            .unwrap()
+    }
+
+    /// Add `profile`, replacing any existing one with the same name.
+    pub fn set_profile(&mut self, profile: Profile) {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    pub fn remove_profile(&mut self, name: &str) {
+        self.profiles.retain(|p| p.name != name);
+        if self.default_profile.as_deref() == Some(name) {
+            self.default_profile = None;
+        }
+    }
+}
+
+impl Profile {
+    /// Write this profile to the config file, leaving the others as
+    /// they are.
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let mut config = Config::load_or_default()?;
+        config.set_profile(self.clone());
+        config.save()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn legacy_config_becomes_default_profile() {
+        let config = Config::parse(
+            r#"
+            url = "https://music.example.com"
+            username = "alice"
+            music_folder_ids = [1, 3]
+            "#,
+        )
+        .unwrap();
+
+        let profile = config.default_profile().unwrap();
+        assert_eq!(profile.name, LEGACY_PROFILE_NAME);
+        assert_eq!(profile.url, "https://music.example.com");
+        assert_eq!(profile.username, "alice");
+        assert_eq!(profile.music_folder_ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn profiles_round_trip() {
+        let mut config = Config::parse(
+            r#"
+            default_profile = "shared"
+
+            [[profile]]
+            name = "home"
+            url = "http://localhost:4533"
+            username = "alice"
+            auth = "api_key"
+            album_sort = "by_year"
+            album_years = [1970, 1979]
+
+            [[profile]]
+            name = "shared"
+            url = "https://music.example.com"
+            username = "alice"
+            ca_certificate = "/etc/tinysonic/ca.pem"
+            proxy = "socks5h://localhost:1080"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.default_profile().unwrap().name, "shared");
+
+        let reparsed = Config::parse(&toml::to_string(&config).unwrap()).unwrap();
+        let shared = &reparsed.profile("shared").unwrap().connection;
+        assert_eq!(
+            shared.ca_certificate.as_deref(),
+            Some(std::path::Path::new("/etc/tinysonic/ca.pem"))
+        );
+        assert_eq!(shared.proxy.as_deref(), Some("socks5h://localhost:1080"));
+        assert!(!shared.accept_invalid_certificates);
+        assert!(reparsed.profile("home").unwrap().connection.proxy.is_none());
+        assert_eq!(reparsed.profile("home").unwrap().auth, AuthMethod::ApiKey);
+        let home = reparsed.profile("home").unwrap();
+        assert_eq!(home.album_sort.as_deref(), Some("by_year"));
+        assert_eq!(home.album_years, Some((1970, 1979)));
+        assert_eq!(reparsed.profile("shared").unwrap().album_sort, None);
+        assert_eq!(reparsed.profile("shared").unwrap().auth, AuthMethod::Token);
+
+        assert_eq!(config.unused_profile_name("work"), "work");
+        assert_eq!(config.unused_profile_name("home"), "home (2)");
+
+        config.remove_profile("shared");
+        assert_eq!(config.default_profile().unwrap().name, "home");
+
+        let reparsed = Config::parse(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(reparsed.profiles.len(), 1);
+        assert!(reparsed.audio.outputs.is_empty());
+        assert_eq!(reparsed.cache, CacheSettings::default());
+        assert_eq!(reparsed.profiles[0].url, "http://localhost:4533");
+    }
+
+    #[test]
+    fn audio_settings() {
+        let config = Config::parse(
+            r#"
+            [audio]
+            outputs = ["alsa", "file"]
+            file_dir = "/tmp/tinysonic"
+            file_format = "flac"
+            device = "alsa_output.usb-dac"
+            fixed_rate = 48000
+            bit_perfect = true
+
+            [cache]
+            max_size_mb = 512
+
+            [[profile]]
+            name = "home"
+            url = "http://localhost:4533"
+            username = "alice"
+            "#,
+        )
+        .unwrap();
+
+        let reparsed = Config::parse(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(reparsed.audio.outputs, [OutputKind::Alsa, OutputKind::File]);
+        assert_eq!(reparsed.audio.file_format, FileFormat::Flac);
+        assert_eq!(
+            reparsed.audio.device.as_deref(),
+            Some("alsa_output.usb-dac")
+        );
+        assert_eq!(reparsed.audio.fixed_rate, Some(48000));
+        assert!(reparsed.audio.bit_perfect);
+        assert_eq!(reparsed.cache.max_size(), 512 << 20);
+        assert_eq!(reparsed.profiles.len(), 1);
+
+        for rate in [0, 2_000_000] {
+            let res = Config::parse(&format!("[audio]\nfixed_rate = {rate}"));
+            assert!(matches!(res, Err(ConfigError::Parse(_))), "{res:?}");
+        }
+    }
+}
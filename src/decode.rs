@@ -0,0 +1,369 @@
+//! Decoding that runs ahead of playback, on a thread of its own, so a
+//! slow packet or a busy CPU doesn't leave the output with nothing to
+//! play.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use symphonia::core::{
+    audio::{AudioBuffer, AudioBufferRef, Signal},
+    codecs::Decoder,
+    errors::Error as SymphoniaError,
+    formats::FormatReader,
+    sample::Sample,
+};
+use tracing::{error, warn};
+
+/// How many played buffers are kept for reuse. The decoder takes one
+/// for each packet as playback hands one back, so it seldom needs more
+/// than a couple.
+const MAX_SPARES: usize = 8;
+
+/// What comes out of the decoder
+pub enum Decoded {
+    Audio(AudioBufferRef<'static>),
+    /// The end of the track (or something we can't read past)
+    End,
+}
+
+/// One track's audio, decoded up to `ahead` before it's needed. The
+/// thread doing it stops when this is dropped.
+pub struct DecodeAhead {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    buffer: Mutex<Buffer>,
+    /// Signalled when there's something to take, or room to put more
+    changed: Condvar,
+    ahead: Duration,
+}
+
+#[derive(Default)]
+struct Buffer {
+    chunks: VecDeque<AudioBufferRef<'static>>,
+    /// How long the chunks play for
+    buffered: Duration,
+    /// Played chunks, for the decoder to copy the next ones into
+    spares: Vec<AudioBufferRef<'static>>,
+    /// The decoder's got to the end
+    finished: bool,
+    /// Nobody wants the rest
+    cancelled: bool,
+}
+
+impl DecodeAhead {
+    pub fn spawn(
+        reader: Box<dyn FormatReader>,
+        decoder: Box<dyn Decoder>,
+        audio_track_id: u32,
+        ahead: Duration,
+    ) -> DecodeAhead {
+        let shared = Arc::new(Shared {
+            buffer: Mutex::new(Buffer::default()),
+            changed: Condvar::new(),
+            ahead,
+        });
+
+        let thread = std::thread::Builder::new()
+            .name("decoder".to_string())
+            .spawn({
+                let shared = shared.clone();
+                move || decode(reader, decoder, audio_track_id, &shared)
+            });
+        let thread = match thread {
+            Ok(thread) => Some(thread),
+            Err(e) => {
+                // Which ends the track straight away
+                error!("Couldn't start decoding: {e}");
+                shared.buffer.lock().unwrap().finished = true;
+                None
+            }
+        };
+
+        DecodeAhead { shared, thread }
+    }
+
+    /// The next lot of audio, waiting until `until` for it if the
+    /// decoder's behind. None if it didn't turn up by then.
+    pub fn next(&self, until: Instant) -> Option<Decoded> {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        loop {
+            if let Some(chunk) = buffer.chunks.pop_front() {
+                buffer.buffered = buffer.buffered.saturating_sub(length(&chunk));
+                self.shared.changed.notify_all();
+                return Some(Decoded::Audio(chunk));
+            }
+            if buffer.finished {
+                return Some(Decoded::End);
+            }
+
+            let now = Instant::now();
+            if now >= until {
+                return None;
+            }
+            buffer = self
+                .shared
+                .changed
+                .wait_timeout(buffer, until - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Hands back audio from `next` once it's been played, so its
+    /// buffer can be reused rather than allocating another
+    pub fn recycle(&self, audio: AudioBufferRef<'static>) {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        if buffer.spares.len() < MAX_SPARES {
+            buffer.spares.push(audio);
+        }
+    }
+
+    /// Whether the whole track's been decoded, even if it hasn't all
+    /// been taken yet
+    pub fn is_finished(&self) -> bool {
+        self.shared.buffer.lock().unwrap().finished
+    }
+
+    #[cfg(test)]
+    fn buffered(&self) -> Duration {
+        self.shared.buffer.lock().unwrap().buffered
+    }
+}
+
+impl Drop for DecodeAhead {
+    fn drop(&mut self) {
+        self.shared.buffer.lock().unwrap().cancelled = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Runs on the decoder thread until the track's over, or it's cancelled
+fn decode(
+    mut reader: Box<dyn FormatReader>,
+    mut decoder: Box<dyn Decoder>,
+    audio_track_id: u32,
+    shared: &Shared,
+) {
+    loop {
+        let packet = match reader.next_packet() {
+            // If the packet does not belong to the selected track, skip it.
+            Ok(p) if p.track_id() != audio_track_id => continue,
+            Ok(p) => p,
+            // Which is how the end of the track looks
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => {
+                warn!("reader error: {e}");
+                break;
+            }
+        };
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => {
+                let spare = shared.buffer.lock().unwrap().take_spare(&d);
+                owned(d, spare)
+            }
+            Err(e) => {
+                warn!("decode error: {}", e);
+                continue;
+            }
+        };
+
+        let mut buffer = shared.buffer.lock().unwrap();
+        while buffer.buffered >= shared.ahead && !buffer.cancelled {
+            buffer = shared.changed.wait(buffer).unwrap();
+        }
+        if buffer.cancelled {
+            return;
+        }
+        buffer.buffered += length(&decoded);
+        buffer.chunks.push_back(decoded);
+        shared.changed.notify_all();
+    }
+
+    shared.buffer.lock().unwrap().finished = true;
+    shared.changed.notify_all();
+}
+
+impl Buffer {
+    /// A played chunk that `audio` can be copied into
+    fn take_spare(&mut self, audio: &AudioBufferRef<'_>) -> Option<AudioBufferRef<'static>> {
+        let fits = |spare: &AudioBufferRef<'_>| {
+            std::mem::discriminant(spare) == std::mem::discriminant(audio)
+                && spare.spec() == audio.spec()
+                && spare.capacity() >= audio.frames()
+        };
+        let i = self.spares.iter().position(fits)?;
+        Some(self.spares.swap_remove(i))
+    }
+}
+
+/// A view of `audio` that borrows it, to play it without giving it up
+pub fn borrowed<'a>(audio: &'a AudioBufferRef<'_>) -> AudioBufferRef<'a> {
+    match audio {
+        AudioBufferRef::U8(buf) => AudioBufferRef::U8(Cow::Borrowed(buf)),
+        AudioBufferRef::U16(buf) => AudioBufferRef::U16(Cow::Borrowed(buf)),
+        AudioBufferRef::U24(buf) => AudioBufferRef::U24(Cow::Borrowed(buf)),
+        AudioBufferRef::U32(buf) => AudioBufferRef::U32(Cow::Borrowed(buf)),
+        AudioBufferRef::S8(buf) => AudioBufferRef::S8(Cow::Borrowed(buf)),
+        AudioBufferRef::S16(buf) => AudioBufferRef::S16(Cow::Borrowed(buf)),
+        AudioBufferRef::S24(buf) => AudioBufferRef::S24(Cow::Borrowed(buf)),
+        AudioBufferRef::S32(buf) => AudioBufferRef::S32(Cow::Borrowed(buf)),
+        AudioBufferRef::F32(buf) => AudioBufferRef::F32(Cow::Borrowed(buf)),
+        AudioBufferRef::F64(buf) => AudioBufferRef::F64(Cow::Borrowed(buf)),
+    }
+}
+
+/// How long `audio` plays for
+fn length(audio: &AudioBufferRef<'_>) -> Duration {
+    Duration::from_secs_f64(audio.frames() as f64 / audio.spec().rate as f64)
+}
+
+/// A copy of what the decoder lent us, in the same format. It goes in
+/// `spare` if there is one, so nothing needs allocating.
+fn owned(
+    decoded: AudioBufferRef<'_>,
+    spare: Option<AudioBufferRef<'static>>,
+) -> AudioBufferRef<'static> {
+    fn copy<S: Sample>(
+        buf: Cow<'_, AudioBuffer<S>>,
+        spare: Option<Cow<'static, AudioBuffer<S>>>,
+    ) -> Cow<'static, AudioBuffer<S>> {
+        let Some(mut spare) = spare else {
+            return Cow::Owned(buf.into_owned());
+        };
+        let into = spare.to_mut();
+        into.clear();
+        into.render_reserved(Some(buf.frames()));
+        for channel in 0..buf.spec().channels.count() {
+            into.chan_mut(channel).copy_from_slice(buf.chan(channel));
+        }
+        spare
+    }
+
+    macro_rules! copy {
+        ($($format:ident),*) => {
+            match (decoded, spare) {
+                $(
+                    (AudioBufferRef::$format(buf), Some(AudioBufferRef::$format(spare))) => {
+                        AudioBufferRef::$format(copy(buf, Some(spare)))
+                    }
+                    (AudioBufferRef::$format(buf), _) => AudioBufferRef::$format(copy(buf, None)),
+                )*
+            }
+        };
+    }
+    copy!(U8, U16, U24, U32, S8, S16, S24, S32, F32, F64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use symphonia::core::{audio::SampleBuffer, io::MediaSourceStream, probe::Hint};
+
+    /// Decoding the first track in `data`, and how many frames it has
+    fn spawn(data: &'static [u8], ahead: Duration) -> (DecodeAhead, u64) {
+        let mss = MediaSourceStream::new(Box::new(std::io::Cursor::new(data)), Default::default());
+        let reader = symphonia::default::get_probe()
+            .format(&Hint::new(), mss, &Default::default(), &Default::default())
+            .unwrap()
+            .format;
+        let track = reader.default_track().unwrap();
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &Default::default())
+            .unwrap();
+        let (id, frames) = (track.id, track.codec_params.n_frames.unwrap());
+        (DecodeAhead::spawn(reader, decoder, id, ahead), frames)
+    }
+
+    fn song(id: &str) -> &'static [u8] {
+        subsonic_mock::Library::fixture().song(id).unwrap().data()
+    }
+
+    #[test]
+    fn stays_a_little_way_ahead() {
+        let ahead = Duration::from_millis(100);
+        let (decoding, frames) = spawn(song("tr-2"), ahead);
+        std::thread::sleep(Duration::from_millis(200));
+
+        // Give or take a packet
+        let buffered = decoding.buffered();
+        assert!(buffered >= ahead && buffered < ahead * 2, "{buffered:?}");
+        assert!(!decoding.is_finished());
+
+        let mut got = 0;
+        let far_off = Instant::now() + Duration::from_secs(10);
+        while let Some(Decoded::Audio(audio)) = decoding.next(far_off) {
+            got += audio.frames() as u64;
+        }
+        assert_eq!(got, frames);
+        assert!(decoding.is_finished());
+        assert!(matches!(decoding.next(far_off), Some(Decoded::End)));
+    }
+
+    /// Where `audio`'s samples are kept
+    fn address(audio: &AudioBufferRef<'_>) -> usize {
+        fn first<S: Sample>(buf: &AudioBuffer<S>) -> usize {
+            buf.chan(0).as_ptr() as usize
+        }
+        match audio {
+            AudioBufferRef::U8(buf) => first(buf),
+            AudioBufferRef::U16(buf) => first(buf),
+            AudioBufferRef::U24(buf) => first(buf),
+            AudioBufferRef::U32(buf) => first(buf),
+            AudioBufferRef::S8(buf) => first(buf),
+            AudioBufferRef::S16(buf) => first(buf),
+            AudioBufferRef::S24(buf) => first(buf),
+            AudioBufferRef::S32(buf) => first(buf),
+            AudioBufferRef::F32(buf) => first(buf),
+            AudioBufferRef::F64(buf) => first(buf),
+        }
+    }
+
+    #[test]
+    fn reuses_played_buffers() {
+        let far_off = Instant::now() + Duration::from_secs(10);
+        let samples = |recycling: bool| {
+            let (decoding, _) = spawn(song("tr-2"), Duration::from_millis(10));
+            let (mut samples, mut buffers) = (Vec::new(), Vec::new());
+            while let Some(Decoded::Audio(audio)) = decoding.next(far_off) {
+                let mut copy = SampleBuffer::<f32>::new(audio.capacity() as u64, *audio.spec());
+                copy.copy_interleaved_ref(borrowed(&audio));
+                samples.extend_from_slice(copy.samples());
+                if recycling {
+                    buffers.push(address(&audio));
+                    decoding.recycle(audio);
+                }
+            }
+            buffers.sort();
+            buffers.dedup();
+            (samples, buffers.len())
+        };
+
+        let (fresh, _) = samples(false);
+        let (recycled, buffers) = samples(true);
+        assert_eq!(recycled, fresh);
+        assert!(buffers <= MAX_SPARES + 4, "{buffers} buffers");
+    }
+
+    #[test]
+    fn stops_when_dropped() {
+        let (decoding, _) = spawn(song("tr-2"), Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(50));
+        // Or this would wait forever on a decoder that has nowhere to
+        // put what it's decoded
+        drop(decoding);
+    }
+}
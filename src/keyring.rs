@@ -0,0 +1,132 @@
+//! Keeping the server password in the system keyring, via the Secret
+//! Service API.
+
+use std::collections::HashMap;
+
+use secret_service::{EncryptionType, SecretService};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::config::{ConfigError, Profile};
+
+const APPLICATION: &str = "tinysonic";
+
+/// Where a password lives in the keyring. This is what goes in the
+/// config file, instead of the password itself. Profiles for the same
+/// account on the same server share one.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct KeyringEntry {
+    pub server: String,
+    pub username: String,
+}
+
+impl KeyringEntry {
+    pub fn for_profile(profile: &Profile) -> Self {
+        KeyringEntry {
+            server: profile.url.clone(),
+            username: profile.username.clone(),
+        }
+    }
+
+    fn attributes(&self) -> HashMap<&str, &str> {
+        HashMap::from([
+            ("application", APPLICATION),
+            ("server", self.server.as_str()),
+            ("username", self.username.as_str()),
+        ])
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum KeyringError {
+    #[error("The system keyring is unavailable")]
+    SecretService(#[from] secret_service::Error),
+
+    #[error("No password for {username} on {server} in the system keyring")]
+    NotFound { server: String, username: String },
+
+    #[error("The password in the system keyring isn't valid UTF-8")]
+    NotUtf8,
+
+    #[error("The profile has neither a password nor a keyring entry")]
+    NoPassword,
+
+    #[error("Couldn't update the config file")]
+    Config(#[from] ConfigError),
+}
+
+pub async fn get_password(entry: &KeyringEntry) -> Result<String, KeyringError> {
+    let ss = SecretService::connect(EncryptionType::Dh).await?;
+    let search = ss.search_items(entry.attributes()).await?;
+    let item = match search.unlocked.into_iter().next() {
+        Some(item) => item,
+        None => match search.locked.into_iter().next() {
+            Some(item) => {
+                item.unlock().await?;
+                item
+            }
+            None => {
+                return Err(KeyringError::NotFound {
+                    server: entry.server.clone(),
+                    username: entry.username.clone(),
+                })
+            }
+        },
+    };
+
+    String::from_utf8(item.get_secret().await?).map_err(|_| KeyringError::NotUtf8)
+}
+
+pub async fn set_password(entry: &KeyringEntry, password: &str) -> Result<(), KeyringError> {
+    let ss = SecretService::connect(EncryptionType::Dh).await?;
+    let collection = ss.get_default_collection().await?;
+    collection.ensure_unlocked().await?;
+    collection
+        .create_item(
+            &format!("tinysonic password for {}", entry.server),
+            entry.attributes(),
+            password.as_bytes(),
+            true, // replace an existing one
+            "text/plain",
+        )
+        .await?;
+    Ok(())
+}
+
+/// Find the server password for `profile`.
+///
+/// A plaintext password left in the profile is moved into the keyring,
+/// and the config file is rewritten to refer to it instead. If there's
+/// no keyring to move it into, the plaintext one keeps working.
+pub async fn resolve_password(profile: &mut Profile) -> Result<String, KeyringError> {
+    if let Some(entry) = &profile.keyring {
+        let password = get_password(entry).await?;
+        // The keyring has it, so it needn't be in the config file too
+        if profile.password.take().is_some() {
+            profile.save()?;
+            info!("Removed the server password from the config file; the keyring has it");
+        }
+        return Ok(password);
+    }
+
+    let password = match profile.password.take() {
+        Some(p) => p,
+        None => return Err(KeyringError::NoPassword),
+    };
+
+    let entry = KeyringEntry::for_profile(profile);
+    match set_password(&entry, &password).await {
+        Ok(()) => {
+            profile.keyring = Some(entry);
+            profile.save()?;
+            info!("Moved the server password from the config file into the system keyring");
+        }
+        Err(e) => {
+            warn!("Couldn't move the server password into the system keyring: {e}");
+            profile.password = Some(password.clone());
+        }
+    }
+
+    Ok(password)
+}
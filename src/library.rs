@@ -1,20 +1,84 @@
+use std::{
+    cmp::{Ordering::Equal, Reverse},
+    future::Future,
+    path::PathBuf,
+    sync::Mutex,
+};
+
 use bytes::Bytes;
-use subsonic_client::{AlbumListType, SubsonicAuth};
+use rand::seq::SliceRandom;
+use subsonic_client::{
+    AlbumListType, ApiError, Certificate, Child, Client, ClientBuilder, Connectivity, Identity,
+    Proxy, RedactedUrl, SubsonicAuth,
+};
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
 
-use crate::Config;
+use crate::{
+    cache::CacheKey,
+    config::{AuthMethod, ConnectionSettings, Profile},
+    pins::{PinError, PinnedAlbum, PinnedStore, PinnedTrack},
+};
 
 const GET_ALBUMS_WINDOW_SIZE: usize = 100;
+const NUM_RANDOM_ALBUMS: usize = 18;
 const NUM_SEARCH_RESULTS: usize = 100;
+const NUM_TOP_SONGS: usize = 50;
+/// What tracks are streamed as. None is however they're stored.
+const STREAM_FORMAT: Option<&str> = None;
+const STREAM_MAX_BIT_RATE: Option<usize> = None;
+
+/// Everything the library does is a request to the server, and can fail
+/// the way those do.
+pub type Result<T> = std::result::Result<T, ApiError>;
+
+/// The profile's connection settings can't be used
+#[derive(Error, Debug)]
+pub enum ConnectionError {
+    #[error("Can't read {}: {1}", .0.display())]
+    ReadFile(PathBuf, #[source] std::io::Error),
+
+    #[error("Bad certificate or key in {}: {1}", .0.display())]
+    BadCertificate(PathBuf, #[source] ApiError),
+
+    #[error("client_certificate and client_key go together")]
+    IncompleteIdentity,
+
+    #[error("Bad proxy {0}: {1}")]
+    BadProxy(String, #[source] ApiError),
+
+    /// The url, or the settings as a whole, were turned down by the client
+    #[error(transparent)]
+    Client(#[from] ApiError),
+}
 
-#[derive(Debug, Default, Clone)]
+#[derive(Default, Clone)]
 pub struct Album {
     pub album_id: String,
     pub name: String,
     pub artist: String,
+    pub artist_id: String,
     pub cover_url: String,
+    /// Kept for listening offline
+    pub pinned: bool,
 }
 
-#[derive(Default, Clone, Debug, Eq, PartialEq)]
+// The urls carry credentials, so keep them out of debug output.
+impl std::fmt::Debug for Album {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Album")
+            .field("album_id", &self.album_id)
+            .field("name", &self.name)
+            .field("artist", &self.artist)
+            .field("artist_id", &self.artist_id)
+            .field("cover_url", &RedactedUrl(&self.cover_url))
+            .field("pinned", &self.pinned)
+            .finish()
+    }
+}
+
+#[derive(Default, Clone, Eq, PartialEq)]
 pub struct TrackMetadata {
     pub id: String,
     pub name: String,
@@ -24,23 +88,163 @@ pub struct TrackMetadata {
     pub cover_url: String,
 }
 
+impl std::fmt::Debug for TrackMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrackMetadata")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("artist", &self.artist)
+            .field("album", &self.album)
+            .field("stream_url", &RedactedUrl(&self.stream_url))
+            .field("cover_url", &RedactedUrl(&self.cover_url))
+            .finish()
+    }
+}
+
+/// One entry in a music directory, as laid out on the server's disk.
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+    /// The playable track, if this entry is a file rather than a
+    /// directory.
+    pub track: Option<TrackMetadata>,
+}
+
+impl DirectoryEntry {
+    pub fn is_dir(&self) -> bool {
+        self.track.is_none()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MusicFolder {
+    pub id: i64,
+    pub name: String,
+}
+
 pub struct Library {
     client: subsonic_client::Client,
+    profile: Mutex<Profile>,
+    /// Albums kept for when the server can't be reached, if there's
+    /// somewhere to keep them
+    pins: Option<PinnedStore>,
 }
 
 impl Library {
-    pub fn new(config: Config) -> Library {
-        Library {
-            client: subsonic_client::Client::new(
-                SubsonicAuth::new(&config.username, &config.password),
-                &config.url,
-            ),
+    pub fn new(profile: Profile, password: &str) -> std::result::Result<Library, ConnectionError> {
+        let auth = match profile.auth {
+            AuthMethod::Token => SubsonicAuth::new(&profile.username, password),
+            AuthMethod::Password => SubsonicAuth::password(&profile.username, password),
+            AuthMethod::ApiKey => SubsonicAuth::api_key(password),
+        };
+        let builder = Client::builder(auth, &profile.url);
+        let client = configure_connection(builder, &profile.connection)?.build()?;
+
+        Ok(Library {
+            client,
+            pins: PinnedStore::open_default(&server_name(&profile)),
+            profile: Mutex::new(profile),
+        })
+    }
+
+    /// Check that the server is reachable and accepts our credentials.
+    pub async fn check_connection(&self) -> Result<()> {
+        self.client.ping().await.map(|_| ())
+    }
+
+    /// Follow whether the server can currently be reached.
+    pub fn connectivity(&self) -> watch::Receiver<Connectivity> {
+        self.client.connectivity()
+    }
+
+    pub async fn music_folders(&self) -> Result<Vec<MusicFolder>> {
+        Ok(self
+            .client
+            .get_music_folders()
+            .await?
+            .into_iter()
+            .map(|f| MusicFolder {
+                id: f.id,
+                name: f.name,
+            })
+            .collect())
+    }
+
+    /// The music folders that album lists and searches are scoped
+    /// to. Empty means all of them.
+    pub fn music_folder_ids(&self) -> Vec<i64> {
+        self.profile.lock().unwrap().music_folder_ids.clone()
+    }
+
+    /// Scope album lists and searches to the given music folders,
+    /// and remember the choice in the config file.
+    pub fn set_music_folder_ids(&self, ids: Vec<i64>) {
+        let mut profile = self.profile.lock().unwrap();
+        profile.music_folder_ids = ids;
+        if let Err(e) = profile.save() {
+            warn!("Couldn't save music folder selection: {e}");
+        }
+    }
+
+    /// How the album list was sorted last time, and the years it was
+    /// browsing by
+    pub fn album_sort(&self) -> (Option<String>, Option<(u16, u16)>) {
+        let profile = self.profile.lock().unwrap();
+        (profile.album_sort.clone(), profile.album_years)
+    }
+
+    /// Remember how the album list is sorted in the config file, to
+    /// start the same way next time.
+    pub fn set_album_sort(&self, order: &str, years: (u16, u16)) {
+        let mut profile = self.profile.lock().unwrap();
+        profile.album_sort = Some(order.to_owned());
+        profile.album_years = Some(years);
+        if let Err(e) = profile.save() {
+            warn!("Couldn't save the album sort order: {e}");
         }
     }
 
-    pub async fn list_albums(&self, album_list_type: AlbumListType) -> Vec<Album> {
+    /// Each folder id a request should be made for; `None` means
+    /// the whole library.
+    fn folder_scope(&self) -> Vec<Option<String>> {
+        let ids = self.music_folder_ids();
+        if ids.is_empty() {
+            vec![None]
+        } else {
+            ids.into_iter().map(|id| Some(id.to_string())).collect()
+        }
+    }
+
+    /// Offline, it's every pinned album, whatever the order asked for
+    pub async fn list_albums(&self, album_list_type: AlbumListType) -> Result<Vec<Album>> {
+        let from_server = self.list_albums_from_server(album_list_type);
+        self.or_pinned(from_server, |pins| self.pinned_albums(pins, pins.albums()))
+            .await
+    }
+
+    async fn list_albums_from_server(&self, album_list_type: AlbumListType) -> Result<Vec<Album>> {
+        let mut folders = vec![];
+        for folder_id in self.folder_scope() {
+            folders.push(
+                self.list_albums_in_folder(album_list_type.clone(), folder_id.as_deref())
+                    .await?,
+            );
+        }
+        merge_album_lists(&album_list_type, folders)
+            .into_iter()
+            .map(|child| self.album(child))
+            .collect()
+    }
+
+    async fn list_albums_in_folder(
+        &self,
+        album_list_type: AlbumListType,
+        folder_id: Option<&str>,
+    ) -> Result<Vec<Child>> {
         let window_size = if album_list_type == AlbumListType::Random {
-            18
+            NUM_RANDOM_ALBUMS
         } else {
             GET_ALBUMS_WINDOW_SIZE
         };
@@ -55,25 +259,12 @@ impl Library {
                     album_list_type.clone(),
                     Some(window_size),
                     Some(offset),
-                    None,
+                    folder_id,
                 )
-                .await
-                .unwrap()
+                .await?
             {
                 fetched_count += 1;
-                albums.push(Album {
-                    album_id: child.id,
-                    name: child.title,
-                    artist: child.artist.unwrap_or_else(|| "".to_string()),
-                    cover_url: match child.cover_art {
-                        Some(art_id) => self
-                            .client
-                            .cover_art_url(&art_id, Some(200))
-                            .unwrap()
-                            .to_string(),
-                        None => "".to_string(),
-                    },
-                });
+                albums.push(child);
             }
 
             if fetched_count < GET_ALBUMS_WINDOW_SIZE {
@@ -85,78 +276,774 @@ impl Library {
             }
         }
 
-        albums
+        Ok(albums)
     }
 
-    pub async fn search(&self, search: String) -> Vec<Album> {
-        let res = self
-            .client
-            .search2(
-                search,
-                NUM_SEARCH_RESULTS, // artist count
-                0,                  // artist offset
-                NUM_SEARCH_RESULTS, // album count
-                0,                  // album offset
-                0,                  // song count
-                0,                  // song offset
-                None,               // folder id
-            )
-            .await
-            .unwrap();
+    /// The year of the oldest album that has one, in the selected music
+    /// folders. None if none of them do.
+    pub async fn earliest_year(&self) -> Result<Option<u16>> {
+        // Which the server lists oldest first
+        let by_year = AlbumListType::ByYear {
+            from_year: 1,
+            to_year: u16::MAX,
+        };
+        let mut years = vec![];
+        for folder_id in self.folder_scope() {
+            let oldest = self
+                .client
+                .get_album_list(by_year.clone(), Some(1), None, folder_id.as_deref())
+                .await?;
+            years.extend(oldest.first().and_then(|a| a.year));
+        }
+        Ok(years
+            .into_iter()
+            .filter_map(|y| u16::try_from(y).ok())
+            .min())
+    }
+
+    pub async fn search(&self, search: String) -> Result<Vec<Album>> {
+        let from_server = self.search_server(&search);
+        self.or_pinned(from_server, |pins| {
+            self.pinned_albums(pins, pins.search(&search))
+        })
+        .await
+    }
+
+    async fn search_server(&self, search: &str) -> Result<Vec<Album>> {
+        let mut folders = vec![];
+        for folder_id in self.folder_scope() {
+            let res = self
+                .client
+                .search2(
+                    search.to_owned(),
+                    NUM_SEARCH_RESULTS, // artist count
+                    0,                  // artist offset
+                    NUM_SEARCH_RESULTS, // album count
+                    0,                  // album offset
+                    0,                  // song count
+                    0,                  // song offset
+                    folder_id.as_deref(),
+                )
+                .await?;
+            folders.push(res.albums);
+        }
 
         // TODO handle artists
-        res.albums
+        // Each folder's best matches come first
+        let mut albums = interleave(folders);
+        albums.truncate(NUM_SEARCH_RESULTS);
+        albums
             .into_iter()
-            .map(|child| Album {
-                album_id: child.id,
-                name: child.title,
-                artist: child.artist.unwrap_or_else(|| "".to_string()),
-                cover_url: match child.cover_art {
-                    Some(art_id) => self
-                        .client
-                        .cover_art_url(&art_id, Some(200))
-                        .unwrap()
-                        .to_string(),
-                    None => "".to_string(),
-                },
-            })
-            .collect::<Vec<_>>()
+            .map(|child| self.album(child))
+            .collect()
+    }
+
+    fn album(&self, child: Child) -> Result<Album> {
+        let pinned = self.is_pinned(&child.id);
+        Ok(Album {
+            album_id: child.id,
+            name: child.title,
+            artist: child.artist.unwrap_or_default(),
+            artist_id: child.artist_id.unwrap_or_default(),
+            cover_url: match child.cover_art {
+                Some(art_id) => self
+                    .client
+                    .cover_art_url(&art_id, Some(200))?
+                    .to_string(),
+                None => "".to_string(),
+            },
+            pinned,
+        })
     }
 
-    pub async fn track_data(&self, track_id: &str) -> Bytes {
+    /// The track as it was uploaded, if it's pinned and has been
+    /// downloaded
+    pub async fn pinned_track_data(&self, track_id: &str) -> Option<Bytes> {
+        self.pins.as_ref()?.track_data(track_id).await
+    }
+
+    /// The track, streamed from the server
+    pub async fn track_data(&self, track_id: &str) -> Result<Bytes> {
         self.client
-            .stream(&track_id, None, None, None, None, None)
+            .stream(
+                track_id,
+                STREAM_MAX_BIT_RATE,
+                STREAM_FORMAT,
+                None,
+                None,
+                None,
+            )
             .await
-            .unwrap()
     }
 
-    pub async fn album_tracks(&self, id: &str) -> Vec<TrackMetadata> {
-        let album = self.client.get_album(id).await.unwrap();
+    /// What [`Library::track_data`] is kept in the cache as
+    pub fn track_cache_key(&self, track_id: &str) -> CacheKey {
+        CacheKey {
+            server: server_name(&self.profile.lock().unwrap()),
+            track_id: track_id.to_string(),
+            format: STREAM_FORMAT.map(str::to_string),
+            max_bit_rate: STREAM_MAX_BIT_RATE,
+        }
+    }
+
+    pub async fn album_tracks(&self, id: &str) -> Result<Vec<TrackMetadata>> {
+        let from_server = self.album_tracks_from_server(id);
+        self.or_pinned(from_server, |pins| {
+            let album = pins.album(id)?;
+            self.pinned_tracks(pins, album)
+        })
+        .await
+    }
+
+    async fn album_tracks_from_server(&self, id: &str) -> Result<Vec<TrackMetadata>> {
+        let album = self.client.get_album(id).await?;
         let md = album.album_id3;
+        album
+            .songs
+            .into_iter()
+            .map(|child| self.track_metadata(child, md.name.clone()))
+            .collect()
+    }
+
+    /// The artist's most popular tracks, according to the server.
+    /// Offline, it's every pinned track of theirs.
+    pub async fn artist_top_tracks(&self, artist_name: &str) -> Result<Vec<TrackMetadata>> {
+        let from_server = self.artist_top_tracks_from_server(artist_name);
+        self.or_pinned(from_server, |pins| {
+            let mut tracks = vec![];
+            for album in pins.albums() {
+                let theirs = album.artist == artist_name;
+                let album_tracks = self.pinned_tracks(pins, album)?;
+                tracks.extend(
+                    album_tracks
+                        .into_iter()
+                        .filter(|t| theirs || t.artist == artist_name),
+                );
+            }
+            Some(tracks).filter(|t| !t.is_empty())
+        })
+        .await
+    }
+
+    async fn artist_top_tracks_from_server(&self, artist_name: &str) -> Result<Vec<TrackMetadata>> {
+        self.client
+            .get_top_songs(artist_name, Some(NUM_TOP_SONGS))
+            .await?
+            .into_iter()
+            .map(|child| {
+                let album = child.album.clone().unwrap_or_default();
+                self.track_metadata(child, album)
+            })
+            .collect()
+    }
+
+    /// Every track on every one of the artist's albums.
+    pub async fn artist_tracks(&self, artist_id: &str) -> Result<Vec<TrackMetadata>> {
+        let from_server = self.artist_tracks_from_server(artist_id);
+        self.or_pinned(from_server, |pins| {
+            let mut tracks = vec![];
+            for album in pins.albums() {
+                if album.artist_id == artist_id {
+                    tracks.extend(self.pinned_tracks(pins, album)?);
+                }
+            }
+            Some(tracks).filter(|t| !t.is_empty())
+        })
+        .await
+    }
+
+    async fn artist_tracks_from_server(&self, artist_id: &str) -> Result<Vec<TrackMetadata>> {
+        let artist = self.client.get_artist(artist_id).await?;
+        let mut tracks = vec![];
+        for album in artist.albums {
+            tracks.extend(self.album_tracks(&album.id).await?);
+        }
+        Ok(tracks)
+    }
+
+    /// Random tracks from the library, spread over the selected
+    /// music folders.
+    pub async fn random_tracks(
+        &self,
+        count: usize,
+        genre: Option<&str>,
+        from_year: Option<u16>,
+        to_year: Option<u16>,
+    ) -> Result<Vec<TrackMetadata>> {
+        let folders = self.folder_scope();
+        let per_folder = (count / folders.len()).max(1);
+
         let mut tracks = vec![];
-        for child in album.songs.into_iter() {
-            let stream_url = self
+        for folder_id in folders {
+            let songs = self
                 .client
-                .stream_url(&child.id, None, None, None, None, None)
-                .unwrap()
-                .to_string();
-            tracks.push(TrackMetadata {
+                .get_random_songs(
+                    Some(per_folder),
+                    genre,
+                    from_year,
+                    to_year,
+                    folder_id.as_deref(),
+                )
+                .await?;
+            for child in songs {
+                let album = child.album.clone().unwrap_or_default();
+                tracks.push(self.track_metadata(child, album)?);
+            }
+        }
+        Ok(tracks)
+    }
+
+    /// Tracks the server considers similar to the given one.
+    pub async fn similar_tracks(&self, track_id: &str, count: usize) -> Result<Vec<TrackMetadata>> {
+        self.client
+            .get_similar_songs_2(track_id, Some(count))
+            .await?
+            .into_iter()
+            .map(|child| {
+                let album = child.album.clone().unwrap_or_default();
+                self.track_metadata(child, album)
+            })
+            .collect()
+    }
+
+    /// The top level of the file tree: every artist directory in the
+    /// indexes, followed by any loose files.
+    pub async fn index_entries(&self) -> Result<Vec<DirectoryEntry>> {
+        let mut entries = vec![];
+        for folder_id in self.folder_scope() {
+            let indexes = self.client.get_indexes(folder_id.as_deref()).await?;
+
+            for index in indexes.indexes {
+                entries.extend(index.artist.into_iter().map(|artist| DirectoryEntry {
+                    id: artist.id,
+                    artist: artist.name.clone(),
+                    name: artist.name,
+                    track: None,
+                }));
+            }
+            for child in indexes.children {
+                entries.push(self.directory_entry(child)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    pub async fn directory_entries(&self, id: &str) -> Result<Vec<DirectoryEntry>> {
+        self.client
+            .get_music_directory(id)
+            .await?
+            .children
+            .into_iter()
+            .map(|child| self.directory_entry(child))
+            .collect()
+    }
+
+    /// Every track under a directory, walking subdirectories depth
+    /// first in the order the server lists them.
+    pub async fn directory_tracks(&self, id: &str) -> Result<Vec<TrackMetadata>> {
+        let mut tracks = vec![];
+        let mut pending = vec![id.to_owned()];
+        while let Some(dir_id) = pending.pop() {
+            let mut subdirs = vec![];
+            for entry in self.directory_entries(&dir_id).await? {
+                match entry.track {
+                    Some(track) => tracks.push(track),
+                    None => subdirs.push(entry.id),
+                }
+            }
+            pending.extend(subdirs.into_iter().rev());
+        }
+        Ok(tracks)
+    }
+
+    fn directory_entry(&self, child: Child) -> Result<DirectoryEntry> {
+        let id = child.id.clone();
+        let name = child.title.clone();
+        let artist = child.artist.clone().unwrap_or_default();
+        let track = if child.is_dir {
+            None
+        } else {
+            let album = child.album.clone().unwrap_or_default();
+            Some(self.track_metadata(child, album)?)
+        };
+
+        Ok(DirectoryEntry {
+            id,
+            name,
+            artist,
+            track,
+        })
+    }
+
+    fn track_metadata(&self, child: Child, album: String) -> Result<TrackMetadata> {
+        let stream_url = self
+            .client
+            .stream_url(&child.id, None, None, None, None, None)?
+            .to_string();
+        Ok(TrackMetadata {
+            id: child.id,
+            name: child.title,
+            artist: child.artist.unwrap_or_else(|| "".to_string()),
+            album,
+            stream_url,
+            cover_url: match child.cover_art {
+                Some(art_id) => self
+                    .client
+                    .cover_art_url(&art_id, None)?
+                    .to_string(),
+                None => "".to_string(),
+            },
+        })
+    }
+
+    /// Keep the album for when the server can't be reached: its cover,
+    /// and its tracks as they were uploaded. This takes as long as
+    /// downloading them does; running it again picks up where it left
+    /// off.
+    pub async fn pin_album(&self, album_id: &str) -> std::result::Result<(), PinError> {
+        let pins = self.pins.as_ref().ok_or(PinError::NoStore)?;
+        let album = self.client.get_album(album_id).await?;
+        let md = album.album_id3;
+        let tracks: Vec<_> = album
+            .songs
+            .into_iter()
+            .map(|child| PinnedTrack {
                 id: child.id,
                 name: child.title,
-                artist: child.artist.unwrap_or_else(|| "".to_string()),
-                album: md.name.clone(),
-                stream_url,
-                cover_url: match child.cover_art {
-                    Some(art_id) => self
-                        .client
-                        .cover_art_url(&art_id, None)
-                        .unwrap()
-                        .to_string(),
-                    None => "".to_string(),
-                },
-            });
-        }
-
-        tracks
+                artist: child.artist.unwrap_or_default(),
+            })
+            .collect();
+        pins.pin(PinnedAlbum {
+            id: album_id.to_owned(),
+            name: md.name,
+            artist: md.artist.unwrap_or_default(),
+            artist_id: md.artist_id.unwrap_or_default(),
+            tracks: tracks.clone(),
+        })?;
+
+        if let Some(art_id) = md.cover_art {
+            match self.client.get_cover_art(&art_id, None).await {
+                Ok(data) => pins.put_cover(album_id, &data).await?,
+                Err(e) => warn!(album_id, "Couldn't download the cover: {e}"),
+            }
+        }
+        for track in tracks {
+            if !pins.is_pinned(album_id) {
+                info!(album_id, "Unpinned while it was downloading");
+                break;
+            }
+            if !pins.is_downloaded(&track.id) {
+                let data = self.client.download(&track.id).await?;
+                pins.put_track(&track.id, &data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish downloading the pinned albums that aren't all there, as
+    /// when tinysonic quit while pinning them. One that still can't be
+    /// is left as it is, for next time.
+    pub async fn resume_pins(&self) {
+        let Some(pins) = &self.pins else {
+            return;
+        };
+        for album in pins.albums() {
+            if album.tracks.iter().all(|t| pins.is_downloaded(&t.id)) {
+                continue;
+            }
+            let album_id = album.id.as_str();
+            info!(album_id, "Resuming pinning the album");
+            if let Err(e) = self.pin_album(album_id).await {
+                warn!(album_id, "Couldn't finish pinning the album: {e}");
+            }
+        }
+    }
+
+    /// Forget the album, and remove what was downloaded of it
+    pub fn unpin_album(&self, album_id: &str) -> std::result::Result<(), PinError> {
+        let pins = self.pins.as_ref().ok_or(PinError::NoStore)?;
+        Ok(pins.unpin(album_id)?)
+    }
+
+    pub fn is_pinned(&self, album_id: &str) -> bool {
+        self.pins
+            .as_ref()
+            .is_some_and(|pins| pins.is_pinned(album_id))
+    }
+
+    /// What was asked of the server, or if it can't be reached, what
+    /// `pinned` finds in the pinned albums instead. If that's nothing,
+    /// it's the server's error after all. Once the server's been given
+    /// up on, it isn't asked again for what's pinned, which saves
+    /// waiting out its retries every time.
+    async fn or_pinned<T>(
+        &self,
+        from_server: impl Future<Output = Result<T>>,
+        pinned: impl Fn(&PinnedStore) -> Option<T>,
+    ) -> Result<T> {
+        if *self.client.connectivity().borrow() == Connectivity::Offline {
+            if let Some(t) = self.pins.as_ref().and_then(&pinned) {
+                debug!("The server's offline, so using pinned albums");
+                return Ok(t);
+            }
+        }
+
+        match from_server.await {
+            Err(e) if e.is_retryable() => match self.pins.as_ref().and_then(pinned) {
+                Some(t) => {
+                    info!("Can't reach the server, so using pinned albums: {e}");
+                    Ok(t)
+                }
+                None => Err(e),
+            },
+            res => res,
+        }
+    }
+
+    fn pinned_albums(&self, pins: &PinnedStore, albums: Vec<PinnedAlbum>) -> Option<Vec<Album>> {
+        let albums: Vec<_> = albums
+            .into_iter()
+            .map(|album| Album {
+                cover_url: pins.cover_url(&album.id).unwrap_or_default(),
+                album_id: album.id,
+                name: album.name,
+                artist: album.artist,
+                artist_id: album.artist_id,
+                pinned: true,
+            })
+            .collect();
+        Some(albums).filter(|a| !a.is_empty())
+    }
+
+    /// A pinned album's tracks, as the server would have given them
+    fn pinned_tracks(&self, pins: &PinnedStore, album: PinnedAlbum) -> Option<Vec<TrackMetadata>> {
+        let cover_url = pins.cover_url(&album.id).unwrap_or_default();
+        album
+            .tracks
+            .into_iter()
+            .map(|track| {
+                let stream_url = self
+                    .client
+                    .stream_url(&track.id, None, None, None, None, None)
+                    .ok()?;
+                Some(TrackMetadata {
+                    id: track.id,
+                    name: track.name,
+                    artist: track.artist,
+                    album: album.name.clone(),
+                    stream_url: stream_url.to_string(),
+                    cover_url: cover_url.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// One list of albums out of each folder's, in the order that was asked
+/// for, as the server would have given it for all of them at once.
+fn merge_album_lists(album_list_type: &AlbumListType, folders: Vec<Vec<Child>>) -> Vec<Child> {
+    if folders.len() == 1 {
+        return folders.into_iter().flatten().collect();
+    }
+
+    let lowercase = |s: &Option<String>| s.as_deref().unwrap_or_default().to_lowercase();
+    let mut albums: Vec<_> = match album_list_type {
+        AlbumListType::Random => {
+            let mut albums: Vec<_> = folders.into_iter().flatten().collect();
+            albums.shuffle(&mut rand::thread_rng());
+            albums.truncate(NUM_RANDOM_ALBUMS);
+            return albums;
+        }
+        // There's no telling which came first across folders, so they
+        // take turns
+        AlbumListType::Recent | AlbumListType::Starred => return interleave(folders),
+        _ => folders.into_iter().flatten().collect(),
+    };
+    match album_list_type {
+        AlbumListType::Newest => albums.sort_by(|a, b| b.created.cmp(&a.created)),
+        AlbumListType::Highest => albums.sort_by(|a, b| {
+            let rating = |c: &Child| (c.user_rating, c.average_rating.unwrap_or_default());
+            rating(b).partial_cmp(&rating(a)).unwrap_or(Equal)
+        }),
+        AlbumListType::Frequent => albums.sort_by_key(|a| Reverse(a.play_count)),
+        AlbumListType::AlphabeticalByArtist => {
+            albums.sort_by_cached_key(|a| (lowercase(&a.artist), a.title.to_lowercase()))
+        }
+        AlbumListType::ByYear { from_year, to_year } if from_year > to_year => {
+            albums.sort_by_key(|a| Reverse(a.year))
+        }
+        AlbumListType::ByYear { .. } => albums.sort_by_key(|a| a.year),
+        _ => albums.sort_by_cached_key(|a| a.title.to_lowercase()),
+    }
+    albums
+}
+
+/// The first of each list, then the second of each, and so on
+fn interleave<T>(lists: Vec<Vec<T>>) -> Vec<T> {
+    let mut lists: Vec<_> = lists.into_iter().map(Vec::into_iter).collect();
+    let mut all = vec![];
+    loop {
+        let before = all.len();
+        all.extend(lists.iter_mut().filter_map(Iterator::next));
+        if all.len() == before {
+            return all;
+        }
+    }
+}
+
+/// Who's using which server, as in `alice@https://music.example.com`.
+/// Anything kept on disk is kept apart by this.
+fn server_name(profile: &Profile) -> String {
+    format!("{}@{}", profile.username, profile.url)
+}
+
+/// Apply a profile's TLS and proxy settings, reading any files they name
+fn configure_connection(
+    mut builder: ClientBuilder,
+    settings: &ConnectionSettings,
+) -> std::result::Result<ClientBuilder, ConnectionError> {
+    let read = |path: &PathBuf| {
+        std::fs::read(path).map_err(|e| ConnectionError::ReadFile(path.clone(), e))
+    };
+
+    if let Some(path) = &settings.ca_certificate {
+        let certificates = Certificate::from_pem_bundle(&read(path)?)
+            .map_err(|e| ConnectionError::BadCertificate(path.clone(), e.into()))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if settings.accept_invalid_certificates {
+        warn!("Not checking the server's certificate");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    match (&settings.client_certificate, &settings.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let identity = Identity::from_pkcs8_pem(&read(cert_path)?, &read(key_path)?)
+                .map_err(|e| ConnectionError::BadCertificate(cert_path.clone(), e.into()))?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err(ConnectionError::IncompleteIdentity),
+    }
+
+    if let Some(proxy) = &settings.proxy {
+        let proxy =
+            Proxy::all(proxy).map_err(|e| ConnectionError::BadProxy(proxy.clone(), e.into()))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(user_agent) = &settings.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+
+    Ok(builder)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use subsonic_client::RetryPolicy;
+    use subsonic_mock::{MockServer, PASSWORD, USERNAME};
+
+    /// A library backed by `server`
+    pub(crate) fn library(server: &MockServer, password: &str) -> Library {
+        Library::new(profile(server), password).unwrap()
+    }
+
+    fn profile(server: &MockServer) -> Profile {
+        Profile {
+            name: "mock".to_owned(),
+            url: server.url(),
+            username: USERNAME.to_owned(),
+            auth: Default::default(),
+            password: None,
+            keyring: None,
+            music_folder_ids: vec![],
+            album_sort: None,
+            album_years: None,
+            connection: Default::default(),
+        }
+    }
+
+    fn names(tracks: &[TrackMetadata]) -> Vec<&str> {
+        tracks.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn lists_albums_in_scope() {
+        let server = MockServer::start().await;
+        let library = library(&server, PASSWORD);
+
+        let albums = library
+            .list_albums(AlbumListType::AlphabeticalByName)
+            .await
+            .unwrap();
+        assert_eq!(albums.len(), 4);
+        let a440 = albums.iter().find(|a| a.album_id == "al-1").unwrap();
+        assert_eq!(a440.artist, "The Test Tones");
+        assert!(a440.cover_url.contains("getCoverArt"));
+        let all: Vec<_> = albums.iter().map(|a| a.album_id.clone()).collect();
+
+        // Several folders' albums come in the same order as they would
+        // from the whole library
+        library.set_music_folder_ids(vec![2, 1]);
+        let albums = library
+            .list_albums(AlbumListType::AlphabeticalByName)
+            .await
+            .unwrap();
+        let ids: Vec<_> = albums.iter().map(|a| a.album_id.clone()).collect();
+        assert_eq!(ids, all);
+
+        library.set_music_folder_ids(vec![2]);
+        let albums = library
+            .list_albums(AlbumListType::AlphabeticalByName)
+            .await
+            .unwrap();
+        let ids: Vec<_> = albums.iter().map(|a| a.album_id.as_str()).collect();
+        assert_eq!(ids, ["al-4"]);
+    }
+
+    #[tokio::test]
+    async fn earliest_year_is_the_oldest_albums() {
+        let server = MockServer::start().await;
+        let library = library(&server, PASSWORD);
+
+        assert_eq!(library.earliest_year().await.unwrap(), Some(1987));
+    }
+
+    #[tokio::test]
+    async fn album_tracks_stream() {
+        let server = MockServer::start().await;
+        let library = library(&server, PASSWORD);
+
+        let tracks = library.album_tracks("al-1").await.unwrap();
+        assert_eq!(
+            names(&tracks),
+            ["Concert Pitch", "Tuning Fork", "Dial Tone"]
+        );
+        assert!(tracks.iter().all(|t| t.album == "A440"));
+
+        let data = library.track_data(&tracks[1].id).await.unwrap();
+        assert_eq!(&data[..], server.library().song("tr-2").unwrap().data());
+    }
+
+    /// A library pinning albums in `dir`, that gives up on the server
+    /// straight away
+    fn pinning_library(server: &MockServer, dir: &std::path::Path) -> Library {
+        let profile = profile(server);
+        let client = Client::builder(SubsonicAuth::new(USERNAME, PASSWORD), &profile.url)
+            .retry(RetryPolicy::none())
+            .build()
+            .unwrap();
+        Library {
+            client,
+            profile: Mutex::new(profile),
+            pins: Some(PinnedStore::open(dir.to_path_buf())),
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_albums_outlast_the_server() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let library = pinning_library(&server, dir.path());
+
+        library.pin_album("al-1").await.unwrap();
+        assert!(library.is_pinned("al-1"));
+        let requests = server.requests();
+        let downloads = requests.iter().filter(|r| r.endpoint == "download");
+        assert_eq!(downloads.count(), 3);
+
+        // Playing a pinned track doesn't need the server at all
+        let data = library.pinned_track_data("tr-2").await.unwrap();
+        assert_eq!(&data[..], server.library().song("tr-2").unwrap().data());
+        assert_eq!(server.requests().len(), requests.len());
+
+        server.fail_next(1, 503);
+        let albums = library.list_albums(AlbumListType::Newest).await.unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(
+            (albums[0].album_id.as_str(), albums[0].pinned),
+            ("al-1", true)
+        );
+        assert!(albums[0].cover_url.starts_with("file://"));
+
+        // Now that it's offline, what's pinned isn't asked for
+        let asked = server.requests().len();
+        let tracks = library.album_tracks("al-1").await.unwrap();
+        assert_eq!(
+            names(&tracks),
+            ["Concert Pitch", "Tuning Fork", "Dial Tone"]
+        );
+        let tracks = library.artist_top_tracks("The Test Tones").await.unwrap();
+        assert_eq!(tracks.len(), 3);
+        assert_eq!(server.requests().len(), asked);
+        server.fail_next(1, 503);
+        assert!(library.album_tracks("al-2").await.is_err());
+
+        library.unpin_album("al-1").unwrap();
+        assert_eq!(library.pinned_track_data("tr-2").await, None);
+    }
+
+    #[tokio::test]
+    async fn unfinished_pins_are_resumed() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let library = pinning_library(&server, dir.path());
+        // As if it quit before anything downloaded
+        let pins = library.pins.as_ref().unwrap();
+        let tracks = ["tr-1", "tr-2", "tr-3"].map(|id| PinnedTrack {
+            id: id.to_owned(),
+            ..Default::default()
+        });
+        pins.pin(PinnedAlbum {
+            id: "al-1".to_owned(),
+            tracks: tracks.to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        library.resume_pins().await;
+        assert!(tracks.iter().all(|t| pins.is_downloaded(&t.id)));
+        assert_eq!(pins.album("al-1").unwrap().name, "A440");
+
+        // Nothing left to do
+        let asked = server.requests().len();
+        library.resume_pins().await;
+        assert_eq!(server.requests().len(), asked);
+    }
+
+    #[tokio::test]
+    async fn directories_are_walked() {
+        let server = MockServer::start().await;
+        let library = library(&server, PASSWORD);
+
+        let top = library.index_entries().await.unwrap();
+        assert_eq!(top.len(), 3);
+        assert!(top.iter().all(DirectoryEntry::is_dir));
+
+        let tracks = library.directory_tracks("ar-1").await.unwrap();
+        assert_eq!(
+            names(&tracks),
+            [
+                "Concert Pitch",
+                "Tuning Fork",
+                "Dial Tone",
+                "Interference",
+                "Phase"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_reported() {
+        let server = MockServer::start().await;
+        let library = library(&server, "hunter3");
+
+        assert!(matches!(
+            library.check_connection().await,
+            Err(ApiError::WrongUsernameOrPassword(_))
+        ));
     }
 }
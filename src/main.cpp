@@ -8,22 +8,56 @@
 
 
 extern "C" {
-    int main_cpp(const char* appPath, quint64 library, quint64 plm) {
+    int main_cpp(const char* appPath, quint64 sessions) {
         int argc = 1;
         char* argv[1] = { (char*)appPath };
         QGuiApplication app(argc, argv);
 
         QQuickStyle::setStyle("org.kde.desktop");
 
+        Profiles* profiles = new Profiles(NULL);
+        qmlRegisterSingletonInstance<Profiles>("io.github.mullr.tinysonic", 1, 0, "Profiles", profiles);
+
+        Setup* setup = new Setup(NULL);
+        setup->set_sessions(sessions);
+        qmlRegisterSingletonInstance<Setup>("io.github.mullr.tinysonic", 1, 0, "Setup", setup);
+
         Albums* albums = new Albums(NULL);
-        albums->set_library(library);
         qmlRegisterSingletonInstance<Albums>("io.github.mullr.tinysonic", 1, 0, "Albums", albums);
 
+        Directories* directories = new Directories(NULL);
+        qmlRegisterSingletonInstance<Directories>("io.github.mullr.tinysonic", 1, 0, "Directories", directories);
+
+        MusicFolders* musicFolders = new MusicFolders(NULL);
+        qmlRegisterSingletonInstance<MusicFolders>("io.github.mullr.tinysonic", 1, 0, "MusicFolders", musicFolders);
+
+        OutputDevices* outputDevices = new OutputDevices(NULL);
+        outputDevices->set_sessions(sessions);
+        qmlRegisterSingletonInstance<OutputDevices>("io.github.mullr.tinysonic", 1, 0, "OutputDevices", outputDevices);
+
         Player* player = new Player(NULL);
-        player->set_library(library);
-        player->set_plm(plm);
         qmlRegisterSingletonInstance<Player>("io.github.mullr.tinysonic", 1, 0, "Player", player);
 
+        // Hand the current library and playlist manager to everything
+        // that needs them, at startup and whenever the profile changes.
+        // This is connected before the qml loads, so it runs before
+        // anything in there reacts to the same signal.
+        auto connectSession = [=]() {
+            if (profiles->current().isEmpty()) {
+                return;
+            }
+            quint64 library = profiles->library_ptr();
+            quint64 plm = profiles->plm_ptr();
+            albums->set_library(library);
+            directories->set_library(library);
+            directories->set_plm(plm);
+            musicFolders->set_library(library);
+            player->set_library(library);
+            player->set_plm(plm);
+        };
+        QObject::connect(profiles, &Profiles::currentChanged, connectSession);
+        profiles->set_sessions(sessions);
+
         QQmlApplicationEngine engine;
         engine.load(QUrl(QStringLiteral("ui/main.qml")));
 
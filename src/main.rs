@@ -1,44 +1,58 @@
 use std::sync::Arc;
 
-use library::Library;
-use plm::PlaylistManager;
-use serde::Deserialize;
+use cache::TrackCache;
+use config::Config;
+use output::OutputSelection;
+use session::SessionManager;
+use tracing::{error, info};
 mod audio;
+mod cache;
+mod config;
+mod decode;
+mod keyring;
 mod library;
 mod output;
+mod pins;
 mod plm;
+mod resample;
+mod session;
 
 pub mod ui_interface {
     include!(concat!(env!("OUT_DIR"), "/src/ui_interface.rs"));
 }
 mod ui_impl;
 
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    pub url: String,
-    pub username: String,
-    pub password: String,
-}
-
 extern "C" {
-    fn main_cpp(app: *const ::std::os::raw::c_char, library: u64, plm: u64);
+    fn main_cpp(app: *const ::std::os::raw::c_char, sessions: u64);
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
-    let config_path = dirs::config_dir()
-        .expect("Can't resolve system config dir")
-        .join("tinysonic")
-        .join("config.toml");
+    let config = Config::load_or_default().expect("Can't read config file");
 
-    let config: Config = toml::from_str(
-        &std::fs::read_to_string(config_path).expect("Can't read config file at {config_path}"),
-    )
-    .expect("Bad config file format");
+    let cache = match config.cache.max_size() {
+        0 => None,
+        max_size => TrackCache::open_default(max_size),
+    };
 
-    let library = Arc::new(Library::new(config));
-    let plm = Arc::new(PlaylistManager::new(library.clone()));
+    // Without any profiles, the ui starts with server setup instead
+    let sessions = Arc::new(SessionManager::new(
+        OutputSelection::Configured(config.audio.clone()),
+        cache,
+    ));
+    match config.default_profile() {
+        // The ui offers to try again, or to pick another
+        Some(profile) => {
+            if let Err(e) = sessions.open(profile.clone()).await {
+                error!(
+                    profile = profile.name.as_str(),
+                    "Can't open the default profile: {e}"
+                );
+            }
+        }
+        None => info!("No server profiles in {}; starting setup", Config::path().display()),
+    }
 
     tokio::task::spawn_blocking(move || {
         use std::ffi::CString;
@@ -47,8 +61,7 @@ async fn main() {
         unsafe {
             main_cpp(
                 app_name.as_ptr(),
-                &library as *const _ as u64,
-                &plm as *const _ as u64,
+                &sessions as *const _ as u64,
             );
         }
     })
@@ -0,0 +1,165 @@
+//! Straight to ALSA, without a sound server in between
+
+use alsa::{
+    device_name::HintIter,
+    pcm::{Access, Format, HwParams, PCM},
+    Direction, ValueOr,
+};
+use symphonia::core::audio::{AudioBufferRef, SignalSpec};
+use symphonia::core::units::Duration;
+use tracing::{debug, error};
+
+use super::{AudioOutput, AudioOutputError, OutputDevice, RawSamples, Result, SampleFormat};
+
+pub struct AlsaOutput {
+    pcm: PCM,
+    sample_buf: RawSamples,
+    frame_bytes: usize,
+    bit_perfect: bool,
+}
+
+impl AlsaOutput {
+    pub fn try_open(
+        spec: SignalSpec,
+        format: SampleFormat,
+        duration: Duration,
+        device: Option<&str>,
+    ) -> Result<Box<dyn AudioOutput>> {
+        let name = device.unwrap_or("default");
+        let pcm = match PCM::new(name, Direction::Playback, false) {
+            Ok(pcm) => pcm,
+            Err(err) => {
+                error!("alsa open error: {}", err);
+                return Err(AudioOutputError::OpenStreamError);
+            }
+        };
+
+        // Wider integers hold the same samples, padded out with zeros.
+        // Failing those, it's converted to float.
+        let formats: &[SampleFormat] = match format {
+            SampleFormat::S16 => &[
+                SampleFormat::S16,
+                SampleFormat::S24,
+                SampleFormat::S32,
+                SampleFormat::F32,
+            ],
+            SampleFormat::S24 => &[SampleFormat::S24, SampleFormat::S32, SampleFormat::F32],
+            SampleFormat::S32 => &[SampleFormat::S32, SampleFormat::F32],
+            SampleFormat::F32 => &[SampleFormat::F32],
+        };
+        let mut opened_as = None;
+        for &candidate in formats {
+            match configure(&pcm, spec, candidate) {
+                Ok(()) => {
+                    opened_as = Some(candidate);
+                    break;
+                }
+                Err(err) => debug!("alsa can't play {:?} as {:?}: {}", spec, candidate, err),
+            }
+        }
+        let Some(opened_as) = opened_as else {
+            error!("alsa can't play {:?}", spec);
+            return Err(AudioOutputError::OpenStreamError);
+        };
+
+        Ok(Box::new(AlsaOutput {
+            pcm,
+            sample_buf: RawSamples::new(opened_as, duration, spec),
+            frame_bytes: spec.channels.count() * bytes_per_sample(opened_as),
+            // Anything but the hardware itself, like `default` or
+            // `plughw:`, can go through plugins that convert and mix.
+            bit_perfect: name.starts_with("hw:")
+                && (opened_as != SampleFormat::F32 || format == SampleFormat::F32),
+        }))
+    }
+}
+
+/// The pcms ALSA knows of that can play
+pub fn list_devices() -> Result<Vec<OutputDevice>> {
+    let hints = match HintIter::new_str(None, "pcm") {
+        Ok(hints) => hints,
+        Err(err) => {
+            error!("can't list alsa devices: {}", err);
+            return Err(AudioOutputError::OpenStreamError);
+        }
+    };
+
+    Ok(hints
+        .filter(|hint| hint.direction.is_none_or(|d| d == Direction::Playback))
+        .filter_map(|hint| {
+            let name = hint.name?;
+            Some(OutputDevice {
+                // The first line is enough, the rest is detail
+                description: hint
+                    .desc
+                    .as_deref()
+                    .and_then(|d| d.lines().next())
+                    .unwrap_or(&name)
+                    .to_string(),
+                name,
+            })
+        })
+        .collect())
+}
+
+fn configure(pcm: &PCM, spec: SignalSpec, format: SampleFormat) -> alsa::Result<()> {
+    let hwp = HwParams::any(pcm)?;
+    hwp.set_channels(spec.channels.count() as u32)?;
+    hwp.set_rate(spec.rate, ValueOr::Nearest)?;
+    hwp.set_format(match format {
+        SampleFormat::S16 => Format::s16(),
+        SampleFormat::S24 => Format::s24_3(),
+        SampleFormat::S32 => Format::s32(),
+        SampleFormat::F32 => Format::float(),
+    })?;
+    hwp.set_access(Access::RWInterleaved)?;
+    pcm.hw_params(&hwp)?;
+
+    // Playing at some other rate would be out of tune
+    let rate = pcm.hw_params_current()?.get_rate()?;
+    if rate != spec.rate {
+        return Err(alsa::Error::unsupported("rate"));
+    }
+    Ok(())
+}
+
+fn bytes_per_sample(format: SampleFormat) -> usize {
+    match format {
+        SampleFormat::S16 => 2,
+        SampleFormat::S24 => 3,
+        SampleFormat::S32 | SampleFormat::F32 => 4,
+    }
+}
+
+impl AudioOutput for AlsaOutput {
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+        if decoded.frames() == 0 {
+            return Ok(());
+        }
+
+        let io = self.pcm.io_bytes();
+        let mut bytes = self.sample_buf.interleave(decoded);
+        while !bytes.is_empty() {
+            match io.writei(bytes) {
+                Ok(frames) => bytes = &bytes[frames * self.frame_bytes..],
+                // Underruns and the like
+                Err(err) => {
+                    if let Err(err) = self.pcm.try_recover(err, true) {
+                        error!("alsa write error: {}", err);
+                        return Err(AudioOutputError::StreamClosedError);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        // Flush is best-effort, ignore the returned result.
+        let _ = self.pcm.drain();
+    }
+
+    fn is_bit_perfect(&self) -> bool {
+        self.bit_perfect
+    }
+}
@@ -0,0 +1,169 @@
+//! An output that keeps what's written to it, for tests
+
+use std::sync::{Arc, Mutex};
+
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
+use symphonia::core::units::Duration;
+
+use super::{AudioOutput, Pacer, Result, SampleFormat};
+
+/// Collects everything written to the outputs it opens. Clones share
+/// what's been collected.
+#[derive(Clone, Default)]
+pub struct Capture {
+    streams: Arc<Mutex<Vec<CapturedStream>>>,
+    paced: bool,
+    fixed_rate: Option<u32>,
+    bit_perfect: bool,
+}
+
+/// What was written to one output. The player opens one per track.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedStream {
+    pub rate: u32,
+    pub channels: usize,
+    /// What it was opened for. The samples are kept as floats whatever
+    /// it is.
+    pub format: SampleFormat,
+    /// Interleaved
+    pub samples: Vec<f32>,
+}
+
+impl std::fmt::Debug for Capture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Capture")
+            .field("streams", &self.streams.lock().unwrap().len())
+            .field("paced", &self.paced)
+            .field("fixed_rate", &self.fixed_rate)
+            .field("bit_perfect", &self.bit_perfect)
+            .finish()
+    }
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture::default()
+    }
+
+    /// Take as long to write as it'd take to play, like a sound card
+    /// would. Otherwise writes return straight away.
+    pub fn paced(mut self) -> Capture {
+        self.paced = true;
+        self
+    }
+
+    /// As though the config asked for everything at `rate`
+    pub fn at_fixed_rate(mut self, rate: u32) -> Capture {
+        self.fixed_rate = Some(rate);
+        self
+    }
+
+    pub fn fixed_rate(&self) -> Option<u32> {
+        self.fixed_rate
+    }
+
+    /// As though the config asked for bit-perfect playback, and the
+    /// outputs could do it
+    pub fn bit_perfect(mut self) -> Capture {
+        self.bit_perfect = true;
+        self
+    }
+
+    pub fn is_bit_perfect(&self) -> bool {
+        self.bit_perfect
+    }
+
+    pub fn streams(&self) -> Vec<CapturedStream> {
+        self.streams.lock().unwrap().clone()
+    }
+
+    /// Frames written so far, over all the streams
+    pub fn frames(&self) -> usize {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| s.samples.len() / s.channels)
+            .sum()
+    }
+
+    pub(super) fn open(
+        &self,
+        spec: SignalSpec,
+        format: SampleFormat,
+        duration: Duration,
+    ) -> Result<Box<dyn AudioOutput>> {
+        let mut streams = self.streams.lock().unwrap();
+        streams.push(CapturedStream {
+            rate: spec.rate,
+            channels: spec.channels.count(),
+            format,
+            samples: vec![],
+        });
+
+        Ok(Box::new(CaptureOutput {
+            capture: self.clone(),
+            index: streams.len() - 1,
+            sample_buf: SampleBuffer::new(duration, spec),
+            pacer: self.paced.then(|| Pacer::new(spec.rate)),
+        }))
+    }
+}
+
+struct CaptureOutput {
+    capture: Capture,
+    index: usize,
+    sample_buf: SampleBuffer<f32>,
+    pacer: Option<Pacer>,
+}
+
+impl AudioOutput for CaptureOutput {
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+        let frames = decoded.frames();
+        if frames == 0 {
+            return Ok(());
+        }
+        self.sample_buf.copy_interleaved_ref(decoded);
+        self.capture.streams.lock().unwrap()[self.index]
+            .samples
+            .extend_from_slice(self.sample_buf.samples());
+
+        if let Some(pacer) = &mut self.pacer {
+            pacer.wait(frames);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {}
+
+    fn is_bit_perfect(&self) -> bool {
+        self.capture.bit_perfect
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::test::ramp;
+    use symphonia::core::audio::{AsAudioBufferRef, Channels};
+
+    #[test]
+    fn keeps_interleaved_samples() {
+        let spec = SignalSpec::new(48000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let capture = Capture::new();
+
+        let mut output = capture.open(spec, SampleFormat::F32, 4).unwrap();
+        output.write(ramp(spec, 4).as_audio_buffer_ref()).unwrap();
+        output.write(ramp(spec, 2).as_audio_buffer_ref()).unwrap();
+        capture.open(spec, SampleFormat::F32, 4).unwrap();
+
+        let streams = capture.streams();
+        assert_eq!(streams.len(), 2);
+        assert_eq!(
+            streams[0].samples,
+            [0.0, -0.0, 0.25, -0.25, 0.5, -0.5, 0.75, -0.75, 0.0, -0.0, 0.5, -0.5]
+        );
+        assert_eq!((streams[0].rate, streams[0].channels), (48000, 2));
+        assert_eq!(capture.frames(), 6);
+    }
+}
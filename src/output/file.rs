@@ -0,0 +1,416 @@
+//! Writing to files instead of a device: one per track, numbered, in
+//! real time.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
+use symphonia::core::units::Duration;
+use tracing::error;
+
+use super::{AudioOutput, AudioOutputError, Pacer, Result};
+use crate::config::FileFormat;
+
+pub struct FileOutput {
+    sample_buf: SampleBuffer<f32>,
+    writer: Box<dyn Writer>,
+    pacer: Pacer,
+}
+
+impl FileOutput {
+    /// Writes `<dir>/<n>.wav` or `<dir>/<n>.flac`, with `n` the first
+    /// number that isn't taken yet.
+    pub fn try_open(
+        dir: &Path,
+        format: FileFormat,
+        spec: SignalSpec,
+        duration: Duration,
+    ) -> Result<Box<dyn AudioOutput>> {
+        let path = next_free_path(dir, format);
+        let writer = match format {
+            FileFormat::Wav => {
+                WavWriter::create(&path, spec).map(|w| Box::new(w) as Box<dyn Writer>)
+            }
+            FileFormat::Flac => {
+                FlacWriter::create(&path, spec).map(|w| Box::new(w) as Box<dyn Writer>)
+            }
+        };
+
+        match writer {
+            Ok(writer) => Ok(Box::new(FileOutput {
+                sample_buf: SampleBuffer::new(duration, spec),
+                writer,
+                pacer: Pacer::new(spec.rate),
+            })),
+            Err(err) => {
+                error!("can't create {}: {}", path.display(), err);
+                Err(AudioOutputError::OpenStreamError)
+            }
+        }
+    }
+}
+
+fn next_free_path(dir: &Path, format: FileFormat) -> PathBuf {
+    let extension = match format {
+        FileFormat::Wav => "wav",
+        FileFormat::Flac => "flac",
+    };
+    (1..)
+        .map(|n| dir.join(format!("{n}.{extension}")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+impl AudioOutput for FileOutput {
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+        let frames = decoded.frames();
+        if frames == 0 {
+            return Ok(());
+        }
+        self.sample_buf.copy_interleaved_ref(decoded);
+
+        if let Err(err) = self.writer.write(self.sample_buf.samples()) {
+            error!("file output write error: {}", err);
+            return Err(AudioOutputError::StreamClosedError);
+        }
+        self.pacer.wait(frames);
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.writer.finish() {
+            error!("file output write error: {}", err);
+        }
+    }
+}
+
+impl Drop for FileOutput {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+trait Writer {
+    /// Interleaved samples
+    fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+
+    /// Fill in whatever the header couldn't say up front. More can
+    /// still be written afterwards.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// 32 bit float WAV
+struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, spec: SignalSpec) -> io::Result<WavWriter> {
+        let channels = spec.channels.count() as u16;
+        let block_align = channels * 4;
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&3u16.to_le_bytes())?; // IEEE float
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&spec.rate.to_le_bytes())?;
+        file.write_all(&(spec.rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&32u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter { file, data_len: 0 })
+    }
+}
+
+impl Writer for WavWriter {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += samples.len() as u32 * 4;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+/// Frames per FLAC block. Every block but the last is this long.
+const FLAC_BLOCK_SIZE: usize = 4096;
+
+/// 24 bit FLAC, stored rather than compressed: what gets written is
+/// already decoded audio, and there's no encoder to hand.
+struct FlacWriter {
+    file: BufWriter<File>,
+    rate: u32,
+    channels: usize,
+    /// Interleaved samples waiting for a whole block
+    pending: Vec<i32>,
+    frames_written: u64,
+    blocks_written: u64,
+}
+
+impl FlacWriter {
+    fn create(path: &Path, spec: SignalSpec) -> io::Result<FlacWriter> {
+        let mut writer = FlacWriter {
+            file: BufWriter::new(File::create(path)?),
+            rate: spec.rate,
+            channels: spec.channels.count(),
+            pending: vec![],
+            frames_written: 0,
+            blocks_written: 0,
+        };
+
+        writer.file.write_all(b"fLaC")?;
+        // The last metadata block, a STREAMINFO 34 bytes long
+        writer.file.write_all(&[0x80, 0, 0, 34])?;
+        writer
+            .file
+            .write_all(&(FLAC_BLOCK_SIZE as u16).to_be_bytes())?;
+        writer
+            .file
+            .write_all(&(FLAC_BLOCK_SIZE as u16).to_be_bytes())?;
+        // Unknown minimum and maximum frame sizes
+        writer.file.write_all(&[0; 6])?;
+        let stream_info = writer.stream_info();
+        writer.file.write_all(&stream_info)?;
+        // Unknown MD5
+        writer.file.write_all(&[0; 16])?;
+
+        Ok(writer)
+    }
+
+    /// The rate, channels, bits per sample and total frames, packed
+    /// into 20, 3, 5 and 36 bits.
+    fn stream_info(&self) -> [u8; 8] {
+        let packed = (self.rate as u64) << 44
+            | ((self.channels as u64 - 1) << 41)
+            | (23 << 36)
+            | self.frames_written;
+        packed.to_be_bytes()
+    }
+
+    fn write_block(&mut self, samples: &[i32]) -> io::Result<()> {
+        let frames = samples.len() / self.channels;
+
+        // Sync code and fixed block size; block size at the end of the
+        // header and rate from STREAMINFO; independent channels, 24 bits
+        let mut frame = vec![0xFF, 0xF8, 0x70, ((self.channels as u8 - 1) << 4) | 0x0C];
+        utf8_number(self.blocks_written, &mut frame);
+        frame.extend_from_slice(&(frames as u16 - 1).to_be_bytes());
+        frame.push(crc8(&frame));
+
+        for channel in 0..self.channels {
+            frame.push(0x02); // Verbatim
+            for sample in samples.iter().skip(channel).step_by(self.channels) {
+                frame.extend_from_slice(&sample.to_be_bytes()[1..]);
+            }
+        }
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+
+        self.file.write_all(&frame)?;
+        self.frames_written += frames as u64;
+        self.blocks_written += 1;
+        Ok(())
+    }
+}
+
+impl Writer for FlacWriter {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        const MAX: f32 = 0x7F_FFFF as f32;
+        self.pending.extend(
+            samples
+                .iter()
+                .map(|s| (s.clamp(-1.0, 1.0) * MAX).round() as i32),
+        );
+
+        let block_len = FLAC_BLOCK_SIZE * self.channels;
+        while self.pending.len() >= block_len {
+            let block = self.pending.drain(..block_len).collect::<Vec<_>>();
+            self.write_block(&block)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the last, short block. Anything written afterwards
+    /// wouldn't be a valid stream.
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let block = std::mem::take(&mut self.pending);
+            self.write_block(&block)?;
+        }
+
+        self.file.seek(SeekFrom::Start(18))?;
+        let stream_info = self.stream_info();
+        self.file.write_all(&stream_info)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+/// FLAC's variation on UTF-8, which goes up to 36 bits
+fn utf8_number(n: u64, out: &mut Vec<u8>) {
+    if n < 0x80 {
+        out.push(n as u8);
+        return;
+    }
+
+    let len = match n {
+        0x80..=0x7FF => 2,
+        0x800..=0xFFFF => 3,
+        0x1_0000..=0x1F_FFFF => 4,
+        0x20_0000..=0x3FF_FFFF => 5,
+        0x400_0000..=0x7FFF_FFFF => 6,
+        _ => 7,
+    };
+    let mut continuation = vec![];
+    let mut rest = n;
+    for _ in 1..len {
+        continuation.push(0x80 | (rest & 0x3F) as u8);
+        rest >>= 6;
+    }
+    out.push((0xFF00u16 >> len) as u8 | rest as u8);
+    out.extend(continuation.into_iter().rev());
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |mut crc, byte| {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::test::ramp;
+    use symphonia::core::audio::{AsAudioBufferRef, Channels};
+
+    fn write_file(
+        format: FileFormat,
+        spec: SignalSpec,
+        frames: usize,
+    ) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut output = FileOutput::try_open(dir.path(), format, spec, frames as u64).unwrap();
+        output
+            .write(ramp(spec, frames).as_audio_buffer_ref())
+            .unwrap();
+        drop(output);
+
+        let written = match format {
+            FileFormat::Wav => dir.path().join("1.wav"),
+            FileFormat::Flac => dir.path().join("1.flac"),
+        };
+        assert_ne!(next_free_path(dir.path(), format), written);
+        (dir, written)
+    }
+
+    #[test]
+    fn writes_wav_files() {
+        let spec = SignalSpec::new(44100, Channels::FRONT_LEFT);
+        let (_dir, path) = write_file(FileFormat::Wav, spec, 4);
+
+        let wav = std::fs::read(path).unwrap();
+        assert_eq!(wav.len(), 44 + 4 * 4);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 16);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 16);
+        assert_eq!(f32::from_le_bytes(wav[48..52].try_into().unwrap()), 0.25);
+    }
+
+    #[test]
+    fn writes_flac_symphonia_can_read() {
+        use symphonia::core::io::MediaSourceStream;
+
+        let spec = SignalSpec::new(48000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let frames = FLAC_BLOCK_SIZE * 2 + 300;
+        let (_dir, path) = write_file(FileFormat::Flac, spec, frames);
+
+        let file = File::open(path).unwrap();
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut reader = symphonia::default::get_probe()
+            .format(
+                &Default::default(),
+                mss,
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap()
+            .format;
+        let params = reader.tracks()[0].codec_params.clone();
+        assert_eq!(params.sample_rate, Some(48000));
+        assert_eq!(params.bits_per_sample, Some(24));
+        assert_eq!(params.n_frames, Some(frames as u64));
+
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &Default::default())
+            .unwrap();
+        let mut samples = vec![];
+        while let Ok(packet) = reader.next_packet() {
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            buf.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buf.samples());
+        }
+
+        let mut expected = SampleBuffer::<f32>::new(frames as u64, spec);
+        expected.copy_interleaved_ref(ramp(spec, frames).as_audio_buffer_ref());
+        assert_eq!(samples.len(), expected.samples().len());
+        for (got, want) in samples.iter().zip(expected.samples()) {
+            assert!((got - want).abs() < 1e-6, "{got} != {want}");
+        }
+    }
+
+    #[test]
+    fn utf8_numbers() {
+        for (n, bytes) in [
+            (0x41, &[0x41][..]),
+            (0x80, &[0xC2, 0x80]),
+            (0x7FF, &[0xDF, 0xBF]),
+            (0x800, &[0xE0, 0xA0, 0x80]),
+        ] {
+            let mut out = vec![];
+            utf8_number(n, &mut out);
+            assert_eq!(out, bytes, "{n:#x}");
+        }
+    }
+}
@@ -0,0 +1,814 @@
+// This is taken directly from
+// https://raw.githubusercontent.com/pdeljanov/Symphonia/master/symphonia-play/src/output.rs
+
+// Symphonia
+// Copyright (c) 2019-2022 The Project Symphonia Developers.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Audio outputs: the platform's sound servers, files, and nowhere.
+//! Which are tried is up to the config.
+
+use std::result;
+
+use symphonia::core::audio::{AudioBufferRef, SignalSpec};
+use symphonia::core::units::Duration;
+#[cfg(any(all(target_os = "linux", feature = "pulseaudio"), feature = "alsa"))]
+use symphonia::core::{audio::RawSampleBuffer, sample::i24};
+use tracing::{debug, error, warn};
+
+use crate::config::{AudioSettings, OutputKind};
+
+#[cfg(feature = "alsa")]
+mod alsa;
+#[cfg(test)]
+mod capture;
+mod file;
+#[cfg(feature = "pipewire")]
+mod pipewire;
+
+#[cfg(test)]
+pub use capture::Capture;
+
+pub trait AudioOutput {
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()>;
+    fn flush(&mut self);
+
+    /// Whether what's written reaches the device as it is, in the
+    /// format it was opened with, without being mixed, resampled or
+    /// turned up or down on the way. Sound servers can't promise that.
+    fn is_bit_perfect(&self) -> bool {
+        false
+    }
+}
+
+/// How samples are handed to an output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    S16,
+    /// Packed into three bytes
+    S24,
+    S32,
+    F32,
+}
+
+impl SampleFormat {
+    /// The narrowest format that holds `decoded` without losing
+    /// anything. Doubles are the exception, but no codec makes those.
+    pub fn of(decoded: &AudioBufferRef<'_>) -> SampleFormat {
+        match decoded {
+            AudioBufferRef::U8(_)
+            | AudioBufferRef::S8(_)
+            | AudioBufferRef::U16(_)
+            | AudioBufferRef::S16(_) => SampleFormat::S16,
+            AudioBufferRef::U24(_) | AudioBufferRef::S24(_) => SampleFormat::S24,
+            AudioBufferRef::U32(_) | AudioBufferRef::S32(_) => SampleFormat::S32,
+            AudioBufferRef::F32(_) | AudioBufferRef::F64(_) => SampleFormat::F32,
+        }
+    }
+}
+
+/// Interleaved samples as bytes, for outputs that take any of the
+/// formats
+#[cfg(any(all(target_os = "linux", feature = "pulseaudio"), feature = "alsa"))]
+enum RawSamples {
+    S16(RawSampleBuffer<i16>),
+    S24(RawSampleBuffer<i24>),
+    S32(RawSampleBuffer<i32>),
+    F32(RawSampleBuffer<f32>),
+}
+
+#[cfg(any(all(target_os = "linux", feature = "pulseaudio"), feature = "alsa"))]
+impl RawSamples {
+    fn new(format: SampleFormat, duration: Duration, spec: SignalSpec) -> RawSamples {
+        match format {
+            SampleFormat::S16 => RawSamples::S16(RawSampleBuffer::new(duration, spec)),
+            SampleFormat::S24 => RawSamples::S24(RawSampleBuffer::new(duration, spec)),
+            SampleFormat::S32 => RawSamples::S32(RawSampleBuffer::new(duration, spec)),
+            SampleFormat::F32 => RawSamples::F32(RawSampleBuffer::new(duration, spec)),
+        }
+    }
+
+    /// Interleave `decoded`, converting it if it's in some other format
+    fn interleave(&mut self, decoded: AudioBufferRef<'_>) -> &[u8] {
+        match self {
+            RawSamples::S16(buf) => {
+                buf.copy_interleaved_ref(decoded);
+                buf.as_bytes()
+            }
+            RawSamples::S24(buf) => {
+                buf.copy_interleaved_ref(decoded);
+                buf.as_bytes()
+            }
+            RawSamples::S32(buf) => {
+                buf.copy_interleaved_ref(decoded);
+                buf.as_bytes()
+            }
+            RawSamples::F32(buf) => {
+                buf.copy_interleaved_ref(decoded);
+                buf.as_bytes()
+            }
+        }
+    }
+}
+
+/// What happens to the audio on its way to the device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputPath {
+    /// It's converted, resampled, or goes through a sound server
+    Converted,
+    /// The device gets the samples as they were decoded
+    BitPerfect,
+}
+
+/// Where decoded audio goes. Picked when the player starts, and can be
+/// changed while it plays.
+#[derive(Clone, Debug)]
+pub enum OutputSelection {
+    /// Whichever of the configured outputs opens first
+    Configured(AudioSettings),
+    #[cfg(test)]
+    Capture(Capture),
+}
+
+impl OutputSelection {
+    /// The settings, if it comes from the config
+    pub fn settings(&self) -> Option<&AudioSettings> {
+        match self {
+            OutputSelection::Configured(settings) => Some(settings),
+            #[cfg(test)]
+            OutputSelection::Capture(_) => None,
+        }
+    }
+
+    /// The rate to play everything at, if not each track's own
+    pub fn fixed_rate(&self) -> Option<u32> {
+        match self {
+            OutputSelection::Configured(settings) => settings.fixed_rate,
+            #[cfg(test)]
+            OutputSelection::Capture(capture) => capture.fixed_rate(),
+        }
+    }
+
+    /// Whether to play each track in its own format and at its own
+    /// rate, whatever else the settings say
+    pub fn bit_perfect(&self) -> bool {
+        match self {
+            OutputSelection::Configured(settings) => settings.bit_perfect,
+            #[cfg(test)]
+            OutputSelection::Capture(capture) => capture.is_bit_perfect(),
+        }
+    }
+}
+
+/// Somewhere an output can play to, like a sound card or a sink
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputDevice {
+    /// What the output knows it by, for [`AudioSettings::device`]
+    pub name: String,
+    /// For people
+    pub description: String,
+}
+
+#[allow(dead_code)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug)]
+pub enum AudioOutputError {
+    OpenStreamError,
+    PlayStreamError,
+    StreamClosedError,
+    /// This build doesn't have support for the output
+    NotBuiltInError,
+}
+
+pub type Result<T> = result::Result<T, AudioOutputError>;
+
+#[cfg(all(target_os = "linux", feature = "pulseaudio"))]
+mod pulseaudio {
+    use super::{AudioOutput, AudioOutputError, RawSamples, Result, SampleFormat};
+
+    use symphonia::core::audio::*;
+    use symphonia::core::units::Duration;
+
+    use libpulse_binding as pulse;
+    use libpulse_simple_binding as psimple;
+
+    use tracing::{error, warn};
+
+    use super::OutputDevice;
+
+    pub struct PulseAudioOutput {
+        pa: psimple::Simple,
+        sample_buf: RawSamples,
+    }
+
+    impl PulseAudioOutput {
+        pub fn try_open(
+            spec: SignalSpec,
+            format: SampleFormat,
+            duration: Duration,
+            device: Option<&str>,
+        ) -> Result<Box<dyn AudioOutput>> {
+            // An interleaved buffer is required to send data to PulseAudio. Use a SampleBuffer to
+            // move data between Symphonia AudioBuffers and the byte buffers required by PulseAudio.
+            let sample_buf = RawSamples::new(format, duration, spec);
+
+            // Create a PulseAudio stream specification.
+            let pa_spec = pulse::sample::Spec {
+                format: match format {
+                    SampleFormat::S16 => pulse::sample::Format::S16NE,
+                    SampleFormat::S24 => pulse::sample::Format::S24NE,
+                    SampleFormat::S32 => pulse::sample::Format::S32NE,
+                    SampleFormat::F32 => pulse::sample::Format::FLOAT32NE,
+                },
+                channels: spec.channels.count() as u8,
+                rate: spec.rate,
+            };
+
+            assert!(pa_spec.is_valid());
+
+            let pa_ch_map = map_channels_to_pa_channelmap(spec.channels);
+
+            // PulseAudio seems to not play very short audio buffers, use these custom buffer
+            // attributes for very short audio streams.
+            //
+            // let pa_buf_attr = pulse::def::BufferAttr {
+            //     maxlength: std::u32::MAX,
+            //     tlength: 1024,
+            //     prebuf: std::u32::MAX,
+            //     minreq: std::u32::MAX,
+            //     fragsize: std::u32::MAX,
+            // };
+
+            // Create a PulseAudio connection.
+            let pa_result = psimple::Simple::new(
+                None,                               // Use default server
+                "Symphonia Player",                 // Application name
+                pulse::stream::Direction::Playback, // Playback stream
+                device,                             // Playback device, or the default
+                "Music",                            // Description of the stream
+                &pa_spec,                           // Signal specificaiton
+                pa_ch_map.as_ref(),                 // Channel map
+                None,                               // Custom buffering attributes
+            );
+
+            match pa_result {
+                Ok(pa) => Ok(Box::new(PulseAudioOutput { pa, sample_buf })),
+                Err(err) => {
+                    error!("audio output stream open error: {}", err);
+
+                    Err(AudioOutputError::OpenStreamError)
+                }
+            }
+        }
+    }
+
+    /// The sinks on the server. The simple API can't ask, so this
+    /// makes a connection of its own and waits on it.
+    pub fn list_devices() -> Result<Vec<OutputDevice>> {
+        use pulse::callbacks::ListResult;
+        use pulse::context::{Context, FlagSet, State};
+        use pulse::mainloop::standard::{IterateResult, Mainloop};
+        use std::{
+            cell::{Cell, RefCell},
+            rc::Rc,
+        };
+
+        let mut mainloop = Mainloop::new().ok_or(AudioOutputError::OpenStreamError)?;
+        let mut context =
+            Context::new(&mainloop, "tinysonic").ok_or(AudioOutputError::OpenStreamError)?;
+        if let Err(err) = context.connect(None, FlagSet::NOFLAGS, None) {
+            error!("can't connect to pulseaudio: {}", err);
+            return Err(AudioOutputError::OpenStreamError);
+        }
+
+        loop {
+            match mainloop.iterate(true) {
+                IterateResult::Success(_) => (),
+                IterateResult::Quit(_) | IterateResult::Err(_) => {
+                    return Err(AudioOutputError::OpenStreamError)
+                }
+            }
+            match context.get_state() {
+                State::Ready => break,
+                State::Failed | State::Terminated => {
+                    error!("can't connect to pulseaudio");
+                    return Err(AudioOutputError::OpenStreamError);
+                }
+                _ => (),
+            }
+        }
+
+        let devices = Rc::new(RefCell::new(vec![]));
+        let done = Rc::new(Cell::new(false));
+        let _op = context.introspect().get_sink_info_list({
+            let devices = devices.clone();
+            let done = done.clone();
+            move |result| match result {
+                ListResult::Item(info) => devices.borrow_mut().push(OutputDevice {
+                    name: info.name.as_deref().unwrap_or_default().to_string(),
+                    description: info.description.as_deref().unwrap_or_default().to_string(),
+                }),
+                ListResult::End | ListResult::Error => done.set(true),
+            }
+        });
+        while !done.get() {
+            if let IterateResult::Quit(_) | IterateResult::Err(_) = mainloop.iterate(true) {
+                return Err(AudioOutputError::StreamClosedError);
+            }
+        }
+
+        context.disconnect();
+        Ok(devices.take())
+    }
+
+    impl AudioOutput for PulseAudioOutput {
+        fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+            // Do nothing if there are no audio frames.
+            if decoded.frames() == 0 {
+                return Ok(());
+            }
+
+            // Interleave samples from the audio buffer into the sample buffer, and write them to
+            // PulseAudio.
+            match self.pa.write(self.sample_buf.interleave(decoded)) {
+                Err(err) => {
+                    error!("audio output stream write error: {}", err);
+
+                    Err(AudioOutputError::StreamClosedError)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn flush(&mut self) {
+            // Flush is best-effort, ignore the returned result.
+            let _ = self.pa.drain();
+        }
+    }
+
+    /// Maps a set of Symphonia `Channels` to a PulseAudio channel map.
+    fn map_channels_to_pa_channelmap(channels: Channels) -> Option<pulse::channelmap::Map> {
+        let mut map: pulse::channelmap::Map = Default::default();
+        map.init();
+        map.set_len(channels.count() as u8);
+
+        let is_mono = channels.count() == 1;
+
+        for (i, channel) in channels.iter().enumerate() {
+            map.get_mut()[i] = match channel {
+                Channels::FRONT_LEFT if is_mono => pulse::channelmap::Position::Mono,
+                Channels::FRONT_LEFT => pulse::channelmap::Position::FrontLeft,
+                Channels::FRONT_RIGHT => pulse::channelmap::Position::FrontRight,
+                Channels::FRONT_CENTRE => pulse::channelmap::Position::FrontCenter,
+                Channels::REAR_LEFT => pulse::channelmap::Position::RearLeft,
+                Channels::REAR_CENTRE => pulse::channelmap::Position::RearCenter,
+                Channels::REAR_RIGHT => pulse::channelmap::Position::RearRight,
+                Channels::LFE1 => pulse::channelmap::Position::Lfe,
+                Channels::FRONT_LEFT_CENTRE => pulse::channelmap::Position::FrontLeftOfCenter,
+                Channels::FRONT_RIGHT_CENTRE => pulse::channelmap::Position::FrontRightOfCenter,
+                Channels::SIDE_LEFT => pulse::channelmap::Position::SideLeft,
+                Channels::SIDE_RIGHT => pulse::channelmap::Position::SideRight,
+                Channels::TOP_CENTRE => pulse::channelmap::Position::TopCenter,
+                Channels::TOP_FRONT_LEFT => pulse::channelmap::Position::TopFrontLeft,
+                Channels::TOP_FRONT_CENTRE => pulse::channelmap::Position::TopFrontCenter,
+                Channels::TOP_FRONT_RIGHT => pulse::channelmap::Position::TopFrontRight,
+                Channels::TOP_REAR_LEFT => pulse::channelmap::Position::TopRearLeft,
+                Channels::TOP_REAR_CENTRE => pulse::channelmap::Position::TopRearCenter,
+                Channels::TOP_REAR_RIGHT => pulse::channelmap::Position::TopRearRight,
+                _ => {
+                    // If a Symphonia channel cannot map to a PulseAudio position then return None
+                    // because PulseAudio will not be able to open a stream with invalid channels.
+                    warn!("failed to map channel {:?} to output", channel);
+                    return None;
+                }
+            }
+        }
+
+        Some(map)
+    }
+}
+
+#[cfg(any(feature = "cpal", not(target_os = "linux")))]
+mod cpal {
+    use super::{AudioOutput, AudioOutputError, Result};
+
+    use symphonia::core::audio::{AudioBufferRef, RawSample, SampleBuffer, SignalSpec};
+    use symphonia::core::conv::ConvertibleSample;
+    use symphonia::core::units::Duration;
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use rb::*;
+
+    use tracing::error;
+
+    use super::OutputDevice;
+
+    pub struct CpalAudioOutput;
+
+    trait AudioOutputSample:
+        cpal::Sample + ConvertibleSample + RawSample + std::marker::Send + 'static
+    {
+    }
+
+    impl AudioOutputSample for f32 {}
+    impl AudioOutputSample for i16 {}
+    impl AudioOutputSample for u16 {}
+
+    impl CpalAudioOutput {
+        pub fn try_open(
+            spec: SignalSpec,
+            duration: Duration,
+            device: Option<&str>,
+        ) -> Result<Box<dyn AudioOutput>> {
+            // Get default host.
+            let host = cpal::default_host();
+
+            // Get the chosen audio output device, or the default one.
+            let device = match device {
+                Some(name) => host.output_devices().ok().and_then(|mut devices| {
+                    devices.find(|d| d.name().ok().as_deref() == Some(name))
+                }),
+                None => host.default_output_device(),
+            };
+            let device = match device {
+                Some(device) => device,
+                _ => {
+                    error!("failed to get the audio output device");
+                    return Err(AudioOutputError::OpenStreamError);
+                }
+            };
+
+            let config = match device.default_output_config() {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("failed to get default audio output device config: {}", err);
+                    return Err(AudioOutputError::OpenStreamError);
+                }
+            };
+
+            // Select proper playback routine based on sample format.
+            match config.sample_format() {
+                cpal::SampleFormat::F32 => {
+                    CpalAudioOutputImpl::<f32>::try_open(spec, duration, &device)
+                }
+                cpal::SampleFormat::I16 => {
+                    CpalAudioOutputImpl::<i16>::try_open(spec, duration, &device)
+                }
+                cpal::SampleFormat::U16 => {
+                    CpalAudioOutputImpl::<u16>::try_open(spec, duration, &device)
+                }
+            }
+        }
+    }
+
+    /// cpal only has names for devices, so they describe themselves
+    pub fn list_devices() -> Result<Vec<OutputDevice>> {
+        let host = cpal::default_host();
+        match host.output_devices() {
+            Ok(devices) => Ok(devices
+                .filter_map(|d| d.name().ok())
+                .map(|name| OutputDevice {
+                    description: name.clone(),
+                    name,
+                })
+                .collect()),
+            Err(err) => {
+                error!("failed to list audio output devices: {}", err);
+                Err(AudioOutputError::OpenStreamError)
+            }
+        }
+    }
+
+    struct CpalAudioOutputImpl<T: AudioOutputSample>
+    where
+        T: AudioOutputSample,
+    {
+        ring_buf_producer: rb::Producer<T>,
+        sample_buf: SampleBuffer<T>,
+        stream: cpal::Stream,
+    }
+
+    impl<T: AudioOutputSample> CpalAudioOutputImpl<T> {
+        pub fn try_open(
+            spec: SignalSpec,
+            duration: Duration,
+            device: &cpal::Device,
+        ) -> Result<Box<dyn AudioOutput>> {
+            // Output audio stream config.
+            let config = cpal::StreamConfig {
+                channels: spec.channels.count() as cpal::ChannelCount,
+                sample_rate: cpal::SampleRate(spec.rate),
+                buffer_size: cpal::BufferSize::Default,
+            };
+
+            // Instantiate a ring buffer capable of buffering 8K (arbitrarily chosen) samples.
+            let ring_buf = SpscRb::new(8 * 1024);
+            let (ring_buf_producer, ring_buf_consumer) = (ring_buf.producer(), ring_buf.consumer());
+
+            let stream_result = device.build_output_stream(
+                &config,
+                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                    // Write out as many samples as possible from the ring buffer to the audio
+                    // output.
+                    let written = ring_buf_consumer.read(data).unwrap_or(0);
+                    // Mute any remaining samples.
+                    data[written..].iter_mut().for_each(|s| *s = T::MID);
+                },
+                move |err| error!("audio output error: {}", err),
+            );
+
+            if let Err(err) = stream_result {
+                error!("audio output stream open error: {}", err);
+
+                return Err(AudioOutputError::OpenStreamError);
+            }
+
+            let stream = stream_result.unwrap();
+
+            // Start the output stream.
+            if let Err(err) = stream.play() {
+                error!("audio output stream play error: {}", err);
+
+                return Err(AudioOutputError::PlayStreamError);
+            }
+
+            let sample_buf = SampleBuffer::<T>::new(duration, spec);
+
+            Ok(Box::new(CpalAudioOutputImpl {
+                ring_buf_producer,
+                sample_buf,
+                stream,
+            }))
+        }
+    }
+
+    impl<T: AudioOutputSample> AudioOutput for CpalAudioOutputImpl<T> {
+        fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+            // Do nothing if there are no audio frames.
+            if decoded.frames() == 0 {
+                return Ok(());
+            }
+
+            // Audio samples must be interleaved for cpal. Interleave the samples in the audio
+            // buffer into the sample buffer.
+            self.sample_buf.copy_interleaved_ref(decoded);
+
+            // Write all the interleaved samples to the ring buffer.
+            let mut samples = self.sample_buf.samples();
+
+            while let Some(written) = self.ring_buf_producer.write_blocking(samples) {
+                samples = &samples[written..];
+            }
+
+            Ok(())
+        }
+
+        fn flush(&mut self) {
+            // Flush is best-effort, ignore the returned result.
+            let _ = self.stream.pause();
+        }
+    }
+}
+
+/// Whether this build can play through `kind` at all
+fn is_built_in(kind: OutputKind) -> bool {
+    match kind {
+        OutputKind::Pipewire => cfg!(feature = "pipewire"),
+        OutputKind::Pulseaudio => cfg!(all(target_os = "linux", feature = "pulseaudio")),
+        OutputKind::Alsa => cfg!(feature = "alsa"),
+        OutputKind::Cpal => cfg!(any(feature = "cpal", not(target_os = "linux"))),
+        OutputKind::File | OutputKind::Null => true,
+    }
+}
+
+/// Which outputs to try, in order
+fn kinds(settings: &AudioSettings) -> Vec<OutputKind> {
+    if settings.outputs.is_empty() {
+        OutputKind::SOUND_SERVERS
+            .into_iter()
+            .filter(|kind| is_built_in(*kind))
+            .collect()
+    } else {
+        settings.outputs.clone()
+    }
+}
+
+/// The devices the first of the selected outputs that has any can play
+/// to. Device names mean different things to each output, so they're
+/// only ever listed from one of them. Blocks while it asks around.
+// With no sound servers built in, there's never anything to list
+#[allow(unreachable_code, unused_variables)]
+pub fn list_devices(settings: &AudioSettings) -> Vec<OutputDevice> {
+    for kind in kinds(settings) {
+        let listed: Result<Vec<OutputDevice>> = match kind {
+            #[cfg(feature = "pipewire")]
+            OutputKind::Pipewire => pipewire::list_devices(),
+            #[cfg(all(target_os = "linux", feature = "pulseaudio"))]
+            OutputKind::Pulseaudio => pulseaudio::list_devices(),
+            #[cfg(feature = "alsa")]
+            OutputKind::Alsa => alsa::list_devices(),
+            #[cfg(any(feature = "cpal", not(target_os = "linux")))]
+            OutputKind::Cpal => cpal::list_devices(),
+            _ => continue,
+        };
+        match listed {
+            Ok(devices) => return devices,
+            Err(err) => warn!("can't list the {kind:?} output's devices: {err:?}"),
+        }
+    }
+    vec![]
+}
+
+/// Open the first of the selected outputs that will. Those that can are
+/// handed samples in `format`; the rest convert them to what they take.
+pub fn open(
+    selection: &OutputSelection,
+    spec: SignalSpec,
+    format: SampleFormat,
+    duration: Duration,
+) -> Result<Box<dyn AudioOutput>> {
+    // Only a single arm outside of tests
+    #[allow(clippy::infallible_destructuring_match)]
+    let settings = match selection {
+        OutputSelection::Configured(settings) => settings,
+        #[cfg(test)]
+        OutputSelection::Capture(capture) => return capture.open(spec, format, duration),
+    };
+
+    let device = settings.device.as_deref();
+    let mut last_err = AudioOutputError::NotBuiltInError;
+    for kind in kinds(settings) {
+        let mut opened = open_kind(kind, settings, device, spec, format, duration);
+        // A device that's gone, like unplugged headphones, shouldn't
+        // stop playback.
+        if opened.is_err() && device.is_some() && OutputKind::SOUND_SERVERS.contains(&kind) {
+            warn!("can't play to {device:?} with the {kind:?} output, trying its default");
+            opened = open_kind(kind, settings, None, spec, format, duration);
+        }
+
+        match opened {
+            Ok(output) => {
+                debug!(output = ?kind, "Opened audio output");
+                return Ok(output);
+            }
+            Err(err) => {
+                warn!("can't open the {kind:?} output: {err:?}");
+                last_err = err;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+#[allow(unused_variables)]
+fn open_kind(
+    kind: OutputKind,
+    settings: &AudioSettings,
+    device: Option<&str>,
+    spec: SignalSpec,
+    format: SampleFormat,
+    duration: Duration,
+) -> Result<Box<dyn AudioOutput>> {
+    match kind {
+        #[cfg(feature = "pipewire")]
+        OutputKind::Pipewire => pipewire::PipeWireOutput::try_open(spec, duration, device),
+        #[cfg(all(target_os = "linux", feature = "pulseaudio"))]
+        OutputKind::Pulseaudio => {
+            pulseaudio::PulseAudioOutput::try_open(spec, format, duration, device)
+        }
+        #[cfg(feature = "alsa")]
+        OutputKind::Alsa => alsa::AlsaOutput::try_open(spec, format, duration, device),
+        #[cfg(any(feature = "cpal", not(target_os = "linux")))]
+        OutputKind::Cpal => cpal::CpalAudioOutput::try_open(spec, duration, device),
+        OutputKind::File => match &settings.file_dir {
+            Some(dir) => file::FileOutput::try_open(dir, settings.file_format, spec, duration),
+            None => {
+                error!("the file output needs a file_dir to write to");
+                Err(AudioOutputError::OpenStreamError)
+            }
+        },
+        OutputKind::Null => Ok(Box::new(NullOutput(Pacer::new(spec.rate)))),
+        #[allow(unreachable_patterns)]
+        _ => Err(AudioOutputError::NotBuiltInError),
+    }
+}
+
+/// Plays nothing, as fast as it'd play something
+struct NullOutput(Pacer);
+
+impl AudioOutput for NullOutput {
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+        self.0.wait(decoded.frames());
+        Ok(())
+    }
+
+    fn flush(&mut self) {}
+}
+
+/// Holds outputs that aren't devices to the speed a device would
+/// play at.
+struct Pacer {
+    started: std::time::Instant,
+    rate: u32,
+    frames: u64,
+}
+
+impl Pacer {
+    fn new(rate: u32) -> Pacer {
+        Pacer {
+            started: std::time::Instant::now(),
+            rate,
+            frames: 0,
+        }
+    }
+
+    /// Wait until everything up to `frames` more would have played.
+    /// After a gap in writing, like a pause, it carries on from there
+    /// rather than catching up.
+    fn wait(&mut self, frames: usize) {
+        self.frames += frames as u64;
+        let played_for = std::time::Duration::from_secs_f64(self.frames as f64 / self.rate as f64);
+
+        let now = std::time::Instant::now();
+        let due = self.started + played_for;
+        if due > now {
+            std::thread::sleep(due - now);
+        } else if let Some(started) = now.checked_sub(played_for) {
+            self.started = started;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, Channels, Signal};
+
+    /// `frames` long, going from 0 up towards 1 on the first channel,
+    /// and down towards -1 on the others.
+    pub(crate) fn ramp(spec: SignalSpec, frames: usize) -> AudioBuffer<f32> {
+        let mut buf = AudioBuffer::new(frames as u64, spec);
+        buf.render_reserved(Some(frames));
+        for ch in 0..spec.channels.count() {
+            let sign = if ch == 0 { 1.0 } else { -1.0 };
+            for (i, s) in buf.chan_mut(ch).iter_mut().enumerate() {
+                *s = sign * i as f32 / frames as f32;
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn falls_back_to_the_next_output() {
+        let spec = SignalSpec::new(44100, Channels::FRONT_LEFT);
+        let dir = tempfile::tempdir().unwrap();
+        let selection = |outputs: Vec<OutputKind>, file_dir: Option<&std::path::Path>| {
+            OutputSelection::Configured(AudioSettings {
+                outputs,
+                file_dir: file_dir.map(ToOwned::to_owned),
+                ..Default::default()
+            })
+        };
+
+        assert!(open(
+            &selection(vec![OutputKind::File], None),
+            spec,
+            SampleFormat::F32,
+            4
+        )
+        .is_err());
+        assert!(open(
+            &selection(vec![OutputKind::File, OutputKind::Null], None),
+            spec,
+            SampleFormat::F32,
+            4
+        )
+        .is_ok());
+
+        let mut output = open(
+            &selection(vec![OutputKind::File, OutputKind::Null], Some(dir.path())),
+            spec,
+            SampleFormat::F32,
+            4,
+        )
+        .unwrap();
+        output.write(ramp(spec, 4).as_audio_buffer_ref()).unwrap();
+        drop(output);
+        assert!(dir.path().join("1.wav").exists());
+    }
+
+    #[test]
+    fn pacing_doesnt_catch_up_after_a_gap() {
+        let mut pacer = Pacer::new(1000);
+        pacer.wait(20);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let start = std::time::Instant::now();
+        pacer.wait(20);
+        pacer.wait(20);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(20));
+    }
+}
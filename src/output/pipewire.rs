@@ -0,0 +1,255 @@
+//! Native PipeWire. Its main loop runs on a thread of its own, and
+//! pulls samples out of a ring buffer that writes fill.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::mpsc,
+    thread::JoinHandle,
+};
+
+use pipewire as pw;
+use pw::spa;
+use rb::{RbConsumer, RbInspector, RbProducer, SpscRb, RB};
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
+use symphonia::core::units::Duration;
+use tracing::error;
+
+use super::{AudioOutput, AudioOutputError, OutputDevice, Result};
+
+pub struct PipeWireOutput {
+    ring_buf: SpscRb<f32>,
+    ring_buf_producer: rb::Producer<f32>,
+    sample_buf: SampleBuffer<f32>,
+    quit: pw::channel::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PipeWireOutput {
+    pub fn try_open(
+        spec: SignalSpec,
+        duration: Duration,
+        device: Option<&str>,
+    ) -> Result<Box<dyn AudioOutput>> {
+        // About a fifth of a second
+        let ring_buf = SpscRb::new(spec.rate as usize * spec.channels.count() / 5);
+        let consumer = ring_buf.consumer();
+        let (quit, quit_rx) = pw::channel::channel();
+        let (opened_tx, opened_rx) = mpsc::channel();
+        let device = device.map(ToOwned::to_owned);
+
+        let thread = std::thread::Builder::new()
+            .name("pipewire".to_string())
+            .spawn(move || {
+                if let Err(err) = run(spec, device, consumer, quit_rx, &opened_tx) {
+                    let _ = opened_tx.send(Err(err));
+                }
+            })
+            .map_err(|_| AudioOutputError::OpenStreamError)?;
+
+        match opened_rx.recv() {
+            Ok(Ok(())) => Ok(Box::new(PipeWireOutput {
+                ring_buf_producer: ring_buf.producer(),
+                ring_buf,
+                sample_buf: SampleBuffer::new(duration, spec),
+                quit,
+                thread: Some(thread),
+            })),
+            Ok(Err(err)) => {
+                error!("pipewire open error: {}", err);
+                Err(AudioOutputError::OpenStreamError)
+            }
+            Err(_) => Err(AudioOutputError::OpenStreamError),
+        }
+    }
+}
+
+/// The sinks on the server
+pub fn list_devices() -> Result<Vec<OutputDevice>> {
+    match sinks() {
+        Ok(devices) => Ok(devices),
+        Err(err) => {
+            error!("can't list pipewire sinks: {}", err);
+            Err(AudioOutputError::OpenStreamError)
+        }
+    }
+}
+
+/// Collects the sinks the registry announces, until the server says
+/// it's announced everything.
+fn sinks() -> std::result::Result<Vec<OutputDevice>, pw::Error> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = context.connect(None)?;
+    let registry = core.get_registry()?;
+
+    let devices = Rc::new(RefCell::new(vec![]));
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let devices = devices.clone();
+            move |global| {
+                let Some(props) = global.props else {
+                    return;
+                };
+                if props.get(*pw::keys::MEDIA_CLASS) != Some("Audio/Sink") {
+                    return;
+                }
+                if let Some(name) = props.get(*pw::keys::NODE_NAME) {
+                    devices.borrow_mut().push(OutputDevice {
+                        name: name.to_string(),
+                        description: props
+                            .get(*pw::keys::NODE_DESCRIPTION)
+                            .unwrap_or(name)
+                            .to_string(),
+                    });
+                }
+            }
+        })
+        .register();
+
+    let done = Rc::new(Cell::new(false));
+    let pending = core.sync(0)?;
+    let _core_listener = core
+        .add_listener_local()
+        .done({
+            let done = done.clone();
+            let mainloop = mainloop.clone();
+            move |id, seq| {
+                if id == pw::core::PW_ID_CORE && seq == pending {
+                    done.set(true);
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+
+    while !done.get() {
+        mainloop.run();
+    }
+    Ok(devices.take())
+}
+
+/// Sets up a playback stream, says so on `opened`, and runs until told
+/// to quit.
+fn run(
+    spec: SignalSpec,
+    device: Option<String>,
+    consumer: rb::Consumer<f32>,
+    quit: pw::channel::Receiver<()>,
+    opened: &mpsc::Sender<std::result::Result<(), pw::Error>>,
+) -> std::result::Result<(), pw::Error> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = context.connect(None)?;
+
+    let _quit = quit.attach(mainloop.loop_(), {
+        let mainloop = mainloop.clone();
+        move |()| mainloop.quit()
+    });
+
+    let channels = spec.channels.count();
+    let mut props = pw::properties::properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Playback",
+        *pw::keys::MEDIA_ROLE => "Music",
+    };
+    if let Some(device) = device {
+        props.insert(*pw::keys::TARGET_OBJECT, device);
+    }
+    let stream = pw::stream::Stream::new(&core, "tinysonic", props)?;
+
+    let _listener = stream
+        .add_local_listener_with_user_data(consumer)
+        .process(move |stream, consumer| {
+            let Some(mut buffer) = stream.dequeue_buffer() else {
+                return;
+            };
+            let data = &mut buffer.datas_mut()[0];
+            let stride = std::mem::size_of::<f32>() * channels;
+
+            let size = match data.data() {
+                Some(bytes) => {
+                    let mut samples = vec![0.0f32; bytes.len() / std::mem::size_of::<f32>()];
+                    // Whatever isn't there yet is silence
+                    let _ = consumer.read(&mut samples);
+                    for (chunk, sample) in bytes.chunks_exact_mut(4).zip(&samples) {
+                        chunk.copy_from_slice(&sample.to_ne_bytes());
+                    }
+                    samples.len() / channels * stride
+                }
+                None => 0,
+            };
+
+            let chunk = data.chunk_mut();
+            *chunk.offset_mut() = 0;
+            *chunk.stride_mut() = stride as i32;
+            *chunk.size_mut() = size as u32;
+        })
+        .register()?;
+
+    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+    audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
+    audio_info.set_rate(spec.rate);
+    audio_info.set_channels(channels as u32);
+    let format = spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(spa::pod::Object {
+            type_: spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+            id: spa::param::ParamType::EnumFormat.as_raw(),
+            properties: audio_info.into(),
+        }),
+    )
+    .expect("Can't serialize the audio format")
+    .0
+    .into_inner();
+    let mut params = [spa::pod::Pod::from_bytes(&format).unwrap()];
+
+    stream.connect(
+        spa::utils::Direction::Output,
+        None,
+        pw::stream::StreamFlags::AUTOCONNECT
+            | pw::stream::StreamFlags::MAP_BUFFERS
+            | pw::stream::StreamFlags::RT_PROCESS,
+        &mut params,
+    )?;
+
+    let _ = opened.send(Ok(()));
+    mainloop.run();
+    Ok(())
+}
+
+impl AudioOutput for PipeWireOutput {
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+        if decoded.frames() == 0 {
+            return Ok(());
+        }
+        if self.thread.as_ref().is_none_or(|t| t.is_finished()) {
+            return Err(AudioOutputError::StreamClosedError);
+        }
+
+        self.sample_buf.copy_interleaved_ref(decoded);
+        let mut samples = self.sample_buf.samples();
+        while let Some(written) = self.ring_buf_producer.write_blocking(samples) {
+            samples = &samples[written..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        while !self.ring_buf.is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for PipeWireOutput {
+    fn drop(&mut self) {
+        let _ = self.quit.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
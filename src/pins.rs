@@ -0,0 +1,277 @@
+//! Albums kept on disk for listening offline: what they're called and
+//! who they're by, their covers, and their tracks as they were
+//! uploaded. While the server can't be reached, these are the whole
+//! library.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use subsonic_client::ApiError;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::cache;
+
+/// What's pinned, kept alongside the files
+const INDEX_FILE: &str = "index.toml";
+
+#[derive(Error, Debug)]
+pub enum PinError {
+    #[error("There's nowhere to keep pinned albums")]
+    NoStore,
+
+    #[error(transparent)]
+    Server(#[from] ApiError),
+
+    #[error("Can't save the album: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PinnedAlbum {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+    pub artist_id: String,
+    #[serde(default, rename = "track")]
+    pub tracks: Vec<PinnedTrack>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PinnedTrack {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Index {
+    #[serde(default, rename = "album")]
+    albums: Vec<PinnedAlbum>,
+}
+
+/// The albums pinned from one server. An album's in the index as soon
+/// as it's pinned; its tracks turn up as they download.
+pub struct PinnedStore {
+    dir: PathBuf,
+    index: Mutex<Index>,
+}
+
+impl PinnedStore {
+    /// The store for albums pinned from `server`, in the usual place
+    /// for this system, if there is one
+    pub fn open_default(server: &str) -> Option<PinnedStore> {
+        let dir = dirs::data_dir()?
+            .join("tinysonic")
+            .join("pinned")
+            .join(cache::file_name(server));
+        Some(PinnedStore::open(dir))
+    }
+
+    /// The store in `dir`, which needn't exist yet
+    pub fn open(dir: PathBuf) -> PinnedStore {
+        let path = dir.join(INDEX_FILE);
+        let index = match std::fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).unwrap_or_else(|e| {
+                warn!("Ignoring {}: {e}", path.display());
+                Index::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(e) => {
+                warn!("Can't read {}: {e}", path.display());
+                Index::default()
+            }
+        };
+        PinnedStore {
+            dir,
+            index: Mutex::new(index),
+        }
+    }
+
+    /// Every pinned album, by artist and then by name
+    pub fn albums(&self) -> Vec<PinnedAlbum> {
+        let mut albums = self.index.lock().unwrap().albums.clone();
+        albums.sort_by(|a, b| (&a.artist, &a.name).cmp(&(&b.artist, &b.name)));
+        albums
+    }
+
+    pub fn album(&self, album_id: &str) -> Option<PinnedAlbum> {
+        let index = self.index.lock().unwrap();
+        index.albums.iter().find(|a| a.id == album_id).cloned()
+    }
+
+    /// The pinned albums with `query` in their name or artist, which is
+    /// near enough what the server's search does
+    pub fn search(&self, query: &str) -> Vec<PinnedAlbum> {
+        let query = query.to_lowercase();
+        let mut albums = self.albums();
+        albums.retain(|a| {
+            a.name.to_lowercase().contains(&query) || a.artist.to_lowercase().contains(&query)
+        });
+        albums
+    }
+
+    pub fn is_pinned(&self, album_id: &str) -> bool {
+        let index = self.index.lock().unwrap();
+        index.albums.iter().any(|a| a.id == album_id)
+    }
+
+    /// Whether a pinned album has the track, downloaded or not
+    pub fn has_track(&self, track_id: &str) -> bool {
+        let index = self.index.lock().unwrap();
+        index
+            .albums
+            .iter()
+            .any(|a| a.tracks.iter().any(|t| t.id == track_id))
+    }
+
+    pub fn is_downloaded(&self, track_id: &str) -> bool {
+        self.track_path(track_id).is_file()
+    }
+
+    /// Pin `album`, or update it if it already is. Its files are
+    /// added separately.
+    pub fn pin(&self, album: PinnedAlbum) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        match index.albums.iter_mut().find(|a| a.id == album.id) {
+            Some(pinned) => *pinned = album,
+            None => index.albums.push(album),
+        }
+        self.save(&index)
+    }
+
+    /// Forget the album, and remove whatever of it is on disk
+    pub fn unpin(&self, album_id: &str) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        let album = match index.albums.iter().position(|a| a.id == album_id) {
+            Some(i) => index.albums.remove(i),
+            None => return Ok(()),
+        };
+        self.save(&index)?;
+
+        for track in &album.tracks {
+            // Unless another pinned album has it too
+            if !index
+                .albums
+                .iter()
+                .any(|a| a.tracks.iter().any(|t| t.id == track.id))
+            {
+                remove_if_there(&self.track_path(&track.id))?;
+            }
+        }
+        remove_if_there(&self.cover_path(album_id))
+    }
+
+    /// The track as it was uploaded, if it's been downloaded
+    pub async fn track_data(&self, track_id: &str) -> Option<Bytes> {
+        let path = self.track_path(track_id);
+        match tokio::fs::read(&path).await {
+            Ok(data) => Some(Bytes::from(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("Can't read pinned track {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Keep a pinned album's track. One that no pinned album has is
+    /// left out, since its album was unpinned while it downloaded.
+    pub async fn put_track(&self, track_id: &str, data: &[u8]) -> io::Result<()> {
+        if !self.has_track(track_id) {
+            return Ok(());
+        }
+        cache::write_whole(&self.track_path(track_id), data).await
+    }
+
+    pub async fn put_cover(&self, album_id: &str, data: &[u8]) -> io::Result<()> {
+        if !self.is_pinned(album_id) {
+            return Ok(());
+        }
+        cache::write_whole(&self.cover_path(album_id), data).await
+    }
+
+    /// A file url for the album's cover, if it's been downloaded
+    pub fn cover_url(&self, album_id: &str) -> Option<String> {
+        let path = self.cover_path(album_id);
+        if !path.is_file() {
+            return None;
+        }
+        url::Url::from_file_path(&path).ok().map(|u| u.to_string())
+    }
+
+    fn track_path(&self, track_id: &str) -> PathBuf {
+        self.dir.join("tracks").join(cache::file_name(track_id))
+    }
+
+    fn cover_path(&self, album_id: &str) -> PathBuf {
+        self.dir.join("covers").join(cache::file_name(album_id))
+    }
+
+    fn save(&self, index: &Index) -> io::Result<()> {
+        let toml = toml::to_string(index).map_err(io::Error::other)?;
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(INDEX_FILE);
+        let partial = path.with_extension("part");
+        std::fs::write(&partial, toml)?;
+        std::fs::rename(&partial, &path)
+    }
+}
+
+fn remove_if_there(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn album(id: &str, track_ids: &[&str]) -> PinnedAlbum {
+        PinnedAlbum {
+            id: id.to_string(),
+            name: format!("Album {id}"),
+            artist: "The Test Tones".to_string(),
+            artist_id: "ar-1".to_string(),
+            tracks: track_ids
+                .iter()
+                .map(|id| PinnedTrack {
+                    id: id.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_tracks_stay_till_the_last_album_goes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = PinnedStore::open(dir.path().to_path_buf());
+        store.pin(album("al-1", &["tr-1", "tr-2"])).unwrap();
+        store.pin(album("al-2", &["tr-2"])).unwrap();
+        store.put_track("tr-1", b"one").await.unwrap();
+        store.put_track("tr-2", b"two").await.unwrap();
+        // No album has it
+        store.put_track("tr-9", b"nine").await.unwrap();
+        assert!(!store.is_downloaded("tr-9"));
+
+        let store = PinnedStore::open(dir.path().to_path_buf());
+        assert_eq!(
+            store.albums(),
+            [album("al-1", &["tr-1", "tr-2"]), album("al-2", &["tr-2"])]
+        );
+        assert_eq!(store.search("album AL-2"), [album("al-2", &["tr-2"])]);
+
+        store.unpin("al-1").unwrap();
+        assert!(!store.is_pinned("al-1"));
+        assert_eq!(store.track_data("tr-1").await, None);
+        assert_eq!(store.track_data("tr-2").await.as_deref(), Some(&b"two"[..]));
+    }
+}
@@ -4,17 +4,47 @@ use tokio::{
     sync::{mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, watch},
     task::{spawn_blocking, JoinHandle},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::{
     audio::{self, AudioCommand, AudioState, AudioThread},
+    cache::TrackCache,
     library::{Library, TrackMetadata},
+    output::{OutputPath, OutputSelection},
 };
 
+/// Radio mode tops the playlist up once it's down to this many tracks.
+const RADIO_LOW_WATER: usize = 3;
+
+/// How many tracks radio mode asks for at a time.
+const RADIO_BATCH_SIZE: usize = 10;
+
+/// How many times in a row radio mode asks again after getting nothing
+/// it hasn't queued already, before it gives up.
+const RADIO_MAX_MISSES: usize = 3;
+
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct PlmStatus {
     pub playing_track: Option<TrackMetadata>,
     pub audio_state: crate::audio::AudioState,
+    /// Whether what's playing reaches the device untouched
+    pub output_path: Option<OutputPath>,
+    pub radio: Option<Radio>,
+    /// The last thing that went wrong, until the next command clears it.
+    pub error: Option<String>,
+}
+
+/// Where radio mode gets more tracks from when the playlist runs low.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Radio {
+    /// Random songs from the library, optionally narrowed down.
+    Random {
+        genre: Option<String>,
+        from_year: Option<u16>,
+        to_year: Option<u16>,
+    },
+    /// Songs similar to the last one in the playlist.
+    Similar,
 }
 
 pub struct PlaylistManager {
@@ -23,12 +53,20 @@ pub struct PlaylistManager {
 }
 
 impl PlaylistManager {
-    pub fn new(library: Arc<Library>) -> PlaylistManager {
+    pub fn new(
+        library: Arc<Library>,
+        output: OutputSelection,
+        cache: Option<Arc<TrackCache>>,
+    ) -> PlaylistManager {
         let (tx, rx) = unbounded_channel::<PlmCommand>();
         let (status_tx, status_rx) = watch::channel(PlmStatus::default());
 
         let tx2 = tx.clone();
-        tokio::spawn(async move { PlmTask::new(tx2, rx, status_tx, library).run().await });
+        tokio::spawn(async move {
+            PlmTask::new(tx2, rx, status_tx, library, output, cache)
+                .run()
+                .await
+        });
         PlaylistManager { tx, status_rx }
     }
 
@@ -40,6 +78,22 @@ impl PlaylistManager {
         self.tx.send(PlmCommand::SetPlaylist(tracks)).unwrap();
     }
 
+    /// Add tracks to the end of the playlist, without interrupting
+    /// whatever is playing.
+    pub fn enqueue(&self, tracks: Vec<TrackMetadata>) {
+        self.tx.send(PlmCommand::Enqueue(tracks)).unwrap();
+    }
+
+    /// Replace the playlist with a never-ending one, fed from `radio`.
+    pub fn start_radio(&self, radio: Radio) {
+        self.tx.send(PlmCommand::StartRadio(radio)).unwrap();
+    }
+
+    /// Stop topping up the playlist. What's already queued still plays.
+    pub fn stop_radio(&self) {
+        self.tx.send(PlmCommand::StopRadio).unwrap();
+    }
+
     pub fn stop(&self) {
         self.tx.send(PlmCommand::Stop).unwrap();
     }
@@ -55,42 +109,82 @@ impl PlaylistManager {
     pub fn next(&self) {
         self.tx.send(PlmCommand::Next).unwrap();
     }
+
+    /// Play to `output` from now on, picking up the current track where
+    /// it's got to.
+    pub fn set_output(&self, output: OutputSelection) {
+        self.tx.send(PlmCommand::SetOutput(output)).unwrap();
+    }
+}
+
+impl Drop for PlaylistManager {
+    /// The task holds on to its own sender, so it has to be told to stop.
+    fn drop(&mut self) {
+        let _ = self.tx.send(PlmCommand::Shutdown);
+    }
 }
 
 pub enum PlmCommand {
     // for ui -> plm
     SetPlaylist(Vec<TrackMetadata>),
+    Enqueue(Vec<TrackMetadata>),
+    StartRadio(Radio),
+    StopRadio,
     Stop,
     Pause,
     Play,
     Next,
+    SetOutput(OutputSelection),
+    Shutdown,
 
     // for library -> plm
     LoadTrackData { track_id: String, data: Bytes },
+    LoadTrackFailed { track_id: String, error: String },
+    RadioTracks(Result<Vec<TrackMetadata>, String>),
 
     // for audio -> plm
-    AudioSkippedTrack { track_id: String },
+    AudioSkippedTrack { track_id: String, reason: String },
     AudioFinishedTrack { track_id: String },
     AudioPlayingTrack { track_id: String },
     AudioState { state: audio::AudioState },
+    AudioOutputPath { path: Option<OutputPath> },
 }
 
 impl std::fmt::Debug for PlmCommand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::SetPlaylist(_) => write!(f, "SetPlaylist"),
+            Self::Enqueue(_) => write!(f, "Enqueue"),
+            Self::StartRadio(radio) => f.debug_tuple("StartRadio").field(radio).finish(),
+            Self::StopRadio => write!(f, "StopRadio"),
             Self::Stop => write!(f, "Stop"),
             Self::Pause => write!(f, "Pause"),
             Self::Play => write!(f, "Play"),
             Self::Next => write!(f, "Next"),
+            Self::SetOutput(output) => f.debug_tuple("SetOutput").field(output).finish(),
+            Self::Shutdown => write!(f, "Shutdown"),
 
             Self::LoadTrackData { track_id, .. } => f
                 .debug_struct("LoadTrackData")
                 .field("track_id", track_id)
                 .finish(),
-            Self::AudioSkippedTrack { track_id } => f
+            Self::LoadTrackFailed { track_id, error } => f
+                .debug_struct("LoadTrackFailed")
+                .field("track_id", track_id)
+                .field("error", error)
+                .finish(),
+            Self::RadioTracks(Ok(tracks)) => f
+                .debug_struct("RadioTracks")
+                .field("count", &tracks.len())
+                .finish(),
+            Self::RadioTracks(Err(error)) => f
+                .debug_struct("RadioTracks")
+                .field("error", error)
+                .finish(),
+            Self::AudioSkippedTrack { track_id, reason } => f
                 .debug_struct("AudioSkippedTrack")
                 .field("track_id", track_id)
+                .field("reason", reason)
                 .finish(),
             Self::AudioFinishedTrack { track_id } => f
                 .debug_struct("AudioFinishedTrack")
@@ -103,6 +197,10 @@ impl std::fmt::Debug for PlmCommand {
             Self::AudioState { state } => {
                 f.debug_struct("AudioState").field("state", state).finish()
             }
+            Self::AudioOutputPath { path } => f
+                .debug_struct("AudioOutputPath")
+                .field("path", path)
+                .finish(),
         }
     }
 }
@@ -112,12 +210,26 @@ struct PlmTask {
     tx: UnboundedSender<PlmCommand>,
     rx: UnboundedReceiver<PlmCommand>,
     library: Arc<Library>,
+    /// Where tracks are looked for before they're downloaded
+    cache: Option<Arc<TrackCache>>,
     audio_tx: UnboundedSender<AudioCommand>,
-    _audio_join_handle: JoinHandle<()>,
+    audio_join_handle: JoinHandle<()>,
     playlist: VecDeque<(TrackMetadata, LoadStatus)>,
     status_tx: watch::Sender<PlmStatus>,
     audio_state: AudioState,
     audio_playing_track_id: Option<String>,
+    audio_output_path: Option<OutputPath>,
+    radio: Option<Radio>,
+    /// What `Radio::Similar` looks for more of when the playlist is empty
+    radio_seed: Option<String>,
+    radio_refilling: bool,
+    /// Batches in a row with nothing new in them
+    radio_misses: usize,
+    error: Option<String>,
+    /// Gets every status as it's published. The watch only keeps the
+    /// latest, which isn't enough for tests.
+    #[cfg(test)]
+    status_log: Option<UnboundedSender<PlmStatus>>,
 }
 
 #[derive(Debug)]
@@ -133,22 +245,33 @@ impl PlmTask {
         rx: UnboundedReceiver<PlmCommand>,
         status_tx: watch::Sender<PlmStatus>,
         library: Arc<Library>,
+        output: OutputSelection,
+        cache: Option<Arc<TrackCache>>,
     ) -> Self {
         let (audio_tx, audio_rx) = tokio::sync::mpsc::unbounded_channel();
         let plm_tx_for_audio = tx.clone();
         let audio_join_handle =
-            spawn_blocking(move || AudioThread::new(audio_rx, plm_tx_for_audio).run());
+            spawn_blocking(move || AudioThread::new(audio_rx, plm_tx_for_audio, output).run());
 
         Self {
             tx,
             rx,
             library,
+            cache,
             audio_tx,
-            _audio_join_handle: audio_join_handle,
+            audio_join_handle,
             playlist: Default::default(),
             status_tx,
             audio_state: AudioState::Stopped,
             audio_playing_track_id: None,
+            audio_output_path: None,
+            radio: None,
+            radio_seed: None,
+            radio_refilling: false,
+            radio_misses: 0,
+            error: None,
+            #[cfg(test)]
+            status_log: None,
         }
     }
 
@@ -156,11 +279,13 @@ impl PlmTask {
         info!("Starting Playlist Manager task");
         while let Some(cmd) = self.rx.recv().await {
             debug!(
-                cmd = format!("{cmd:?}").as_str(),
+                cmd = ?cmd,
                 "Playlist Manager command"
             );
             match cmd {
                 PlmCommand::SetPlaylist(tracks) => {
+                    self.clear_error();
+                    self.radio = None;
                     self.audio_tx.send(AudioCommand::Stop).unwrap();
                     self.playlist = tracks
                         .into_iter()
@@ -170,7 +295,32 @@ impl PlmTask {
                     self.load_as_needed();
                 }
 
+                PlmCommand::Enqueue(tracks) => {
+                    self.clear_error();
+                    self.playlist
+                        .extend(tracks.into_iter().map(|t| (t, LoadStatus::NotLoaded)));
+                    self.load_as_needed();
+                }
+
+                PlmCommand::StartRadio(radio) => {
+                    self.clear_error();
+                    self.radio_seed = self.audio_playing_track_id.clone();
+                    self.radio = Some(radio);
+                    self.radio_misses = 0;
+                    self.audio_tx.send(AudioCommand::Stop).unwrap();
+                    self.playlist.clear();
+                    self.load_as_needed();
+                    self.audio_tx.send(AudioCommand::Play).unwrap();
+                    self.publish_status();
+                }
+
+                PlmCommand::StopRadio => {
+                    self.radio = None;
+                    self.publish_status();
+                }
+
                 PlmCommand::Stop => {
+                    self.radio = None;
                     self.playlist.clear();
                     self.audio_tx.send(AudioCommand::Stop).unwrap();
                 }
@@ -180,6 +330,7 @@ impl PlmTask {
                 }
 
                 PlmCommand::Play => {
+                    self.clear_error();
                     self.audio_tx.send(AudioCommand::Play).unwrap();
                 }
 
@@ -189,20 +340,45 @@ impl PlmTask {
                     self.load_as_needed();
                 }
 
+                PlmCommand::SetOutput(output) => {
+                    self.audio_tx.send(AudioCommand::SetOutput(output)).unwrap();
+                }
+
+                PlmCommand::Shutdown => break,
+
                 PlmCommand::LoadTrackData { track_id, data } => {
                     self.load_track_data(track_id, data);
                     self.load_as_needed();
                 }
 
-                PlmCommand::AudioSkippedTrack { track_id }
-                | PlmCommand::AudioFinishedTrack { track_id } => {
+                PlmCommand::LoadTrackFailed { track_id, error } => {
+                    self.record_failure(&track_id, &error);
                     self.playlist.retain(|t| t.0.id != track_id);
                     self.load_as_needed();
-                    // SHOULD be this, but will be corrected later if it's wrong.
-                    if let Some((t, _)) = self.playlist.get(0) {
-                        self.audio_playing_track_id = Some(t.id.clone());
-                        self.publish_status();
-                    }
+                    self.publish_status();
+                }
+
+                PlmCommand::RadioTracks(Ok(tracks)) => {
+                    self.radio_refilling = false;
+                    self.add_radio_tracks(tracks);
+                }
+
+                PlmCommand::RadioTracks(Err(error)) => {
+                    self.radio_refilling = false;
+                    // It'd most likely fail again straight away
+                    warn!("Radio couldn't get more tracks; turning it off: {error}");
+                    self.radio = None;
+                    self.error = Some(format!("Radio stopped: {error}"));
+                    self.publish_status();
+                }
+
+                PlmCommand::AudioSkippedTrack { track_id, reason } => {
+                    self.record_failure(&track_id, &reason);
+                    self.track_done(track_id);
+                }
+
+                PlmCommand::AudioFinishedTrack { track_id } => {
+                    self.track_done(track_id);
                 }
 
                 PlmCommand::AudioPlayingTrack { track_id } => {
@@ -214,8 +390,58 @@ impl PlmTask {
                     self.audio_state = state;
                     self.publish_status();
                 }
+
+                PlmCommand::AudioOutputPath { path } => {
+                    self.audio_output_path = path;
+                    self.publish_status();
+                }
             }
         }
+
+        info!("Stopping Playlist Manager task");
+        // Hanging up on the audio thread stops it. Our receiver stays
+        // open until it has, so its last notifications have somewhere
+        // to go.
+        let PlmTask {
+            rx: _rx,
+            audio_tx,
+            audio_join_handle,
+            ..
+        } = self;
+        drop(audio_tx);
+        if let Err(e) = audio_join_handle.await {
+            error!("Audio thread failed: {e}");
+        }
+    }
+
+    /// The audio thread is done with a track, one way or another.
+    fn track_done(&mut self, track_id: String) {
+        self.playlist.retain(|t| t.0.id != track_id);
+        self.load_as_needed();
+        // SHOULD be this, but will be corrected later if it's wrong.
+        if let Some((t, _)) = self.playlist.get(0) {
+            self.audio_playing_track_id = Some(t.id.clone());
+            self.publish_status();
+        }
+    }
+
+    /// Note that a track is being skipped, so the ui can say why. The
+    /// caller takes it out of the playlist and publishes the status.
+    fn record_failure(&mut self, track_id: &str, reason: &str) {
+        warn!(track_id, "Skipping track: {reason}");
+        let name = self
+            .playlist
+            .iter()
+            .find(|(t, _)| t.id == track_id)
+            .map(|(t, _)| t.name.as_str())
+            .unwrap_or(track_id);
+        self.error = Some(format!("Skipped {name}: {reason}"));
+    }
+
+    fn clear_error(&mut self) {
+        if self.error.take().is_some() {
+            self.publish_status();
+        }
     }
 
     /// Try and get the first two entries in the playlist (now
@@ -227,6 +453,100 @@ impl PlmTask {
         if let Some((_, LoadStatus::SentToAudioThread)) = self.playlist.get(0) {
             self.load_pl_index(1);
         }
+
+        self.refill_radio();
+    }
+
+    /// In radio mode, fetch more tracks once the playlist is running
+    /// low.
+    fn refill_radio(&mut self) {
+        let radio = match &self.radio {
+            Some(radio) => radio.clone(),
+            None => return,
+        };
+        if self.radio_refilling || self.playlist.len() >= RADIO_LOW_WATER {
+            return;
+        }
+
+        let seed = self
+            .playlist
+            .back()
+            .map(|(t, _)| t.id.clone())
+            .or_else(|| self.radio_seed.clone())
+            // Similar tracks didn't turn up anything new last time
+            .filter(|_| self.radio_misses == 0);
+        let tx = self.tx.clone();
+        let library = self.library.clone();
+        tokio::spawn(async move {
+            let mut tracks = match (&radio, &seed) {
+                (Radio::Similar, Some(seed)) => {
+                    library.similar_tracks(seed, RADIO_BATCH_SIZE).await
+                }
+                (Radio::Similar, None) => Ok(vec![]),
+                (
+                    Radio::Random {
+                        genre,
+                        from_year,
+                        to_year,
+                    },
+                    _,
+                ) => {
+                    library
+                        .random_tracks(RADIO_BATCH_SIZE, genre.as_deref(), *from_year, *to_year)
+                        .await
+                }
+            };
+
+            // The server may not know anything similar (e.g. no
+            // last.fm integration); random beats silence.
+            if matches!(&tracks, Ok(t) if t.is_empty()) && radio == Radio::Similar {
+                tracks = library
+                    .random_tracks(RADIO_BATCH_SIZE, None, None, None)
+                    .await;
+            }
+
+            // Ignoring the error: we may have shut down meanwhile
+            let _ = tx.send(PlmCommand::RadioTracks(tracks.map_err(|e| e.to_string())));
+        });
+
+        self.radio_refilling = true;
+    }
+
+    fn add_radio_tracks(&mut self, tracks: Vec<TrackMetadata>) {
+        // Radio was turned off while these were on their way
+        if self.radio.is_none() {
+            return;
+        }
+
+        let tracks = tracks
+            .into_iter()
+            .filter(|t| !self.playlist.iter().any(|(queued, _)| queued.id == t.id))
+            .collect::<Vec<_>>();
+
+        match tracks.last() {
+            Some(last) => {
+                self.radio_seed = Some(last.id.clone());
+                self.radio_misses = 0;
+            }
+            // Random tracks are different every time, and similar ones
+            // give way to random ones, so it's worth asking again
+            None if self.radio_misses < RADIO_MAX_MISSES => {
+                self.radio_misses += 1;
+                debug!(misses = self.radio_misses, "Radio found nothing new; asking again");
+                self.refill_radio();
+                return;
+            }
+            None => {
+                warn!("Radio keeps finding nothing new to play; turning it off");
+                self.radio = None;
+                self.publish_status();
+                return;
+            }
+        }
+
+        self.playlist
+            .extend(tracks.into_iter().map(|t| (t, LoadStatus::NotLoaded)));
+        self.load_as_needed();
     }
 
     fn publish_status(&mut self) {
@@ -247,9 +567,21 @@ impl PlmTask {
         let status = PlmStatus {
             playing_track,
             audio_state: self.audio_state,
+            output_path: self.audio_output_path,
+            radio: self.radio.clone(),
+            error: self.error.clone(),
         };
 
-        self.status_tx.send(status).unwrap();
+        // Several things can lead to the same status
+        if *self.status_tx.borrow() == status {
+            return;
+        }
+        #[cfg(test)]
+        if let Some(log) = &self.status_log {
+            let _ = log.send(status.clone());
+        }
+        // Ignoring the error: the manager may have been dropped meanwhile
+        let _ = self.status_tx.send(status);
     }
 
     fn load_pl_index(&mut self, index: usize) {
@@ -263,13 +595,51 @@ impl PlmTask {
                 let tx = self.tx.clone();
                 let track_id = track.id.clone();
                 let library = self.library.clone();
+                let cache = self.cache.clone();
                 tokio::spawn(async move {
-                    let data = library.track_data(&track_id).await;
-                    tx.send(PlmCommand::LoadTrackData {
-                        track_id: track_id.clone(),
-                        data,
-                    })
-                    .unwrap();
+                    // Pinned tracks are on disk already, and aren't
+                    // cached as well
+                    if let Some(data) = library.pinned_track_data(&track_id).await {
+                        debug!(track_id = track_id.as_str(), "Loaded pinned track");
+                        let _ = tx.send(PlmCommand::LoadTrackData { track_id, data });
+                        return;
+                    }
+
+                    let key = library.track_cache_key(&track_id);
+                    let cached = match &cache {
+                        Some(cache) => cache.get(&key).await,
+                        None => None,
+                    };
+                    if let Some(data) = cached {
+                        debug!(track_id = track_id.as_str(), "Loaded track from the cache");
+                        let _ = tx.send(PlmCommand::LoadTrackData { track_id, data });
+                        return;
+                    }
+
+                    // The client has already retried, if it was worth it
+                    let mut downloaded = None;
+                    let cmd = match library.track_data(&track_id).await {
+                        Ok(data) => {
+                            downloaded = Some(data.clone());
+                            PlmCommand::LoadTrackData { track_id, data }
+                        }
+                        Err(e) => PlmCommand::LoadTrackFailed {
+                            track_id,
+                            error: e.to_string(),
+                        },
+                    };
+                    // Ignoring the error: we may have shut down meanwhile
+                    let _ = tx.send(cmd);
+
+                    // Only once it's on its way to being played
+                    if let (Some(cache), Some(data)) = (cache, downloaded) {
+                        if let Err(e) = cache.put(&key, &data).await {
+                            warn!(
+                                track_id = key.track_id.as_str(),
+                                "Couldn't cache track: {e}"
+                            );
+                        }
+                    }
                 });
 
                 *load_status = LoadStatus::Loading;
@@ -292,7 +662,7 @@ impl PlmTask {
             match status {
                 LoadStatus::NotLoaded | LoadStatus::SentToAudioThread => {
                     error!(
-                        status = format!("{:?}", status).as_str(),
+                        status = ?status,
                         track_id = t.id.as_str(),
                         "Unexpected load status"
                     );
@@ -310,3 +680,289 @@ impl PlmTask {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::Capture;
+    use std::time::Duration;
+    use subsonic_mock::{MockServer, PASSWORD};
+    use symphonia::core::{audio::SampleBuffer, io::MediaSourceStream};
+
+    /// A playlist manager playing from the mock server into a capture
+    struct Harness {
+        server: MockServer,
+        library: Arc<Library>,
+        plm: PlaylistManager,
+        capture: Capture,
+        statuses: UnboundedReceiver<PlmStatus>,
+    }
+
+    impl Harness {
+        async fn start(capture: Capture) -> Harness {
+            Harness::start_with_cache(capture, None).await
+        }
+
+        async fn start_with_cache(capture: Capture, cache: Option<Arc<TrackCache>>) -> Harness {
+            let server = MockServer::start().await;
+            let library = Arc::new(crate::library::test::library(&server, PASSWORD));
+
+            let (tx, rx) = unbounded_channel();
+            let (status_tx, status_rx) = watch::channel(PlmStatus::default());
+            let (log_tx, statuses) = unbounded_channel();
+            let mut task = PlmTask::new(
+                tx.clone(),
+                rx,
+                status_tx,
+                library.clone(),
+                OutputSelection::Capture(capture.clone()),
+                cache,
+            );
+            task.status_log = Some(log_tx);
+            tokio::spawn(task.run());
+
+            Harness {
+                server,
+                library,
+                plm: PlaylistManager { tx, status_rx },
+                capture,
+                statuses,
+            }
+        }
+
+        async fn album(&self, id: &str) -> Vec<TrackMetadata> {
+            self.library.album_tracks(id).await.unwrap()
+        }
+
+        /// Every status published until one matches, that one included
+        async fn until(&mut self, done: impl Fn(&PlmStatus) -> bool) -> Vec<PlmStatus> {
+            let mut seen = vec![];
+            loop {
+                let status = tokio::time::timeout(Duration::from_secs(10), self.statuses.recv())
+                    .await
+                    .unwrap_or_else(|_| panic!("Timed out; saw {seen:#?}"))
+                    .unwrap();
+                let matched = done(&status);
+                seen.push(status);
+                if matched {
+                    return seen;
+                }
+            }
+        }
+    }
+
+    /// What the status says is going on, in short
+    fn summary(statuses: &[PlmStatus]) -> Vec<(Option<&str>, AudioState)> {
+        statuses
+            .iter()
+            .map(|s| {
+                (
+                    s.playing_track.as_ref().map(|t| t.id.as_str()),
+                    s.audio_state,
+                )
+            })
+            .collect()
+    }
+
+    /// Once the track's going out of an output
+    fn playing(id: &'static str) -> impl Fn(&PlmStatus) -> bool {
+        move |s| {
+            s.audio_state == AudioState::Playing
+                && s.playing_track.as_ref().map(|t| t.id.as_str()) == Some(id)
+                && s.output_path.is_some()
+        }
+    }
+
+    fn in_state(state: AudioState) -> impl Fn(&PlmStatus) -> bool {
+        move |s| s.audio_state == state
+    }
+
+    /// The samples symphonia decodes `data` to, interleaved
+    fn decode(data: &'static [u8]) -> Vec<f32> {
+        let mss = MediaSourceStream::new(Box::new(std::io::Cursor::new(data)), Default::default());
+        let mut reader = symphonia::default::get_probe()
+            .format(
+                &Default::default(),
+                mss,
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap()
+            .format;
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&reader.tracks()[0].codec_params, &Default::default())
+            .unwrap();
+
+        let mut samples = vec![];
+        while let Ok(packet) = reader.next_packet() {
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            buf.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buf.samples());
+        }
+        samples
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn plays_an_album_through() {
+        let mut h = Harness::start(Capture::new().paced()).await;
+        h.plm.set_playlist(h.album("al-1").await);
+        h.plm.play();
+        // Before then, it depends on whether the first track arrives
+        // before the command to play it.
+        h.until(playing("tr-1")).await;
+
+        let statuses = h.until(in_state(AudioState::WillPlayWhenDataArrives)).await;
+        assert_eq!(
+            summary(&statuses),
+            [
+                (Some("tr-2"), AudioState::Playing),
+                (Some("tr-3"), AudioState::Playing),
+                (None, AudioState::WillPlayWhenDataArrives),
+            ]
+        );
+
+        let streams = h.capture.streams();
+        assert_eq!(streams.len(), 3);
+        for (stream, id) in streams.iter().zip(["tr-1", "tr-2", "tr-3"]) {
+            let song = h.server.library().song(id).unwrap();
+            assert_eq!(
+                (stream.rate, stream.channels),
+                (song.sample_rate(), song.channels() as usize)
+            );
+            assert!(stream.samples == decode(song.data()), "{id}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pausing_loses_nothing() {
+        let mut h = Harness::start(Capture::new().paced()).await;
+        h.plm.set_playlist(h.album("al-4").await);
+        h.plm.play();
+        h.until(playing("tr-8")).await;
+
+        h.plm.pause();
+        h.until(in_state(AudioState::Paused)).await;
+        let frames = h.capture.frames();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(h.capture.frames(), frames);
+
+        h.plm.play();
+        let statuses = h.until(in_state(AudioState::WillPlayWhenDataArrives)).await;
+        assert_eq!(
+            summary(&statuses),
+            [
+                (Some("tr-8"), AudioState::Playing),
+                (None, AudioState::WillPlayWhenDataArrives),
+            ]
+        );
+
+        let song = h.server.library().song("tr-8").unwrap();
+        let streams = h.capture.streams();
+        assert_eq!(streams.len(), 1);
+        assert!(streams[0].samples == decode(song.data()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn next_cuts_the_track_short() {
+        let mut h = Harness::start(Capture::new().paced()).await;
+        h.plm.set_playlist(h.album("al-1").await);
+        h.plm.play();
+        h.until(playing("tr-1")).await;
+        // Long enough for tr-2 to have downloaded; otherwise we'd wait for it
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        h.plm.next();
+        let statuses = h.until(in_state(AudioState::WillPlayWhenDataArrives)).await;
+        assert_eq!(
+            summary(&statuses),
+            [
+                (Some("tr-2"), AudioState::Playing),
+                (Some("tr-3"), AudioState::Playing),
+                (None, AudioState::WillPlayWhenDataArrives),
+            ]
+        );
+
+        let streams = h.capture.streams();
+        let full = decode(h.server.library().song("tr-1").unwrap().data());
+        assert_eq!(streams.len(), 3);
+        assert!(streams[0].samples.len() < full.len());
+        assert!(full.starts_with(&streams[0].samples));
+        assert!(streams[1].samples == decode(h.server.library().song("tr-2").unwrap().data()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn switching_outputs_carries_on_mid_track() {
+        let mut h = Harness::start(Capture::new().paced()).await;
+        h.plm.set_playlist(h.album("al-4").await);
+        h.plm.play();
+        h.until(playing("tr-8")).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let other = Capture::new().paced();
+        h.plm.set_output(OutputSelection::Capture(other.clone()));
+        h.until(in_state(AudioState::WillPlayWhenDataArrives)).await;
+
+        let (before, after) = (h.capture.streams(), other.streams());
+        assert_eq!((before.len(), after.len()), (1, 1));
+        assert!(!before[0].samples.is_empty() && !after[0].samples.is_empty());
+        let song = h.server.library().song("tr-8").unwrap();
+        assert!([&before[0].samples[..], &after[0].samples[..]].concat() == decode(song.data()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stopping_goes_quiet() {
+        let mut h = Harness::start(Capture::new().paced()).await;
+        h.plm.set_playlist(h.album("al-1").await);
+        h.plm.play();
+        h.until(playing("tr-1")).await;
+
+        h.plm.stop();
+        let statuses = h.until(in_state(AudioState::Stopped)).await;
+        assert_eq!(summary(&statuses), [(None, AudioState::Stopped)]);
+
+        let frames = h.capture.frames();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(h.capture.frames(), frames);
+        assert_eq!(h.capture.streams().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn radio_asks_again_before_giving_up() {
+        let mut h = Harness::start(Capture::new().paced()).await;
+        // Fifty Hertz is the only drone there is, so after the first
+        // lot there's nothing new
+        h.plm.start_radio(Radio::Random {
+            genre: Some("Drone".to_owned()),
+            from_year: None,
+            to_year: None,
+        });
+        h.until(|s| s.radio.is_some()).await;
+        h.until(|s| s.radio.is_none()).await;
+
+        let requests = h.server.requests();
+        let asked = requests.iter().filter(|r| r.endpoint == "getRandomSongs");
+        assert_eq!(asked.count(), 2 + RADIO_MAX_MISSES);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn plays_it_again_from_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TrackCache::new(dir.path().to_path_buf(), 100 << 20);
+        let mut h = Harness::start_with_cache(Capture::new().paced(), Some(Arc::new(cache))).await;
+        for _ in 0..2 {
+            h.plm.set_playlist(h.album("al-4").await);
+            h.plm.play();
+            h.until(playing("tr-8")).await;
+            h.until(in_state(AudioState::WillPlayWhenDataArrives)).await;
+        }
+
+        let streamed = h.server.requests();
+        let streamed = streamed.iter().filter(|r| r.endpoint == "stream");
+        assert_eq!(streamed.count(), 1);
+        let song = h.server.library().song("tr-8").unwrap();
+        let streams = h.capture.streams();
+        assert_eq!(streams.len(), 2);
+        assert!(streams.iter().all(|s| s.samples == decode(song.data())));
+    }
+}
@@ -0,0 +1,234 @@
+//! Sample rate conversion, for when the output can't play at the
+//! track's rate.
+//!
+//! It's a windowed sinc interpolator, with the kernel worked out ahead
+//! of time at a fixed number of fractional positions.
+
+use std::f64::consts::PI;
+
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+
+/// Input frames either side of each output frame that go into it
+const HALF_TAPS: usize = 16;
+const TAPS: usize = 2 * HALF_TAPS;
+
+/// How finely the distance between input frames is divided up
+const PHASES: usize = 256;
+
+/// Converts a stream of audio from one rate to another. It holds back
+/// a few frames of input to look ahead with, which `flush` lets out.
+pub struct Resampler {
+    from: u64,
+    to: u64,
+    /// `TAPS` weights for each of `PHASES` positions between two input
+    /// frames
+    kernel: Vec<f32>,
+    /// Per channel, the input that's still needed. Starts with zeros,
+    /// so the first frame is in the middle of the kernel.
+    input: Vec<Vec<f32>>,
+    /// Where the next output frame falls in `input`: a frame, and
+    /// `to`ths of the way on from it. Kept exact so it doesn't drift.
+    pos: usize,
+    pos_frac: u64,
+    converted: AudioBuffer<f32>,
+    out: AudioBuffer<f32>,
+}
+
+impl Resampler {
+    /// From `from` Hz to the rate in `spec`. Each `process` call is
+    /// given at most `capacity` frames.
+    pub fn new(from: u32, spec: SignalSpec, capacity: u64) -> Resampler {
+        let step = from as f64 / spec.rate as f64;
+        // Going down, anything above the new Nyquist frequency has to go
+        let cutoff = step.recip().min(1.0) * 0.95;
+
+        let mut kernel = Vec::with_capacity(PHASES * TAPS);
+        for phase in 0..PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let weights: Vec<f64> = (0..TAPS)
+                .map(|tap| sinc_weight(frac + (HALF_TAPS - 1) as f64 - tap as f64, cutoff))
+                .collect();
+            // No gain at 0 Hz, whatever the phase
+            let sum: f64 = weights.iter().sum();
+            kernel.extend(weights.iter().map(|w| (w / sum) as f32));
+        }
+
+        Resampler {
+            from: from as u64,
+            to: spec.rate as u64,
+            kernel,
+            input: vec![vec![0.0; HALF_TAPS - 1]; spec.channels.count()],
+            pos: HALF_TAPS - 1,
+            pos_frac: 0,
+            converted: AudioBuffer::new(capacity, SignalSpec::new(from, spec.channels)),
+            out: AudioBuffer::new(output_frames(capacity, from, spec.rate), spec),
+        }
+    }
+
+    /// The most frames a `process` call can take
+    pub fn input_capacity(&self) -> usize {
+        self.converted.capacity()
+    }
+
+    /// Resample some more input. What comes out lags behind what goes
+    /// in.
+    pub fn process(&mut self, decoded: AudioBufferRef<'_>) -> &AudioBuffer<f32> {
+        decoded.convert(&mut self.converted);
+        for (ch, input) in self.input.iter_mut().enumerate() {
+            input.extend_from_slice(self.converted.chan(ch));
+        }
+
+        self.out.clear();
+        let available = self.input[0].len();
+        let mut frames = 0;
+        while self.pos + HALF_TAPS < available && frames < self.out.capacity() {
+            self.render_frame(frames);
+            frames += 1;
+        }
+        self.consume();
+        &self.out
+    }
+
+    /// Let out what's been held back, as though the input carried on
+    /// in silence. Nothing should be processed after.
+    pub fn flush(&mut self) -> &AudioBuffer<f32> {
+        let end = self.input[0].len();
+        for input in &mut self.input {
+            input.resize(input.len() + HALF_TAPS, 0.0);
+        }
+
+        self.out.clear();
+        let mut frames = 0;
+        while self.pos < end && frames < self.out.capacity() {
+            self.render_frame(frames);
+            frames += 1;
+        }
+        self.consume();
+        &self.out
+    }
+
+    /// Work out the output frame at `pos` into `out` at `index`
+    fn render_frame(&mut self, index: usize) {
+        let first = self.pos + 1 - HALF_TAPS;
+        let phase = (self.pos_frac * PHASES as u64 / self.to) as usize;
+        let weights = &self.kernel[phase * TAPS..][..TAPS];
+
+        self.out.render_reserved(Some(1));
+        for (ch, input) in self.input.iter().enumerate() {
+            self.out.chan_mut(ch)[index] = input[first..first + TAPS]
+                .iter()
+                .zip(weights)
+                .map(|(s, w)| s * w)
+                .sum();
+        }
+        self.pos_frac += self.from;
+        self.pos += (self.pos_frac / self.to) as usize;
+        self.pos_frac %= self.to;
+    }
+
+    /// Drop the input that's behind every output frame still to come
+    fn consume(&mut self) {
+        let used = (self.pos + 1).saturating_sub(HALF_TAPS);
+        for input in &mut self.input {
+            input.drain(..used);
+        }
+        self.pos -= used;
+    }
+}
+
+/// The most output frames `frames` of input can make
+pub fn output_frames(frames: u64, from: u32, to: u32) -> u64 {
+    (frames * to as u64).div_ceil(from as u64) + 1
+}
+
+/// A low-pass filter's response `x` frames from its centre, tapered
+/// off with a Blackman window.
+fn sinc_weight(x: f64, cutoff: f64) -> f64 {
+    let half = HALF_TAPS as f64;
+    if x.abs() >= half {
+        return 0.0;
+    }
+    let sinc = if x == 0.0 {
+        1.0
+    } else {
+        (PI * cutoff * x).sin() / (PI * cutoff * x)
+    };
+    let window = 0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+    cutoff * sinc * window
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use symphonia::core::audio::{AsAudioBufferRef, Channels};
+
+    fn sine(rate: u32, hz: f64, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2.0 * PI * hz * i as f64 / rate as f64).sin() as f32 * 0.5)
+            .collect()
+    }
+
+    /// All of `samples`, resampled in chunks of `chunk` frames
+    fn resample(samples: &[f32], from: u32, to: u32, chunk: usize) -> Vec<f32> {
+        let spec = SignalSpec::new(to, Channels::FRONT_LEFT);
+        let mut resampler = Resampler::new(from, spec, chunk as u64);
+        let mut out = vec![];
+        for chunk in samples.chunks(chunk) {
+            let mut buf =
+                AudioBuffer::<f32>::new(chunk.len() as u64, SignalSpec::new(from, spec.channels));
+            buf.render_reserved(Some(chunk.len()));
+            buf.chan_mut(0).copy_from_slice(chunk);
+            out.extend_from_slice(resampler.process(buf.as_audio_buffer_ref()).chan(0));
+        }
+        out.extend_from_slice(resampler.flush().chan(0));
+        out
+    }
+
+    #[test]
+    fn keeps_the_tune() {
+        for (from, to) in [
+            (44100, 48000),
+            (48000, 44100),
+            (96000, 48000),
+            (22050, 48000),
+        ] {
+            let input = sine(from, 1000.0, from as usize / 4);
+            let output = resample(&input, from, to, 1152);
+
+            let expected = input.len() as f64 * to as f64 / from as f64;
+            assert!(
+                (output.len() as f64 - expected).abs() <= 1.0,
+                "{from} to {to}"
+            );
+
+            // Away from the ends, where it fades in and out
+            let ideal = sine(to, 1000.0, output.len());
+            let worst = output[HALF_TAPS * 4..output.len() - HALF_TAPS * 4]
+                .iter()
+                .zip(&ideal[HALF_TAPS * 4..])
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            assert!(worst < 0.005, "{from} to {to} is off by {worst}");
+        }
+    }
+
+    #[test]
+    fn chunking_makes_no_difference() {
+        let input = sine(44100, 440.0, 10000);
+        assert_eq!(
+            resample(&input, 44100, 48000, 1000),
+            resample(&input, 44100, 48000, 77)
+        );
+    }
+
+    #[test]
+    fn filters_what_wont_fit() {
+        // Above the 22050 Hz Nyquist frequency of the output
+        let input = sine(96000, 30000.0, 48000);
+        let output = resample(&input, 96000, 44100, 4096);
+        let loudest = output[100..output.len() - 100]
+            .iter()
+            .fold(0.0, |max: f32, s| max.max(s.abs()));
+        assert!(loudest < 0.01, "{loudest}");
+    }
+}
@@ -0,0 +1,123 @@
+//! The connection to whichever server profile is in use, and switching
+//! between them.
+
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::{
+    cache::TrackCache,
+    config::{Config, ConfigError, Profile},
+    keyring::{self, KeyringError},
+    library::{ConnectionError, Library},
+    output::OutputSelection,
+    plm::PlaylistManager,
+};
+
+/// A library and playlist manager for one profile. Dropping the last
+/// reference to one shuts down its playback.
+pub struct Session {
+    pub profile: String,
+    pub library: Arc<Library>,
+    pub plm: Arc<PlaylistManager>,
+}
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("There's no profile called {0}")]
+    NoSuchProfile(String),
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    Keyring(#[from] KeyringError),
+
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+}
+
+/// Owns the current session. Anything that needs the library or
+/// playlist manager subscribes, and picks up the new ones whenever the
+/// profile is switched.
+pub struct SessionManager {
+    current: watch::Sender<Option<Arc<Session>>>,
+    /// Where every session plays to
+    output: Mutex<OutputSelection>,
+    /// Shared by every session; it knows which server a track's from
+    cache: Option<Arc<TrackCache>>,
+}
+
+impl SessionManager {
+    pub fn new(output: OutputSelection, cache: Option<TrackCache>) -> SessionManager {
+        let (current, _) = watch::channel(None);
+        SessionManager {
+            current,
+            output: Mutex::new(output),
+            cache: cache.map(Arc::new),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<Arc<Session>>> {
+        self.current.subscribe()
+    }
+
+    pub fn current(&self) -> Option<Arc<Session>> {
+        self.current.borrow().clone()
+    }
+
+    pub fn output(&self) -> OutputSelection {
+        self.output.lock().unwrap().clone()
+    }
+
+    /// Play to `output`, now and in sessions to come.
+    pub fn set_output(&self, output: OutputSelection) {
+        *self.output.lock().unwrap() = output.clone();
+        if let Some(session) = self.current() {
+            session.plm.set_output(output);
+        }
+    }
+
+    /// Switch to the profile called `name` in the config file.
+    pub async fn switch_to(&self, name: &str) -> Result<Arc<Session>, SessionError> {
+        let config = Config::load()?;
+        let profile = config
+            .profile(name)
+            .ok_or_else(|| SessionError::NoSuchProfile(name.to_string()))?
+            .clone();
+        self.open(profile).await
+    }
+
+    /// Switch to `profile`, looking up its password.
+    pub async fn open(&self, mut profile: Profile) -> Result<Arc<Session>, SessionError> {
+        let password = keyring::resolve_password(&mut profile).await?;
+        self.start(profile, &password)
+    }
+
+    /// Switch to `profile`, with a password that's already known.
+    pub fn start(&self, profile: Profile, password: &str) -> Result<Arc<Session>, SessionError> {
+        info!(profile = profile.name.as_str(), "Starting session");
+        let library = Arc::new(Library::new(profile.clone(), password)?);
+        let resuming = library.clone();
+        tokio::spawn(async move { resuming.resume_pins().await });
+        let plm = Arc::new(PlaylistManager::new(
+            library.clone(),
+            self.output(),
+            self.cache.clone(),
+        ));
+        let session = Arc::new(Session {
+            profile: profile.name,
+            library,
+            plm,
+        });
+
+        if let Some(old) = self.current.send_replace(Some(session.clone())) {
+            // Don't leave it playing while the last references to it
+            // are let go of.
+            old.plm.stop();
+        }
+        Ok(session)
+    }
+}
@@ -2,12 +2,19 @@ use std::sync::Arc;
 
 use subsonic_client::AlbumListType;
 use tokio::sync::Mutex;
+use tracing::warn;
 
 use crate::{
     library::{Album, Library},
     ui_interface::{AlbumsEmitter, AlbumsList, AlbumsTrait},
 };
 
+/// What a fetch came back with: the albums, or what went wrong.
+type Fetched = Result<Vec<Album>, String>;
+
+/// Albums that couldn't be pinned, and why
+type PinFailures = Vec<(String, String)>;
+
 pub struct Albums {
     emit: AlbumsEmitter,
     model: AlbumsList,
@@ -15,11 +22,58 @@ pub struct Albums {
     library: Option<Arc<Library>>,
 
     list: Vec<Album>,
-    incoming: Arc<Mutex<Option<Vec<Album>>>>,
+    incoming: Arc<Mutex<Option<Fetched>>>,
+    pin_failures: Arc<Mutex<PinFailures>>,
     album_list_type: AlbumListType,
+    year_range: (u16, u16),
+    earliest_year: u16,
+    incoming_earliest_year: Arc<Mutex<Option<u16>>>,
     search: String,
+    error: String,
+}
+
+impl Albums {
+    fn set_error(&mut self, error: String) {
+        if self.error != error {
+            self.error = error;
+            self.emit.error_changed();
+        }
+    }
+
+    /// The list `sort_order` names, None if it isn't one
+    fn list_type(&self, order: &str) -> Option<AlbumListType> {
+        Some(match order {
+            "random" => AlbumListType::Random,
+            "newest" => AlbumListType::Newest,
+            "highest" => AlbumListType::Highest,
+            "frequent" => AlbumListType::Frequent,
+            "recent" => AlbumListType::Recent,
+            "by_name" => AlbumListType::AlphabeticalByName,
+            "by_artist" => AlbumListType::AlphabeticalByArtist,
+            "starred" => AlbumListType::Starred,
+            "by_year" => AlbumListType::ByYear {
+                from_year: self.year_range.0,
+                to_year: self.year_range.1,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Remember the sort order and year range for next time
+    fn save_sort(&self) {
+        if let Some(library) = &self.library {
+            library.set_album_sort(self.sort_order(), self.year_range);
+        }
+    }
 }
 
+/// The year range used by "by_year" until the user picks another one.
+const DEFAULT_YEAR_RANGE: (u16, u16) = (1990, 1999);
+
+/// Where the years to pick from start, until the server says how old
+/// its oldest album is.
+const DEFAULT_EARLIEST_YEAR: u16 = 1900;
+
 impl AlbumsTrait for Albums {
     fn new(emit: AlbumsEmitter, model: AlbumsList) -> Self {
         Self {
@@ -28,8 +82,13 @@ impl AlbumsTrait for Albums {
             library: None,
             list: vec![],
             incoming: Arc::new(Mutex::new(None)),
+            pin_failures: Default::default(),
             album_list_type: AlbumListType::Random,
+            year_range: DEFAULT_YEAR_RANGE,
+            earliest_year: DEFAULT_EARLIEST_YEAR,
+            incoming_earliest_year: Default::default(),
             search: Default::default(),
+            error: String::new(),
         }
     }
 
@@ -42,6 +101,35 @@ impl AlbumsTrait for Albums {
             let arc_ref = &*(p as *const Arc<Library>);
             self.library = Some(arc_ref.clone());
         }
+
+        let library = self.library.as_ref().unwrap().clone();
+
+        // Sorted the way it was last time
+        let (order, years) = library.album_sort();
+        if let Some(years) = years.filter(|years| *years != self.year_range) {
+            self.year_range = years;
+            self.emit.from_year_changed();
+            self.emit.to_year_changed();
+        }
+        if let Some(list_type) = order.and_then(|order| self.list_type(&order)) {
+            if self.album_list_type != list_type {
+                self.album_list_type = list_type;
+                self.emit.sort_order_changed();
+            }
+        }
+
+        let mut emit = self.emit.clone();
+        let incoming = self.incoming_earliest_year.clone();
+        tokio::spawn(async move {
+            match library.earliest_year().await {
+                Ok(Some(year)) => {
+                    *incoming.lock().await = Some(year);
+                    emit.invoke_handle_incoming_earliest_year();
+                }
+                Ok(None) => (),
+                Err(e) => warn!("Couldn't find the earliest year: {e}"),
+            }
+        });
     }
 
     fn sort_order(&self) -> &str {
@@ -54,32 +142,55 @@ impl AlbumsTrait for Albums {
             AlbumListType::AlphabeticalByName => "by_name",
             AlbumListType::AlphabeticalByArtist => "by_artist",
             AlbumListType::Starred => "starred",
-            AlbumListType::ByYear { .. } => "",
+            AlbumListType::ByYear { .. } => "by_year",
             AlbumListType::ByGenre { .. } => "",
         }
     }
 
     fn set_sort_order(&mut self, order: String) {
-        let order: String = order.into();
-        let new_album_list_type = match order.as_str() {
-            "random" => AlbumListType::Random,
-            "newest" => AlbumListType::Newest,
-            "highest" => AlbumListType::Highest,
-            "frequent" => AlbumListType::Frequent,
-            "recent" => AlbumListType::Recent,
-            "by_name" => AlbumListType::AlphabeticalByName,
-            "by_artist" => AlbumListType::AlphabeticalByArtist,
-            "starred" => AlbumListType::Starred,
-            _ => return,
+        let new_album_list_type = match self.list_type(&order) {
+            Some(list_type) => list_type,
+            None => return,
         };
 
         if self.album_list_type != new_album_list_type {
             self.album_list_type = new_album_list_type;
             self.emit.sort_order_changed();
+            self.save_sort();
             self.fetch()
         }
     }
 
+    fn from_year(&self) -> u16 {
+        self.year_range.0
+    }
+
+    fn to_year(&self) -> u16 {
+        self.year_range.1
+    }
+
+    fn earliest_year(&self) -> u16 {
+        self.earliest_year
+    }
+
+    /// Change the year range. If we're currently browsing by year,
+    /// this refetches the list.
+    fn set_year_range(&mut self, from_year: u16, to_year: u16) {
+        if self.year_range == (from_year, to_year) {
+            return;
+        }
+
+        self.year_range = (from_year, to_year);
+        self.emit.from_year_changed();
+        self.emit.to_year_changed();
+        self.save_sort();
+
+        if let AlbumListType::ByYear { .. } = self.album_list_type {
+            self.album_list_type = AlbumListType::ByYear { from_year, to_year };
+            self.fetch();
+        }
+    }
+
     fn search(&self) -> &str {
         &self.search
     }
@@ -110,6 +221,13 @@ impl AlbumsTrait for Albums {
             .unwrap_or_default()
     }
 
+    fn artist_id(&self, index: usize) -> &str {
+        self.list
+            .get(index)
+            .map(|a| a.artist_id.as_str())
+            .unwrap_or_default()
+    }
+
     fn cover_url(&self, index: usize) -> &str {
         self.list
             .get(index)
@@ -124,6 +242,41 @@ impl AlbumsTrait for Albums {
             .unwrap_or_default()
     }
 
+    fn pinned(&self, index: usize) -> bool {
+        self.list.get(index).map(|a| a.pinned).unwrap_or_default()
+    }
+
+    /// Pin or unpin an album. Pinning downloads it in the background,
+    /// and if that fails, it's unpinned again.
+    fn set_pinned(&mut self, index: usize, value: bool) -> bool {
+        let album = match self.list.get_mut(index) {
+            Some(album) if album.pinned != value => album,
+            _ => return false,
+        };
+        album.pinned = value;
+
+        let library = self.library.as_ref().unwrap().clone();
+        let album_id = album.album_id.clone();
+        if value {
+            let mut emit = self.emit.clone();
+            let pin_failures = self.pin_failures.clone();
+            tokio::spawn(async move {
+                if let Err(e) = library.pin_album(&album_id).await {
+                    warn!(album_id, "Couldn't pin the album: {e}");
+                    // Rather than leave half of it on disk
+                    if let Err(e) = library.unpin_album(&album_id) {
+                        warn!(album_id, "Couldn't unpin the album: {e}");
+                    }
+                    pin_failures.lock().await.push((album_id, e.to_string()));
+                    emit.invoke_handle_incoming_pin_failures();
+                }
+            });
+        } else if let Err(e) = library.unpin_album(&album_id) {
+            warn!(album_id, "Couldn't unpin the album: {e}");
+        }
+        true
+    }
+
     /// Fetch new albums from the library
     fn fetch(&mut self) {
         self.model.begin_reset_model();
@@ -138,7 +291,7 @@ impl AlbumsTrait for Albums {
             let album_list_type = self.album_list_type.clone();
             tokio::spawn(async move {
                 let albums = library.list_albums(album_list_type).await;
-                *incoming.lock().await = Some(albums);
+                *incoming.lock().await = Some(albums.map_err(|e| e.to_string()));
                 emit.invoke_handle_incoming_list();
             });
         } else {
@@ -146,7 +299,7 @@ impl AlbumsTrait for Albums {
 
             tokio::spawn(async move {
                 let albums = library.search(search).await;
-                *incoming.lock().await = Some(albums);
+                *incoming.lock().await = Some(albums.map_err(|e| e.to_string()));
                 emit.invoke_handle_incoming_list();
             });
         }
@@ -154,10 +307,47 @@ impl AlbumsTrait for Albums {
 
     /// The album list was updated. Dispatched on the ui thread by `fetch`.
     fn handle_incoming_list(&mut self) {
-        if let Some(albums) = self.incoming.blocking_lock().take() {
-            self.model.begin_reset_model();
-            self.list = albums;
-            self.model.end_reset_model();
+        let result = self.incoming.blocking_lock().take();
+        match result {
+            Some(Ok(albums)) => {
+                self.model.begin_reset_model();
+                self.list = albums;
+                self.model.end_reset_model();
+                self.set_error(String::new());
+            }
+            Some(Err(e)) => {
+                warn!("Couldn't fetch albums: {e}");
+                self.set_error(format!("Couldn't fetch albums: {e}"));
+            }
+            None => (),
         }
     }
+
+    /// Albums couldn't be pinned. Dispatched on the ui thread by
+    /// `set_pinned`.
+    fn handle_incoming_pin_failures(&mut self) {
+        let failures = std::mem::take(&mut *self.pin_failures.blocking_lock());
+        for (album_id, e) in failures {
+            if let Some(index) = self.list.iter().position(|a| a.album_id == album_id) {
+                self.list[index].pinned = false;
+                self.model.data_changed(index, index);
+            }
+            self.set_error(format!("Couldn't pin the album: {e}"));
+        }
+    }
+
+    /// The server said how old its oldest album is. Dispatched on the
+    /// ui thread by `set_library`.
+    fn handle_incoming_earliest_year(&mut self) {
+        if let Some(year) = self.incoming_earliest_year.blocking_lock().take() {
+            if self.earliest_year != year {
+                self.earliest_year = year;
+                self.emit.earliest_year_changed();
+            }
+        }
+    }
+
+    fn error(&self) -> &str {
+        &self.error
+    }
 }
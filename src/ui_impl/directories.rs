@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    library::{DirectoryEntry, Library, TrackMetadata},
+    plm::PlaylistManager,
+    ui_interface::{DirectoriesEmitter, DirectoriesList, DirectoriesTrait},
+};
+
+/// Browses the server's file tree: indexes, then artist directories,
+/// then whatever is below them.
+pub struct Directories {
+    emit: DirectoriesEmitter,
+    model: DirectoriesList,
+
+    library: Option<Arc<Library>>,
+    plm: Option<Arc<PlaylistManager>>,
+
+    /// (id, name) of each directory opened below the indexes
+    path: Vec<(String, String)>,
+    list: Vec<DirectoryEntry>,
+    incoming: Arc<Mutex<Option<Vec<DirectoryEntry>>>>,
+}
+
+impl Directories {
+    fn library(&self) -> &Arc<Library> {
+        self.library.as_ref().unwrap()
+    }
+
+    fn plm(&self) -> &Arc<PlaylistManager> {
+        self.plm.as_ref().unwrap()
+    }
+
+    fn path_changed(&mut self) {
+        self.emit.title_changed();
+        self.emit.can_go_up_changed();
+        self.fetch();
+    }
+
+    /// Resolve an entry to the tracks it contains, then hand them to
+    /// `f` along with the playlist manager.
+    fn with_tracks(
+        &self,
+        index: u32,
+        f: impl FnOnce(&PlaylistManager, Vec<TrackMetadata>) + Send + 'static,
+    ) {
+        let entry = match self.list.get(index as usize) {
+            Some(e) => e.clone(),
+            None => return,
+        };
+        let plm = self.plm().clone();
+
+        match entry.track {
+            Some(track) => f(&plm, vec![track]),
+            None => {
+                let library = self.library().clone();
+                tokio::spawn(async move {
+                    match library.directory_tracks(&entry.id).await {
+                        Ok(tracks) => f(&plm, tracks),
+                        Err(e) => warn!("Couldn't list the tracks in {}: {e}", entry.name),
+                    }
+                });
+            }
+        }
+    }
+}
+
+impl DirectoriesTrait for Directories {
+    fn new(emit: DirectoriesEmitter, model: DirectoriesList) -> Self {
+        Self {
+            emit,
+            model,
+            library: None,
+            plm: None,
+            path: vec![],
+            list: vec![],
+            incoming: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn emit(&mut self) -> &mut DirectoriesEmitter {
+        &mut self.emit
+    }
+
+    fn set_library(&mut self, p: u64) {
+        unsafe {
+            let arc_ref = &*(p as *const Arc<Library>);
+            self.library = Some(arc_ref.clone());
+        }
+    }
+
+    fn set_plm(&mut self, p: u64) {
+        unsafe {
+            let arc_ref = &*(p as *const Arc<PlaylistManager>);
+            self.plm = Some(arc_ref.clone());
+        }
+    }
+
+    fn title(&self) -> &str {
+        self.path
+            .last()
+            .map(|(_, name)| name.as_str())
+            .unwrap_or("Files")
+    }
+
+    fn can_go_up(&self) -> bool {
+        !self.path.is_empty()
+    }
+
+    fn row_count(&self) -> usize {
+        self.list.len()
+    }
+
+    fn entry_id(&self, index: usize) -> &str {
+        self.list
+            .get(index)
+            .map(|e| e.id.as_str())
+            .unwrap_or_default()
+    }
+
+    fn name(&self, index: usize) -> &str {
+        self.list
+            .get(index)
+            .map(|e| e.name.as_str())
+            .unwrap_or_default()
+    }
+
+    fn artist(&self, index: usize) -> &str {
+        self.list
+            .get(index)
+            .map(|e| e.artist.as_str())
+            .unwrap_or_default()
+    }
+
+    fn is_dir(&self, index: usize) -> bool {
+        self.list
+            .get(index)
+            .map(|e| e.is_dir())
+            .unwrap_or_default()
+    }
+
+    /// Fetch the entries of the current directory from the library
+    fn fetch(&mut self) {
+        self.model.begin_reset_model();
+        self.list.clear();
+        self.model.end_reset_model();
+
+        let library = self.library().clone();
+        let mut emit = self.emit.clone();
+        let incoming = self.incoming.clone();
+        let dir_id = self.path.last().map(|(id, _)| id.clone());
+
+        tokio::spawn(async move {
+            let entries = match dir_id {
+                Some(id) => library.directory_entries(&id).await,
+                None => library.index_entries().await,
+            };
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Couldn't list the directory: {e}");
+                    return;
+                }
+            };
+            *incoming.lock().await = Some(entries);
+            emit.invoke_handle_incoming_list();
+        });
+    }
+
+    fn open(&mut self, index: u32) {
+        let (id, name) = match self.list.get(index as usize) {
+            Some(e) if e.is_dir() => (e.id.clone(), e.name.clone()),
+            _ => return,
+        };
+
+        self.path.push((id, name));
+        self.path_changed();
+    }
+
+    fn up(&mut self) {
+        if self.path.pop().is_some() {
+            self.path_changed();
+        }
+    }
+
+    /// Replace the playlist with everything under the entry, and play it.
+    fn play(&mut self, index: u32) {
+        self.with_tracks(index, |plm, tracks| {
+            plm.set_playlist(tracks);
+            plm.play();
+        });
+    }
+
+    /// Add everything under the entry to the end of the playlist.
+    fn enqueue(&mut self, index: u32) {
+        self.with_tracks(index, |plm, tracks| plm.enqueue(tracks));
+    }
+
+    /// The entry list was updated. Dispatched on the ui thread by `fetch`.
+    fn handle_incoming_list(&mut self) {
+        if let Some(entries) = self.incoming.blocking_lock().take() {
+            self.model.begin_reset_model();
+            self.list = entries;
+            self.model.end_reset_model();
+        }
+    }
+}
@@ -1,5 +1,15 @@
 mod albums;
+mod directories;
+mod music_folders;
+mod output_devices;
 mod player;
+mod profiles;
+mod setup;
 
 pub use albums::*;
+pub use directories::*;
+pub use music_folders::*;
+pub use output_devices::*;
 pub use player::*;
+pub use profiles::*;
+pub use setup::*;
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    library::{Library, MusicFolder},
+    ui_interface::{MusicFoldersEmitter, MusicFoldersList, MusicFoldersTrait},
+};
+
+pub struct MusicFolders {
+    emit: MusicFoldersEmitter,
+    model: MusicFoldersList,
+
+    library: Option<Arc<Library>>,
+
+    list: Vec<(MusicFolder, bool)>,
+    incoming: Arc<Mutex<Option<Vec<MusicFolder>>>>,
+}
+
+impl MusicFolders {
+    fn library(&self) -> &Arc<Library> {
+        self.library.as_ref().unwrap()
+    }
+}
+
+impl MusicFoldersTrait for MusicFolders {
+    fn new(emit: MusicFoldersEmitter, model: MusicFoldersList) -> Self {
+        Self {
+            emit,
+            model,
+            library: None,
+            list: vec![],
+            incoming: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn emit(&mut self) -> &mut MusicFoldersEmitter {
+        &mut self.emit
+    }
+
+    fn set_library(&mut self, p: u64) {
+        unsafe {
+            let arc_ref = &*(p as *const Arc<Library>);
+            self.library = Some(arc_ref.clone());
+        }
+    }
+
+    fn row_count(&self) -> usize {
+        self.list.len()
+    }
+
+    fn folder_id(&self, index: usize) -> i64 {
+        self.list.get(index).map(|(f, _)| f.id).unwrap_or_default()
+    }
+
+    fn name(&self, index: usize) -> &str {
+        self.list
+            .get(index)
+            .map(|(f, _)| f.name.as_str())
+            .unwrap_or_default()
+    }
+
+    fn selected(&self, index: usize) -> bool {
+        self.list
+            .get(index)
+            .map(|(_, selected)| *selected)
+            .unwrap_or_default()
+    }
+
+    /// Select or deselect a folder. The selection is handed to the
+    /// library, which scopes album lists and searches to it.
+    fn set_selected(&mut self, index: usize, value: bool) -> bool {
+        match self.list.get_mut(index) {
+            Some((_, selected)) => *selected = value,
+            None => return false,
+        }
+
+        let ids = self
+            .list
+            .iter()
+            .filter(|(_, selected)| *selected)
+            .map(|(f, _)| f.id)
+            .collect();
+        self.library().set_music_folder_ids(ids);
+        true
+    }
+
+    /// Fetch the music folders from the library
+    fn fetch(&mut self) {
+        let library = self.library().clone();
+        let mut emit = self.emit.clone();
+        let incoming = self.incoming.clone();
+
+        tokio::spawn(async move {
+            let folders = match library.music_folders().await {
+                Ok(folders) => folders,
+                Err(e) => {
+                    warn!("Couldn't fetch the music folders: {e}");
+                    return;
+                }
+            };
+            *incoming.lock().await = Some(folders);
+            emit.invoke_handle_incoming_list();
+        });
+    }
+
+    /// The folder list was updated. Dispatched on the ui thread by `fetch`.
+    fn handle_incoming_list(&mut self) {
+        if let Some(folders) = self.incoming.blocking_lock().take() {
+            let selected_ids = self.library().music_folder_ids();
+            self.model.begin_reset_model();
+            self.list = folders
+                .into_iter()
+                .map(|f| {
+                    let selected = selected_ids.contains(&f.id);
+                    (f, selected)
+                })
+                .collect();
+            self.model.end_reset_model();
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    config::{AudioSettings, Config},
+    output::{self, OutputDevice, OutputSelection},
+    session::SessionManager,
+    ui_interface::{OutputDevicesEmitter, OutputDevicesList, OutputDevicesTrait},
+};
+
+/// The devices playback can go to. Picking one moves whatever's
+/// playing there, and saves it in the config for next time.
+pub struct OutputDevices {
+    emit: OutputDevicesEmitter,
+    model: OutputDevicesList,
+
+    sessions: Option<Arc<SessionManager>>,
+
+    /// The system default first, with an empty name, then whatever the
+    /// output listed.
+    list: Vec<OutputDevice>,
+    incoming: Arc<Mutex<Option<Vec<OutputDevice>>>>,
+}
+
+impl OutputDevices {
+    fn sessions(&self) -> &Arc<SessionManager> {
+        self.sessions.as_ref().unwrap()
+    }
+
+    fn settings(&self) -> AudioSettings {
+        self.sessions()
+            .output()
+            .settings()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn device(&self, index: usize) -> Option<&OutputDevice> {
+        self.list.get(index)
+    }
+}
+
+impl OutputDevicesTrait for OutputDevices {
+    fn new(emit: OutputDevicesEmitter, model: OutputDevicesList) -> Self {
+        Self {
+            emit,
+            model,
+            sessions: None,
+            list: vec![],
+            incoming: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn emit(&mut self) -> &mut OutputDevicesEmitter {
+        &mut self.emit
+    }
+
+    fn set_sessions(&mut self, p: u64) {
+        let sessions = unsafe { &*(p as *const Arc<SessionManager>) }.clone();
+        self.sessions = Some(sessions);
+    }
+
+    fn row_count(&self) -> usize {
+        self.list.len()
+    }
+
+    fn name(&self, index: usize) -> &str {
+        self.device(index)
+            .map(|d| d.name.as_str())
+            .unwrap_or_default()
+    }
+
+    fn description(&self, index: usize) -> &str {
+        self.device(index)
+            .map(|d| d.description.as_str())
+            .unwrap_or_default()
+    }
+
+    fn is_current(&self, index: usize) -> bool {
+        match self.device(index) {
+            Some(d) => self.settings().device.unwrap_or_default() == d.name,
+            None => false,
+        }
+    }
+
+    /// Ask the output what devices there are. That can block for a
+    /// while, so it's done off the ui thread.
+    fn fetch(&mut self) {
+        let settings = self.settings();
+        let mut emit = self.emit.clone();
+        let incoming = self.incoming.clone();
+
+        tokio::spawn(async move {
+            let devices =
+                match tokio::task::spawn_blocking(move || output::list_devices(&settings)).await {
+                    Ok(devices) => devices,
+                    Err(e) => {
+                        warn!("Couldn't list the output devices: {e}");
+                        return;
+                    }
+                };
+            *incoming.lock().await = Some(devices);
+            emit.invoke_handle_incoming_list();
+        });
+    }
+
+    /// Play to a device from now on, and at startup
+    fn select(&mut self, index: u32) {
+        let device = match self.device(index as usize) {
+            Some(d) if d.name.is_empty() => None,
+            Some(d) => Some(d.name.clone()),
+            None => return,
+        };
+
+        let mut settings = self.settings();
+        settings.device = device.clone();
+        self.sessions()
+            .set_output(OutputSelection::Configured(settings));
+        if !self.list.is_empty() {
+            self.model.data_changed(0, self.list.len() - 1);
+        }
+
+        let saved = Config::load_or_default().and_then(|mut config| {
+            config.audio.device = device;
+            config.save()
+        });
+        if let Err(e) = saved {
+            warn!("Couldn't save the output device: {e}");
+        }
+    }
+
+    /// The device list was updated. Dispatched on the ui thread by `fetch`.
+    fn handle_incoming_list(&mut self) {
+        if let Some(devices) = self.incoming.blocking_lock().take() {
+            let current = self.settings().device;
+            self.model.begin_reset_model();
+            self.list = vec![OutputDevice {
+                name: String::new(),
+                description: "Default".to_string(),
+            }];
+            self.list.extend(devices);
+            // Keep showing a chosen device that's gone away, so it's
+            // clear why playback is going somewhere else.
+            if let Some(name) = current {
+                if !self.list.iter().any(|d| d.name == name) {
+                    self.list.push(OutputDevice {
+                        description: format!("{name} (not found)"),
+                        name,
+                    });
+                }
+            }
+            self.model.end_reset_model();
+        }
+    }
+}
@@ -1,10 +1,15 @@
-use std::sync::Arc;
-use tokio::sync::watch;
+use rand::seq::SliceRandom;
+use std::{future::Future, sync::Arc};
+use tokio::sync::{watch, Mutex};
+use tracing::warn;
+
+use subsonic_client::Connectivity;
 
 use crate::{
     audio::AudioState,
-    library::Library,
-    plm::{PlaylistManager, PlmStatus},
+    library::{self, Library},
+    output::OutputPath,
+    plm::{PlaylistManager, PlmStatus, Radio},
     ui_interface::{PlayerEmitter, PlayerTrait},
 };
 
@@ -14,6 +19,9 @@ pub struct Player {
     plm: Option<Arc<PlaylistManager>>,
     plm_status: PlmStatus,
     plm_status_rx: watch::Receiver<PlmStatus>,
+    connectivity_rx: watch::Receiver<Connectivity>,
+    error: String,
+    incoming_error: Arc<Mutex<Option<String>>>,
 }
 
 impl Player {
@@ -24,11 +32,30 @@ impl Player {
     fn plm(&self) -> &Arc<PlaylistManager> {
         self.plm.as_ref().unwrap()
     }
+
+    /// Run `job` in the background, and show the error if it fails.
+    /// `action` finishes the sentence "Couldn't ...".
+    fn spawn_reporting_errors(
+        &mut self,
+        action: &'static str,
+        job: impl Future<Output = library::Result<()>> + Send + 'static,
+    ) {
+        let mut emit = self.emit.clone();
+        let incoming_error = self.incoming_error.clone();
+        tokio::spawn(async move {
+            if let Err(e) = job.await {
+                warn!("Couldn't {action}: {e}");
+                *incoming_error.lock().await = Some(format!("Couldn't {action}: {e}"));
+                emit.invoke_handle_incoming_error();
+            }
+        });
+    }
 }
 
 impl PlayerTrait for Player {
     fn new(emit: PlayerEmitter) -> Self {
         let (_, initial_plm_status_rx) = watch::channel(PlmStatus::default());
+        let (_, initial_connectivity_rx) = watch::channel(Connectivity::default());
 
         Player {
             emit,
@@ -36,6 +63,9 @@ impl PlayerTrait for Player {
             plm: None,
             plm_status: Default::default(),
             plm_status_rx: initial_plm_status_rx,
+            connectivity_rx: initial_connectivity_rx,
+            error: String::new(),
+            incoming_error: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -48,6 +78,40 @@ impl PlayerTrait for Player {
             let arc_ref = &*(p as *const Arc<Library>);
             self.library = Some(arc_ref.clone());
         }
+        self.connectivity_rx = self.library().connectivity();
+
+        let mut emit = self.emit.clone();
+        let mut poll_connectivity = self.connectivity_rx.clone();
+        tokio::spawn(async move {
+            // Ends when the library goes away
+            while poll_connectivity.changed().await.is_ok() {
+                emit.invoke_handle_incoming_connectivity();
+            }
+        });
+
+        self.handle_incoming_connectivity();
+    }
+
+    fn handle_incoming_connectivity(&mut self) {
+        self.emit.connectivity_changed();
+    }
+
+    /// "" while things are working, otherwise "retrying" or "offline"
+    fn connectivity(&self) -> &str {
+        match *self.connectivity_rx.borrow() {
+            Connectivity::Unknown | Connectivity::Online => "",
+            Connectivity::Retrying { .. } => "retrying",
+            Connectivity::Offline => "offline",
+        }
+    }
+
+    /// "bit_perfect" or "converted" while something's playing
+    fn output_path(&self) -> &str {
+        match self.plm_status.output_path {
+            Some(OutputPath::BitPerfect) => "bit_perfect",
+            Some(OutputPath::Converted) => "converted",
+            None => "",
+        }
     }
 
     fn set_plm(&mut self, p: u64) {
@@ -58,8 +122,8 @@ impl PlayerTrait for Player {
 
         let mut poll_status = self.plm_status_rx.clone();
         tokio::spawn(async move {
-            loop {
-                let _ = poll_status.changed().await;
+            // Ends when the playlist manager shuts down
+            while poll_status.changed().await.is_ok() {
                 emit.invoke_handle_incoming_plm_status();
             }
         });
@@ -81,6 +145,35 @@ impl PlayerTrait for Player {
         if new_plm_status.audio_state != self.plm_status.audio_state {
             self.emit.play_state_changed();
         }
+        if new_plm_status.radio != self.plm_status.radio {
+            self.emit.radio_changed();
+        }
+        if new_plm_status.output_path != self.plm_status.output_path {
+            self.emit.output_path_changed();
+        }
+        if new_plm_status.error != self.plm_status.error {
+            self.error = self.plm_status.error.clone().unwrap_or_default();
+            self.emit.error_changed();
+        }
+    }
+
+    /// Something in the background failed. Dispatched on the ui thread
+    /// by `spawn_reporting_errors`.
+    fn handle_incoming_error(&mut self) {
+        let error = self.incoming_error.blocking_lock().take();
+        if let Some(error) = error {
+            self.error = error;
+            self.emit.error_changed();
+        }
+    }
+
+    fn error(&self) -> &str {
+        &self.error
+    }
+
+    fn dismiss_error(&mut self) {
+        self.error.clear();
+        self.emit.error_changed();
     }
 
     fn current_album(&self) -> &str {
@@ -127,6 +220,35 @@ impl PlayerTrait for Player {
         }
     }
 
+    fn radio(&self) -> &str {
+        match self.plm_status.radio {
+            None => "",
+            Some(Radio::Random { .. }) => "random",
+            Some(Radio::Similar) => "similar",
+        }
+    }
+
+    /// Start random radio. An empty genre, or a zero year, means
+    /// that constraint isn't applied.
+    fn start_random_radio(&mut self, genre: String, from_year: u16, to_year: u16) {
+        let genre = if genre.is_empty() { None } else { Some(genre) };
+        let from_year = if from_year == 0 { None } else { Some(from_year) };
+        let to_year = if to_year == 0 { None } else { Some(to_year) };
+        self.plm().start_radio(Radio::Random {
+            genre,
+            from_year,
+            to_year,
+        });
+    }
+
+    fn start_similar_radio(&mut self) {
+        self.plm().start_radio(Radio::Similar);
+    }
+
+    fn stop_radio(&mut self) {
+        self.plm().stop_radio();
+    }
+
     fn next(&mut self) -> () {
         self.plm().next();
     }
@@ -147,10 +269,43 @@ impl PlayerTrait for Player {
         let library = self.library().clone();
         let plm = self.plm().clone();
 
-        tokio::spawn(async move {
-            let tracks = library.album_tracks(&id).await;
+        self.spawn_reporting_errors("load the album", async move {
+            let tracks = library.album_tracks(&id).await?;
+            plm.set_playlist(tracks);
+            plm.play();
+            Ok(())
+        });
+    }
+
+    /// Play the artist's top songs. Servers without any play
+    /// statistics for the artist get a shuffle of everything instead.
+    fn play_artist(&mut self, id: String, name: String) {
+        let library = self.library().clone();
+        let plm = self.plm().clone();
+
+        self.spawn_reporting_errors("load the artist's songs", async move {
+            let mut tracks = library.artist_top_tracks(&name).await?;
+            if tracks.is_empty() {
+                tracks = library.artist_tracks(&id).await?;
+                tracks.shuffle(&mut rand::thread_rng());
+            }
+            plm.set_playlist(tracks);
+            plm.play();
+            Ok(())
+        });
+    }
+
+    /// Play every song from all of the artist's albums, shuffled.
+    fn shuffle_artist(&mut self, id: String) {
+        let library = self.library().clone();
+        let plm = self.plm().clone();
+
+        self.spawn_reporting_errors("load the artist's songs", async move {
+            let mut tracks = library.artist_tracks(&id).await?;
+            tracks.shuffle(&mut rand::thread_rng());
             plm.set_playlist(tracks);
             plm.play();
+            Ok(())
         });
     }
 }
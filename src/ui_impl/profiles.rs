@@ -0,0 +1,232 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    config::{Config, Profile},
+    session::{Session, SessionManager},
+    ui_interface::{ProfilesEmitter, ProfilesList, ProfilesTrait},
+};
+
+/// The server profiles in the config file, and which one is in use.
+/// This is where the rest of the ui gets its library and playlist
+/// manager from: when `current` changes, they're handed out again.
+pub struct Profiles {
+    emit: ProfilesEmitter,
+    model: ProfilesList,
+
+    sessions: Option<Arc<SessionManager>>,
+    session: Option<Arc<Session>>,
+
+    config: Config,
+    error: String,
+    incoming_error: Arc<Mutex<Option<String>>>,
+}
+
+impl Profiles {
+    fn sessions(&self) -> &Arc<SessionManager> {
+        self.sessions.as_ref().unwrap()
+    }
+
+    fn profile(&self, index: usize) -> Option<&Profile> {
+        self.config.profiles.get(index)
+    }
+
+    fn set_error(&mut self, error: String) {
+        self.error = error;
+        self.emit.error_changed();
+    }
+
+    /// Change the config file. It's reread first, since the library
+    /// saves its profile's settings there too.
+    fn update_config(&mut self, f: impl FnOnce(&mut Config)) {
+        self.fetch();
+        self.model.begin_reset_model();
+        f(&mut self.config);
+        self.model.end_reset_model();
+
+        if let Err(e) = self.config.save() {
+            warn!("Couldn't save the config file: {e}");
+            self.set_error(format!("Couldn't save the config file: {e}"));
+        }
+    }
+}
+
+impl ProfilesTrait for Profiles {
+    fn new(emit: ProfilesEmitter, model: ProfilesList) -> Self {
+        Self {
+            emit,
+            model,
+            sessions: None,
+            session: None,
+            config: Config::default(),
+            error: String::new(),
+            incoming_error: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn emit(&mut self) -> &mut ProfilesEmitter {
+        &mut self.emit
+    }
+
+    /// Start following the session manager's current session.
+    fn set_sessions(&mut self, p: u64) {
+        let sessions = unsafe { &*(p as *const Arc<SessionManager>) }.clone();
+
+        let mut emit = self.emit.clone();
+        let mut session_rx = sessions.subscribe();
+        tokio::spawn(async move {
+            while session_rx.changed().await.is_ok() {
+                emit.invoke_handle_session_changed();
+            }
+        });
+
+        self.sessions = Some(sessions);
+        self.handle_session_changed();
+    }
+
+    fn current(&self) -> &str {
+        self.session
+            .as_ref()
+            .map(|s| s.profile.as_str())
+            .unwrap_or_default()
+    }
+
+    fn error(&self) -> &str {
+        &self.error
+    }
+
+    /// The current session's library, for `set_library` elsewhere.
+    /// Only valid until `current` changes.
+    fn library_ptr(&self) -> u64 {
+        self.session
+            .as_ref()
+            .map_or(0, |s| &s.library as *const _ as u64)
+    }
+
+    /// The current session's playlist manager, for `set_plm` elsewhere.
+    /// Only valid until `current` changes.
+    fn plm_ptr(&self) -> u64 {
+        self.session
+            .as_ref()
+            .map_or(0, |s| &s.plm as *const _ as u64)
+    }
+
+    fn row_count(&self) -> usize {
+        self.config.profiles.len()
+    }
+
+    fn name(&self, index: usize) -> &str {
+        self.profile(index)
+            .map(|p| p.name.as_str())
+            .unwrap_or_default()
+    }
+
+    fn url(&self, index: usize) -> &str {
+        self.profile(index)
+            .map(|p| p.url.as_str())
+            .unwrap_or_default()
+    }
+
+    fn username(&self, index: usize) -> &str {
+        self.profile(index)
+            .map(|p| p.username.as_str())
+            .unwrap_or_default()
+    }
+
+    fn is_default(&self, index: usize) -> bool {
+        match (self.profile(index), self.config.default_profile()) {
+            (Some(p), Some(default)) => p.name == default.name,
+            _ => false,
+        }
+    }
+
+    fn is_current(&self, index: usize) -> bool {
+        self.profile(index)
+            .map(|p| p.name == self.current())
+            .unwrap_or_default()
+    }
+
+    /// Reread the profiles from the config file
+    fn fetch(&mut self) {
+        self.model.begin_reset_model();
+        match Config::load_or_default() {
+            Ok(config) => self.config = config,
+            Err(e) => {
+                warn!("Couldn't read the config file: {e}");
+                self.set_error(format!("Couldn't read the config file: {e}"));
+            }
+        }
+        self.model.end_reset_model();
+    }
+
+    fn switch_to(&mut self, index: u32) {
+        let name = match self.profile(index as usize) {
+            Some(p) if p.name != self.current() => p.name.clone(),
+            _ => return,
+        };
+        self.set_error(String::new());
+
+        let sessions = self.sessions().clone();
+        let mut emit = self.emit.clone();
+        let incoming_error = self.incoming_error.clone();
+        tokio::spawn(async move {
+            // Success shows up as a session change
+            if let Err(e) = sessions.switch_to(&name).await {
+                warn!("Couldn't switch to profile {name}: {e}");
+                *incoming_error.lock().await = Some(format!("Couldn't switch to {name}: {e}"));
+                emit.invoke_handle_incoming_error();
+            }
+        });
+    }
+
+    /// Open this profile at startup from now on
+    fn make_default(&mut self, index: u32) {
+        let name = match self.profile(index as usize) {
+            Some(p) => p.name.clone(),
+            None => return,
+        };
+
+        self.update_config(|config| config.default_profile = Some(name));
+    }
+
+    /// Forget a profile. The one in use can't be removed. Its password
+    /// stays in the keyring, as other profiles may share it.
+    fn remove(&mut self, index: u32) {
+        let name = match self.profile(index as usize) {
+            Some(p) if p.name != self.current() => p.name.clone(),
+            _ => return,
+        };
+
+        self.update_config(|config| config.remove_profile(&name));
+    }
+
+    /// The session manager switched profiles. Dispatched on the ui
+    /// thread by the subscription in `set_sessions`.
+    fn handle_session_changed(&mut self) {
+        let session = self.sessions().current();
+        let changed = match (&session, &self.session) {
+            (Some(new), Some(old)) => !Arc::ptr_eq(new, old),
+            (None, None) => false,
+            _ => true,
+        };
+        if !changed {
+            return;
+        }
+
+        self.session = session;
+        // Setup may have added the profile we've just switched to
+        self.fetch();
+        self.emit.current_changed();
+    }
+
+    /// Switching profiles failed. Dispatched on the ui thread by
+    /// `switch_to`.
+    fn handle_incoming_error(&mut self) {
+        let error = self.incoming_error.blocking_lock().take();
+        if let Some(error) = error {
+            self.set_error(error);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::sync::Arc;
+
+use subsonic_client::ApiError;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    config::{Config, Profile},
+    keyring,
+    library::Library,
+    session::SessionManager,
+    ui_interface::{SetupEmitter, SetupTrait},
+};
+
+/// Adding a server profile, which is also the first-run setup. Once the
+/// details check out they're saved, and the session manager switches to
+/// the new profile.
+pub struct Setup {
+    emit: SetupEmitter,
+    sessions: Option<Arc<SessionManager>>,
+
+    name: String,
+    url: String,
+    username: String,
+    password: String,
+
+    checking: bool,
+    error: String,
+
+    incoming: Arc<Mutex<Option<Result<(), String>>>>,
+}
+
+impl SetupTrait for Setup {
+    fn new(emit: SetupEmitter) -> Self {
+        Self {
+            emit,
+            sessions: None,
+            name: String::new(),
+            url: String::new(),
+            username: String::new(),
+            password: String::new(),
+            checking: false,
+            error: String::new(),
+            incoming: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn emit(&mut self) -> &mut SetupEmitter {
+        &mut self.emit
+    }
+
+    fn set_sessions(&mut self, p: u64) {
+        unsafe {
+            let arc_ref = &*(p as *const Arc<SessionManager>);
+            self.sessions = Some(arc_ref.clone());
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_name(&mut self, value: String) {
+        self.name = value;
+        self.emit.name_changed();
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn set_url(&mut self, value: String) {
+        self.url = value;
+        self.emit.url_changed();
+    }
+
+    fn username(&self) -> &str {
+        &self.username
+    }
+
+    fn set_username(&mut self, value: String) {
+        self.username = value;
+        self.emit.username_changed();
+    }
+
+    fn password(&self) -> &str {
+        &self.password
+    }
+
+    fn set_password(&mut self, value: String) {
+        self.password = value;
+        self.emit.password_changed();
+    }
+
+    fn checking(&self) -> bool {
+        self.checking
+    }
+
+    fn error(&self) -> &str {
+        &self.error
+    }
+
+    /// Try to log in with the entered details, and save them if it works
+    fn check_and_save(&mut self) {
+        if self.checking {
+            return;
+        }
+
+        let url = self.url.trim().to_string();
+        let name = match check_url(&url).and_then(|host| new_profile_name(self.name.trim(), host)) {
+            Ok(name) => name,
+            Err(e) => {
+                self.error = e;
+                self.emit.error_changed();
+                return;
+            }
+        };
+
+        let profile = Profile {
+            name,
+            url,
+            username: self.username.trim().to_string(),
+            auth: Default::default(),
+            password: Some(self.password.clone()),
+            keyring: None,
+            music_folder_ids: vec![],
+            album_sort: None,
+            album_years: None,
+            connection: Default::default(),
+        };
+
+        self.checking = true;
+        self.emit.checking_changed();
+        self.error.clear();
+        self.emit.error_changed();
+
+        let sessions = self.sessions.as_ref().unwrap().clone();
+        let mut emit = self.emit.clone();
+        let incoming = self.incoming.clone();
+        tokio::spawn(async move {
+            let result = connect(&sessions, profile).await;
+            *incoming.lock().await = Some(result);
+            emit.invoke_handle_incoming_result();
+        });
+    }
+
+    /// The login check finished. Dispatched on the ui thread by
+    /// `check_and_save`.
+    fn handle_incoming_result(&mut self) {
+        let result = match self.incoming.blocking_lock().take() {
+            Some(result) => result,
+            None => return,
+        };
+
+        self.checking = false;
+        self.emit.checking_changed();
+
+        match result {
+            Ok(()) => {
+                self.name.clear();
+                self.emit.name_changed();
+                self.url.clear();
+                self.emit.url_changed();
+                self.username.clear();
+                self.emit.username_changed();
+                self.password.clear();
+                self.emit.password_changed();
+            }
+            Err(e) => {
+                self.error = e;
+                self.emit.error_changed();
+            }
+        }
+    }
+}
+
+/// Check the url makes sense, and return its host, as a fallback name
+/// for the profile.
+fn check_url(url: &str) -> Result<String, String> {
+    match url::Url::parse(url) {
+        Ok(u) if u.scheme() == "http" || u.scheme() == "https" => {
+            Ok(u.host_str().unwrap_or_default().to_string())
+        }
+        Ok(_) => Err("The server URL should start with http:// or https://".to_string()),
+        Err(_) => Err("That doesn't look like a URL".to_string()),
+    }
+}
+
+/// What to call the new profile. A name that was typed in mustn't be
+/// taken already, or saving would replace that profile; the server's
+/// host name gets a number if it is.
+fn new_profile_name(typed: &str, host: String) -> Result<String, String> {
+    let config =
+        Config::load_or_default().map_err(|e| format!("Couldn't read the config file: {e}"))?;
+    if typed.is_empty() {
+        Ok(config.unused_profile_name(&host))
+    } else if config.profile(typed).is_some() {
+        Err(format!("There's already a server called {typed}"))
+    } else {
+        Ok(typed.to_string())
+    }
+}
+
+async fn connect(sessions: &SessionManager, mut profile: Profile) -> Result<(), String> {
+    let password = profile.password.clone().unwrap_or_default();
+
+    Library::new(profile.clone(), &password)
+        .map_err(|e| e.to_string())?
+        .check_connection()
+        .await
+        .map_err(|e| describe_error(&e))?;
+
+    // It works, so keep it. This moves the password into the keyring
+    // when there is one, and saves the profile if it does...
+    keyring::resolve_password(&mut profile)
+        .await
+        .map_err(|e| e.to_string())?;
+    // ...but there might not be.
+    profile
+        .save()
+        .map_err(|e| format!("Couldn't save the config file: {e}"))?;
+    info!(?profile, "Saved new server profile");
+
+    sessions
+        .start(profile, &password)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Explain a failed login in terms of what to change in the form
+fn describe_error(e: &ApiError) -> String {
+    warn!("Server check failed: {e}");
+    match e {
+        ApiError::WrongUsernameOrPassword(_) => {
+            "The server didn't accept that username and password.".to_string()
+        }
+        ApiError::SuspiciousRedirect => "The server answered with a redirect instead of the \
+            Subsonic API. Check the URL: for Navidrome it's usually the address you'd open \
+            in a browser."
+            .to_string(),
+        ApiError::RequestError(source) => format!("Couldn't reach the server: {source}"),
+        ApiError::MalformedApiResponse => {
+            "That server doesn't seem to speak the Subsonic API. Check the URL.".to_string()
+        }
+        e => e.to_string(),
+    }
+}
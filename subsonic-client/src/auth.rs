@@ -1,41 +1,104 @@
 //! Authorization code lifted from `sunk`
 
-use std::iter;
+use std::{fmt, iter};
 
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
-#[derive(Debug)]
-pub struct SubsonicAuth {
-    user: String,
-    password: String,
+/// How requests prove who they're from
+#[derive(Clone)]
+pub enum SubsonicAuth {
+    /// A salted hash of the password. What the API recommends, and
+    /// what [`SubsonicAuth::new`] gives you.
+    Token { user: String, password: String },
+    /// The password itself, hex encoded. For servers which can't check
+    /// tokens, like Subsonic with LDAP users.
+    Password { user: String, password: String },
+    /// An API key, from servers with the OpenSubsonic `apiKeyAuthentication`
+    /// extension. The key says who the user is.
+    ApiKey { key: String },
+}
+
+impl fmt::Debug for SubsonicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubsonicAuth::Token { user, .. } => f
+                .debug_struct("Token")
+                .field("user", user)
+                .field("password", &"REDACTED")
+                .finish(),
+            SubsonicAuth::Password { user, .. } => f
+                .debug_struct("Password")
+                .field("user", user)
+                .field("password", &"REDACTED")
+                .finish(),
+            SubsonicAuth::ApiKey { .. } => {
+                f.debug_struct("ApiKey").field("key", &"REDACTED").finish()
+            }
+        }
+    }
 }
 
 const SALT_SIZE: usize = 36; // Minimum 6 characters.
 
 impl SubsonicAuth {
+    /// Token authentication
     pub fn new(user: impl Into<String>, password: impl Into<String>) -> SubsonicAuth {
-        SubsonicAuth {
+        SubsonicAuth::Token {
             user: user.into(),
             password: password.into(),
         }
     }
 
+    /// Password authentication
+    pub fn password(user: impl Into<String>, password: impl Into<String>) -> SubsonicAuth {
+        SubsonicAuth::Password {
+            user: user.into(),
+            password: password.into(),
+        }
+    }
+
+    pub fn api_key(key: impl Into<String>) -> SubsonicAuth {
+        SubsonicAuth::ApiKey { key: key.into() }
+    }
+
+    /// The same credentials without a token, for servers which answer
+    /// token auth with error 41. `None` if there's no token to begin with.
+    pub fn without_token(&self) -> Option<SubsonicAuth> {
+        match self {
+            SubsonicAuth::Token { user, password } => Some(SubsonicAuth::password(user, password)),
+            _ => None,
+        }
+    }
+
     pub fn add_to_query_pairs(
         &self,
         query_pairs: &mut url::form_urlencoded::Serializer<url::UrlQuery>,
     ) {
-        let mut rng = thread_rng();
-        let salt: String = iter::repeat(())
-            .map(|()| char::from(rng.sample(Alphanumeric)))
-            .take(SALT_SIZE)
-            .collect();
+        match self {
+            SubsonicAuth::Token { user, password } => {
+                let mut rng = thread_rng();
+                let salt: String = iter::repeat(())
+                    .map(|()| char::from(rng.sample(Alphanumeric)))
+                    .take(SALT_SIZE)
+                    .collect();
+
+                let pre_t = password.to_string() + &salt;
+                let token = format!("{:x}", md5::compute(pre_t.as_bytes()));
 
-        let pre_t = self.password.to_string() + &salt;
-        let token = format!("{:x}", md5::compute(pre_t.as_bytes()));
+                query_pairs.append_pair("u", user);
+                query_pairs.append_pair("t", &token);
+                query_pairs.append_pair("s", &salt);
+            }
+            SubsonicAuth::Password { user, password } => {
+                let hex: String = password.bytes().map(|b| format!("{b:02x}")).collect();
 
-        query_pairs.append_pair("u", &self.user);
-        query_pairs.append_pair("t", &token);
-        query_pairs.append_pair("s", &salt);
+                query_pairs.append_pair("u", user);
+                query_pairs.append_pair("p", &format!("enc:{hex}"));
+            }
+            SubsonicAuth::ApiKey { key } => {
+                query_pairs.append_pair("apiKey", key);
+            }
+        }
 
         let format = "json";
         let crate_name = env!("CARGO_PKG_NAME");
@@ -45,3 +108,62 @@ impl SubsonicAuth {
         query_pairs.append_pair("f", format);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn query(auth: &SubsonicAuth) -> Vec<(String, String)> {
+        let mut url = url::Url::parse("https://music.example.com/rest/ping").unwrap();
+        auth.add_to_query_pairs(&mut url.query_pairs_mut());
+        url.query_pairs()
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .filter(|(k, _)| !["v", "c", "f"].contains(&k.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn token_is_salted_password_hash() {
+        let pairs = query(&SubsonicAuth::new("alice", "hunter2"));
+        let [(u, user), (t, token), (s, salt)] = &pairs[..] else {
+            panic!("{pairs:?}");
+        };
+
+        assert_eq!((u.as_str(), user.as_str()), ("u", "alice"));
+        assert_eq!((t.as_str(), s.as_str()), ("t", "s"));
+        assert_eq!(
+            *token,
+            format!("{:x}", md5::compute(format!("hunter2{salt}")))
+        );
+    }
+
+    #[test]
+    fn password_is_hex_encoded() {
+        assert_eq!(
+            query(&SubsonicAuth::password("alice", "sésame")),
+            [
+                ("u".to_string(), "alice".to_string()),
+                ("p".to_string(), "enc:73c3a973616d65".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn api_key_stands_alone() {
+        assert_eq!(
+            query(&SubsonicAuth::api_key("k3y")),
+            [("apiKey".to_string(), "k3y".to_string())]
+        );
+    }
+
+    #[test]
+    fn debug_hides_secrets() {
+        for auth in [
+            SubsonicAuth::new("alice", "hunter2"),
+            SubsonicAuth::password("alice", "hunter2"),
+            SubsonicAuth::api_key("hunter2"),
+        ] {
+            assert!(!format!("{auth:?}").contains("hunter2"));
+        }
+    }
+}
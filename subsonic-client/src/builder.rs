@@ -0,0 +1,239 @@
+//! Configuring a [`Client`]
+
+use std::{sync::RwLock, time::Duration};
+
+use reqwest::{Certificate, Identity, Proxy};
+use tokio::sync::watch;
+use url::Url;
+
+use crate::{ApiError, ApiResult, Client, RetryPolicy, SubsonicAuth};
+
+/// What the client calls itself, unless told otherwise
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Builds a [`Client`] with something other than the default timeouts,
+/// retry policy, TLS settings or proxy.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use subsonic_client::{Certificate, Client, Proxy, RetryPolicy, SubsonicAuth};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let ca = std::fs::read("/etc/ssl/private-ca.pem")?;
+/// let client = Client::builder(SubsonicAuth::new("alice", "hunter2"), "https://music.example.com/rest")
+///     .connect_timeout(Duration::from_secs(5))
+///     .retry(RetryPolicy::none())
+///     .add_root_certificate(Certificate::from_pem(&ca)?)
+///     .proxy(Proxy::all("socks5h://localhost:1080")?)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ClientBuilder {
+    auth: SubsonicAuth,
+    base_url: String,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    retry: RetryPolicy,
+
+    root_certificates: Vec<Certificate>,
+    accept_invalid_certs: bool,
+    identity: Option<Identity>,
+    proxies: Vec<Proxy>,
+    user_agent: String,
+}
+
+impl ClientBuilder {
+    pub(crate) fn new(auth: SubsonicAuth, base_url: &str) -> Self {
+        ClientBuilder {
+            auth,
+            base_url: base_url.to_owned(),
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            retry: RetryPolicy::default(),
+            root_certificates: vec![],
+            accept_invalid_certs: false,
+            identity: None,
+            proxies: vec![],
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+        }
+    }
+
+    /// How long to wait for a connection to the server.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for the server to say something: the response
+    /// to a request, or the next piece of a download.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Trust certificates signed by `certificate`, as well as the
+    /// system's roots. For servers with a self-signed certificate, or
+    /// one from a private CA.
+    pub fn add_root_certificate(mut self, certificate: Certificate) -> Self {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// Don't check the server's certificate at all. Anyone between us
+    /// and the server can then read the password, so this is a last
+    /// resort.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Present a client certificate, for servers behind a proxy that
+    /// asks for one.
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Go through `proxy`. Can be called more than once, for different
+    /// schemes. Without any, the usual proxy environment variables are
+    /// followed.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxies.push(proxy);
+        self
+    }
+
+    /// Send this as the `User-Agent`, instead of `subsonic-client/<version>`
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Fails if the base url isn't an http(s) url, or the TLS settings
+    /// are rejected.
+    pub fn build(self) -> ApiResult<Client> {
+        let base_url = normalize_base_url(&self.base_url)?;
+        let (connectivity, _) = watch::channel(Default::default());
+
+        let mut client = reqwest::ClientBuilder::new()
+            .redirect(reqwest::redirect::Policy::none())
+            .connect_timeout(self.connect_timeout)
+            .user_agent(self.user_agent)
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+        for certificate in self.root_certificates {
+            client = client.add_root_certificate(certificate);
+        }
+        if let Some(identity) = self.identity {
+            client = client.identity(identity);
+        }
+        for proxy in self.proxies {
+            client = client.proxy(proxy);
+        }
+
+        Ok(Client {
+            auth: RwLock::new(self.auth),
+            base_url,
+            client: client.build()?,
+            read_timeout: self.read_timeout,
+            retry: RetryPolicy {
+                max_attempts: self.retry.max_attempts.max(1),
+                ..self.retry
+            },
+            connectivity,
+            capabilities: Default::default(),
+        })
+    }
+}
+
+/// The url that endpoint names are appended to. People configure the
+/// address of the server, with or without the `/rest` the API lives
+/// under, and with or without a trailing slash: all of them end up as
+/// `.../rest`.
+fn normalize_base_url(base_url: &str) -> ApiResult<Url> {
+    let mut url = Url::parse(base_url.trim()).map_err(ApiError::InvalidUrl)?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(ApiError::UnsupportedUrlScheme(url.scheme().to_owned()));
+    }
+
+    let ends_in_rest = url
+        .path_segments()
+        .ok_or(ApiError::UrlCannotBeABaseUrl)?
+        .rfind(|s| !s.is_empty())
+        == Some("rest");
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| ApiError::UrlCannotBeABaseUrl)?;
+        segments.pop_if_empty();
+        if !ends_in_rest {
+            segments.push("rest");
+        }
+    }
+    // Requests carry their own query
+    url.set_query(None);
+    url.set_fragment(None);
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base_urls_end_in_rest() {
+        for (configured, normalized) in [
+            (
+                "https://music.example.com",
+                "https://music.example.com/rest",
+            ),
+            (
+                "https://music.example.com/",
+                "https://music.example.com/rest",
+            ),
+            (
+                "https://music.example.com/rest",
+                "https://music.example.com/rest",
+            ),
+            (
+                "https://music.example.com/rest/",
+                "https://music.example.com/rest",
+            ),
+            (
+                "http://nas.lan:4533/navidrome/",
+                "http://nas.lan:4533/navidrome/rest",
+            ),
+            (
+                " https://music.example.com/?x=1 ",
+                "https://music.example.com/rest",
+            ),
+        ] {
+            assert_eq!(
+                normalize_base_url(configured).unwrap().as_str(),
+                normalized,
+                "{configured}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_base_urls_are_rejected() {
+        assert!(matches!(
+            normalize_base_url("music.example.com"),
+            Err(ApiError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            normalize_base_url("ftp://music.example.com"),
+            Err(ApiError::UnsupportedUrlScheme(_))
+        ));
+        assert!(matches!(
+            normalize_base_url("mailto:alice@example.com"),
+            Err(ApiError::UnsupportedUrlScheme(_))
+        ));
+    }
+}
@@ -0,0 +1,43 @@
+//! What a server can do, for callers to branch on
+
+use crate::{OpenSubsonicExtension, RequestInfo};
+
+/// The server's type and version, and the OpenSubsonic extensions it
+/// supports. See [`Client::capabilities`].
+///
+/// [`Client::capabilities`]: crate::Client::capabilities
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    /// The Subsonic API version
+    pub api_version: String,
+    pub server_type: Option<String>,
+    pub server_version: Option<String>,
+    pub open_subsonic: bool,
+    pub extensions: Vec<OpenSubsonicExtension>,
+}
+
+impl Capabilities {
+    pub(crate) fn new(info: RequestInfo, extensions: Vec<OpenSubsonicExtension>) -> Self {
+        Capabilities {
+            api_version: info.version,
+            server_type: Some(info.server_type).filter(|t| !t.is_empty()),
+            server_version: Some(info.server_version).filter(|v| !v.is_empty()),
+            open_subsonic: info.open_subsonic,
+            extensions,
+        }
+    }
+
+    /// Whether the server has any version of the extension called `name`,
+    /// like `"songLyrics"` or `"apiKeyAuthentication"`.
+    pub fn supports(&self, name: &str) -> bool {
+        self.extensions.iter().any(|e| e.name == name)
+    }
+
+    /// Whether the server has version `version` of the extension called
+    /// `name`.
+    pub fn supports_version(&self, name: &str, version: i32) -> bool {
+        self.extensions
+            .iter()
+            .any(|e| e.name == name && e.versions.contains(&version))
+    }
+}
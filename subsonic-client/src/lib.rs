@@ -1,33 +1,94 @@
 mod auth;
+mod builder;
+mod capabilities;
+mod redact;
+mod retry;
 
 pub use auth::SubsonicAuth;
+pub use builder::ClientBuilder;
+pub use capabilities::Capabilities;
+pub use redact::RedactedUrl;
+pub use reqwest::{Certificate, Identity, Proxy};
+pub use retry::{Connectivity, RetryPolicy};
 
-use bytes::Bytes;
-use reqwest::StatusCode;
+use std::{future::Future, sync::RwLock, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use reqwest::{header, StatusCode};
 use serde::{de::DeserializeOwned, Deserialize};
 use thiserror::Error;
-use tracing::info;
+use tokio::sync::{watch, OnceCell};
+use tracing::{info, warn};
 use url::Url;
 
 pub struct Client {
-    auth: SubsonicAuth,
+    /// Token auth is swapped for password auth if the server turns it down
+    auth: RwLock<SubsonicAuth>,
     base_url: Url,
     client: reqwest::Client,
+    read_timeout: Duration,
+    retry: RetryPolicy,
+    connectivity: watch::Sender<Connectivity>,
+    capabilities: OnceCell<Capabilities>,
 }
 
 impl Client {
-    pub fn new(auth: SubsonicAuth, base_url: &str) -> Self {
-        // TODO err
-        let base_url = url::Url::parse(base_url).unwrap();
+    /// A client with the default timeouts and retry policy. The base url
+    /// can be the server's address, or the `/rest` url under it.
+    pub fn new(auth: SubsonicAuth, base_url: &str) -> ApiResult<Self> {
+        Self::builder(auth, base_url).build()
+    }
+
+    pub fn builder(auth: SubsonicAuth, base_url: &str) -> ClientBuilder {
+        ClientBuilder::new(auth, base_url)
+    }
+
+    /// Follow whether the server can be reached. This changes as
+    /// requests succeed, fail, and are retried.
+    pub fn connectivity(&self) -> watch::Receiver<Connectivity> {
+        self.connectivity.subscribe()
+    }
+
+    fn set_connectivity(&self, connectivity: Connectivity) {
+        self.connectivity.send_if_modified(|c| {
+            let changed = *c != connectivity;
+            *c = connectivity;
+            changed
+        });
+    }
+
+    /// Wait for `f`, but only as long as the read timeout.
+    async fn within_read_timeout<T>(
+        &self,
+        f: impl Future<Output = Result<T, reqwest::Error>>,
+    ) -> ApiResult<T> {
+        match tokio::time::timeout(self.read_timeout, f).await {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(ApiError::Timeout),
+        }
+    }
 
-        Self {
-            auth,
-            base_url,
-            client: reqwest::ClientBuilder::new()
-                .redirect(reqwest::redirect::Policy::none())
-                .build()
-                .unwrap(),
+    /// Decide what to do after failed attempt number `attempt`: wait
+    /// until it's time for the next one, or give up with the error.
+    async fn retry_after(&self, attempt: u32, e: ApiError) -> ApiResult<()> {
+        if !e.is_retryable() {
+            if e.server_answered() {
+                self.set_connectivity(Connectivity::Online);
+            }
+            return Err(e);
         }
+
+        if attempt >= self.retry.max_attempts {
+            warn!("Giving up after {attempt} attempts: {e}");
+            self.set_connectivity(Connectivity::Offline);
+            return Err(e);
+        }
+
+        let backoff = self.retry.backoff(attempt);
+        warn!("Attempt {attempt} failed, retrying in {backoff:?}: {e}");
+        self.set_connectivity(Connectivity::Retrying { attempt });
+        tokio::time::sleep(backoff).await;
+        Ok(())
     }
 
     fn build_req_url(&self, endpoint: &str, args: &[(&str, String)]) -> Result<Url, ApiError> {
@@ -39,7 +100,10 @@ impl Client {
 
         {
             let mut query_pairs = req_url.query_pairs_mut();
-            self.auth.add_to_query_pairs(&mut query_pairs);
+            self.auth
+                .read()
+                .unwrap()
+                .add_to_query_pairs(&mut query_pairs);
 
             for (k, v) in args.iter() {
                 query_pairs.append_pair(k, v);
@@ -54,22 +118,45 @@ impl Client {
         endpoint: &str,
         args: &[(&str, String)],
     ) -> ApiResult<ResponseEnvelope<T>> {
-        let req_url = self.build_req_url(endpoint, args)?;
+        match self.request_with_retries(endpoint, args).await {
+            Err(ApiError::NoTokenAuthForLdap(message)) if self.fall_back_to_password() => {
+                info!("Server turned down token auth ({message}), using password auth instead");
+                self.request_with_retries(endpoint, args).await
+            }
+            res => res,
+        }
+    }
 
-        info!(url = req_url.to_string().as_str(), "Subsonic API Request");
-        let res = self.client.get(req_url).send().await?.error_for_status()?;
-        let status = res.status();
+    /// Switch from token to password auth for this and later requests.
+    /// Returns whether there was a token to switch from.
+    fn fall_back_to_password(&self) -> bool {
+        let mut auth = self.auth.write().unwrap();
+        match auth.without_token() {
+            Some(password_auth) => {
+                *auth = password_auth;
+                true
+            }
+            None => false,
+        }
+    }
 
-        let res = match res.json::<ResponseEnvelope<T>>().await {
-            Ok(json) => json,
-            Err(e) => {
-                if status == StatusCode::FOUND {
-                    return Err(ApiError::SuspiciousRedirect);
-                } else {
-                    return Err(e.into());
-                }
+    async fn request_with_retries<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        args: &[(&str, String)],
+    ) -> ApiResult<ResponseEnvelope<T>> {
+        let req_url = self.build_req_url(endpoint, args)?;
+
+        // Every endpoint we call only reads, so it's always safe to retry.
+        let mut attempt = 0;
+        let res = loop {
+            attempt += 1;
+            match self.request_once(req_url.clone()).await {
+                Ok(res) => break res,
+                Err(e) => self.retry_after(attempt, e).await?,
             }
         };
+        self.set_connectivity(Connectivity::Online);
 
         if let Some(e) = res.body.error {
             let message = e.message.unwrap_or_else(|| "".to_string());
@@ -93,6 +180,78 @@ impl Client {
         }
     }
 
+    async fn request_once<T: DeserializeOwned>(
+        &self,
+        req_url: Url,
+    ) -> ApiResult<ResponseEnvelope<T>> {
+        info!(url = %RedactedUrl(req_url.as_str()), "Subsonic API Request");
+        let res = self
+            .within_read_timeout(self.client.get(req_url).send())
+            .await?
+            .error_for_status()?;
+        let status = res.status();
+
+        match self
+            .within_read_timeout(res.json::<ResponseEnvelope<T>>())
+            .await
+        {
+            Ok(json) => Ok(json),
+            Err(_) if status == StatusCode::FOUND => Err(ApiError::SuspiciousRedirect),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The whole body from `url`, retried if need be. A `resumable`
+    /// body is the same every time it's asked for, so a retry can ask
+    /// for just the rest of it.
+    async fn fetch_body(&self, url: Url, resumable: bool) -> ApiResult<Bytes> {
+        let mut data = BytesMut::new();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if !resumable {
+                data.clear();
+            }
+            match self.fetch_body_once(url.clone(), &mut data).await {
+                Ok(()) => break,
+                Err(e) => self.retry_after(attempt, e).await?,
+            }
+        }
+        self.set_connectivity(Connectivity::Online);
+        Ok(data.freeze())
+    }
+
+    /// Add to `data` whatever is left of the body at `url`.
+    async fn fetch_body_once(&self, url: Url, data: &mut BytesMut) -> ApiResult<()> {
+        info!(url = %RedactedUrl(url.as_str()), resume_from = data.len(), "Subsonic API Request");
+        let mut req = self.client.get(url.clone());
+        if !data.is_empty() {
+            req = req.header(header::RANGE, format!("bytes={}-", data.len()));
+        }
+
+        let mut res = self
+            .within_read_timeout(req.send())
+            .await?
+            .error_for_status()?;
+        if !data.is_empty() && res.status() != StatusCode::PARTIAL_CONTENT {
+            // The server ignored the range, so it's all coming again.
+            data.clear();
+        } else if !data.is_empty() && content_range_start(&res) != Some(data.len() as u64) {
+            // Some other part of it, which is no use
+            warn!("The server sent the wrong part of the body, fetching all of it");
+            data.clear();
+            res = self
+                .within_read_timeout(self.client.get(url).send())
+                .await?
+                .error_for_status()?;
+        }
+
+        while let Some(chunk) = self.within_read_timeout(res.chunk()).await? {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(())
+    }
+
     async fn request<T: DeserializeOwned>(&self, endpoint: &str) -> ApiResult<T> {
         // SAFETY: request_raw will error out if payload is None
         Ok(self.request_raw(endpoint, &[]).await?.body.payload.unwrap())
@@ -122,6 +281,30 @@ impl Client {
         Ok(res.license)
     }
 
+    /// Only for OpenSubsonic servers: see [`RequestInfo::open_subsonic`].
+    pub async fn get_open_subsonic_extensions(&self) -> ApiResult<Vec<OpenSubsonicExtension>> {
+        let res = self
+            .request::<GetOpenSubsonicExtensionsBody>("getOpenSubsonicExtensions")
+            .await?;
+        Ok(res.extensions)
+    }
+
+    /// What the server is, and which OpenSubsonic extensions it has.
+    /// Asked for once, then remembered.
+    pub async fn capabilities(&self) -> ApiResult<&Capabilities> {
+        self.capabilities
+            .get_or_try_init(|| async {
+                let info = self.ping().await?;
+                let extensions = if info.open_subsonic {
+                    self.get_open_subsonic_extensions().await?
+                } else {
+                    vec![]
+                };
+                Ok(Capabilities::new(info, extensions))
+            })
+            .await
+    }
+
     pub async fn get_music_folders(&self) -> ApiResult<Vec<MusicFolder>> {
         let res = self
             .request::<GetMusicFoldersBody>("getMusicFolders")
@@ -134,8 +317,15 @@ impl Client {
         Ok(res.inner.genre)
     }
 
-    pub async fn get_indexes(&self) -> ApiResult<IndexesInfo> {
-        let res = self.request::<GetIndexesBody>("getIndexes").await?;
+    pub async fn get_indexes(&self, music_folder_id: Option<&str>) -> ApiResult<IndexesInfo> {
+        let mut params = vec![];
+        if let Some(id) = music_folder_id {
+            params.push(("musicFolderId", id.to_owned()));
+        }
+
+        let res = self
+            .request_args::<GetIndexesBody>("getIndexes", &params)
+            .await?;
         Ok(IndexesInfo {
             last_modified: res.inner.last_modified,
             ignored_articles: res.inner.ignored_articles,
@@ -254,6 +444,46 @@ impl Client {
         Ok(res.album_info)
     }
 
+    pub async fn get_similar_songs(&self, id: &str, count: Option<usize>) -> ApiResult<Vec<Child>> {
+        let mut params = vec![("id", id.to_owned())];
+        if let Some(count) = count {
+            params.push(("count", count.to_string()));
+        }
+
+        let res = self
+            .request_args::<GetSimilarSongsBody>("getSimilarSongs", &params)
+            .await?;
+        Ok(res.inner.song.unwrap_or_default())
+    }
+
+    pub async fn get_similar_songs_2(
+        &self,
+        id: &str,
+        count: Option<usize>,
+    ) -> ApiResult<Vec<Child>> {
+        let mut params = vec![("id", id.to_owned())];
+        if let Some(count) = count {
+            params.push(("count", count.to_string()));
+        }
+
+        let res = self
+            .request_args::<GetSimilarSongs2Body>("getSimilarSongs2", &params)
+            .await?;
+        Ok(res.inner.song.unwrap_or_default())
+    }
+
+    pub async fn get_top_songs(&self, artist: &str, count: Option<usize>) -> ApiResult<Vec<Child>> {
+        let mut params = vec![("artist", artist.to_owned())];
+        if let Some(count) = count {
+            params.push(("count", count.to_string()));
+        }
+
+        let res = self
+            .request_args::<GetTopSongsBody>("getTopSongs", &params)
+            .await?;
+        Ok(res.inner.song.unwrap_or_default())
+    }
+
     pub async fn get_album_list(
         &self,
         list_type: AlbumListType,
@@ -280,7 +510,7 @@ impl Client {
             params.push(("offset", offset.to_string()));
         }
         if let Some(music_folder_id) = music_folder_id {
-            params.push(("music_folder_id", music_folder_id.to_owned()));
+            params.push(("musicFolderId", music_folder_id.to_owned()));
         }
 
         let res = self
@@ -289,6 +519,37 @@ impl Client {
         Ok(res.inner.album.unwrap_or_default())
     }
 
+    pub async fn get_random_songs(
+        &self,
+        size: Option<usize>,
+        genre: Option<&str>,
+        from_year: Option<u16>,
+        to_year: Option<u16>,
+        music_folder_id: Option<&str>,
+    ) -> ApiResult<Vec<Child>> {
+        let mut params = vec![];
+        if let Some(size) = size {
+            params.push(("size", size.to_string()));
+        }
+        if let Some(genre) = genre {
+            params.push(("genre", genre.to_owned()));
+        }
+        if let Some(from_year) = from_year {
+            params.push(("fromYear", from_year.to_string()));
+        }
+        if let Some(to_year) = to_year {
+            params.push(("toYear", to_year.to_string()));
+        }
+        if let Some(music_folder_id) = music_folder_id {
+            params.push(("musicFolderId", music_folder_id.to_owned()));
+        }
+
+        let res = self
+            .request_args::<GetRandomSongsBody>("getRandomSongs", &params)
+            .await?;
+        Ok(res.inner.song.unwrap_or_default())
+    }
+
     pub fn cover_art_url(&self, id: &str, size: Option<usize>) -> Result<Url, ApiError> {
         let mut params = vec![("id", id.to_owned())];
         if let Some(size) = size {
@@ -300,15 +561,7 @@ impl Client {
 
     pub async fn get_cover_art(&self, id: &str, size: Option<usize>) -> Result<Bytes, ApiError> {
         let url = self.cover_art_url(id, size)?;
-        info!(url = url.to_string().as_str(), "Subsonic API Request");
-        Ok(self
-            .client
-            .get(url)
-            .send()
-            .await?
-            .error_for_status()?
-            .bytes()
-            .await?)
+        self.fetch_body(url, false).await
     }
 
     pub fn stream_url(
@@ -360,18 +613,23 @@ impl Client {
             estimate_content_length,
             converted,
         )?;
+        // A transcoded stream needn't come out the same twice
+        let untranscoded =
+            matches!(format, None | Some("raw")) && max_bit_rate.is_none() && time_offset.is_none();
+        self.fetch_body(url, untranscoded).await
+    }
 
-        info!(url = url.to_string().as_str(), "Subsonic API Request");
-        Ok(self
-            .client
-            .get(url)
-            .send()
-            .await?
-            .error_for_status()?
-            .bytes()
-            .await?)
+    pub fn download_url(&self, id: &str) -> Result<Url, ApiError> {
+        self.build_req_url("download", &[("id", id.to_owned())])
     }
 
+    /// The file as it was uploaded, never transcoded
+    pub async fn download(&self, id: &str) -> Result<Bytes, ApiError> {
+        let url = self.download_url(id)?;
+        self.fetch_body(url, true).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn search2(
         &self,
         query: String,
@@ -419,6 +677,14 @@ impl Client {
     }
 }
 
+/// Where the part of the body in a 206 response starts, from its
+/// `Content-Range: bytes <start>-<end>/<size>`
+fn content_range_start(res: &reqwest::Response) -> Option<u64> {
+    let range = res.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
+}
+
 type ApiResult<T> = Result<T, ApiError>;
 
 #[derive(Error, Debug)]
@@ -426,8 +692,17 @@ pub enum ApiError {
     #[error("The configured url is not a valid base url")]
     UrlCannotBeABaseUrl,
 
-    #[error("Request error")]
-    RequestError(#[from] reqwest::Error),
+    #[error("The configured url is not a url: {0}")]
+    InvalidUrl(#[source] url::ParseError),
+
+    #[error("The configured url should start with http:// or https://, not {0}://")]
+    UnsupportedUrlScheme(String),
+
+    #[error("Request error: {0}")]
+    RequestError(#[source] reqwest::Error),
+
+    #[error("The server took too long to respond")]
+    Timeout,
 
     #[error("{0}")]
     Generic(String),
@@ -463,6 +738,34 @@ pub enum ApiError {
     SuspiciousRedirect,
 }
 
+impl ApiError {
+    /// Whether trying again might help: the server couldn't be reached,
+    /// took too long, or said it's having temporary trouble.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Timeout => true,
+            ApiError::RequestError(e) => match e.status() {
+                Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                None => e.is_connect() || e.is_timeout() || e.is_request() || e.is_body(),
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether this came from the server, as opposed to not getting an
+    /// answer from it at all.
+    fn server_answered(&self) -> bool {
+        !self.is_retryable() && !matches!(self, ApiError::UrlCannotBeABaseUrl)
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        // The url has the credentials in it, and errors end up in logs.
+        ApiError::RequestError(e.without_url())
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ResponseEnvelope<T> {
     #[serde(rename = "subsonic-response")]
@@ -492,9 +795,16 @@ pub struct ResponseError {
 pub struct RequestInfo {
     pub status: ResponseStatus,
     pub version: String,
-    #[serde(rename = "type")]
+    /// Which server software this is. Only OpenSubsonic servers, and
+    /// some others, say; it's empty otherwise.
+    #[serde(rename = "type", default)]
     pub server_type: String,
+    #[serde(default)]
     pub server_version: String,
+    /// Whether the server implements the OpenSubsonic additions to the
+    /// API, and so has `getOpenSubsonicExtensions`.
+    #[serde(default)]
+    pub open_subsonic: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -529,6 +839,22 @@ pub struct License {
     pub trial_expires: Option<String>,
 }
 
+///////////////////////////////
+// getOpenSubsonicExtensions //
+///////////////////////////////
+
+#[derive(Debug, Deserialize)]
+struct GetOpenSubsonicExtensionsBody {
+    #[serde(rename = "openSubsonicExtensions")]
+    extensions: Vec<OpenSubsonicExtension>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OpenSubsonicExtension {
+    pub name: String,
+    pub versions: Vec<i32>,
+}
+
 /////////////////////
 // getMusicFolders //
 /////////////////////
@@ -638,6 +964,39 @@ pub struct Child {
     pub bookmark_position: Option<i64>,
     pub original_width: Option<i32>,
     pub original_height: Option<i32>,
+
+    // OpenSubsonic
+    pub bit_depth: Option<i32>,
+    pub sampling_rate: Option<i32>,
+    pub channel_count: Option<i32>,
+    pub music_brainz_id: Option<String>,
+    pub sort_name: Option<String>,
+    pub moods: Option<Vec<String>>,
+    pub replay_gain: Option<ReplayGain>,
+    pub artists: Option<Vec<ArtistRef>>,
+    pub display_artist: Option<String>,
+    pub album_artists: Option<Vec<ArtistRef>>,
+    pub display_album_artist: Option<String>,
+}
+
+/// One of several artists credited on an OpenSubsonic song or album
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtistRef {
+    pub id: String,
+    pub name: String,
+}
+
+/// In dB, to bring tracks or albums to the same loudness
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_peak: Option<f32>,
+    pub base_gain: Option<f32>,
+    pub fallback_gain: Option<f32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -773,6 +1132,24 @@ pub struct AlbumId3 {
     pub starred: Option<String>,
     pub year: Option<i32>,
     pub genre: Option<String>,
+
+    // OpenSubsonic
+    pub music_brainz_id: Option<String>,
+    pub sort_name: Option<String>,
+    pub moods: Option<Vec<String>>,
+    pub is_compilation: Option<bool>,
+    pub release_date: Option<ItemDate>,
+    pub original_release_date: Option<ItemDate>,
+    pub artists: Option<Vec<ArtistRef>>,
+    pub display_artist: Option<String>,
+}
+
+/// A date which may only be partly known
+#[derive(Debug, Deserialize)]
+pub struct ItemDate {
+    pub year: Option<i32>,
+    pub month: Option<u8>,
+    pub day: Option<u8>,
 }
 
 //////////////
@@ -884,6 +1261,42 @@ pub struct AlbumInfo {
     pub large_image_url: Option<String>,
 }
 
+/////////////////////
+// getSimilarSongs //
+/////////////////////
+
+#[derive(Debug, Deserialize)]
+struct GetSimilarSongsBody {
+    #[serde(rename = "similarSongs")]
+    inner: SongList,
+}
+
+//////////////////////
+// getSimilarSongs2 //
+//////////////////////
+
+#[derive(Debug, Deserialize)]
+struct GetSimilarSongs2Body {
+    #[serde(rename = "similarSongs2")]
+    inner: SongList,
+}
+
+/// The `{"song": [...]}` shape shared by the song list endpoints.
+#[derive(Debug, Deserialize)]
+struct SongList {
+    song: Option<Vec<Child>>,
+}
+
+/////////////////
+// getTopSongs //
+/////////////////
+
+#[derive(Debug, Deserialize)]
+struct GetTopSongsBody {
+    #[serde(rename = "topSongs")]
+    inner: SongList,
+}
+
 //////////////////
 // getAlbumList //
 //////////////////
@@ -930,6 +1343,16 @@ struct GetAlbumListInner {
     album: Option<Vec<Child>>,
 }
 
+////////////////////
+// getRandomSongs //
+////////////////////
+
+#[derive(Debug, Deserialize)]
+struct GetRandomSongsBody {
+    #[serde(rename = "randomSongs")]
+    inner: SongList,
+}
+
 #[derive(Debug, Deserialize)]
 struct Search2Body {
     #[serde(rename = "searchResult2")]
@@ -952,6 +1375,7 @@ pub struct SearchResults {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::sync::{Arc, Mutex};
 
     #[track_caller]
     fn check_example<T: DeserializeOwned>(path: &str) {
@@ -959,7 +1383,7 @@ mod test {
         let res = serde_json::from_str::<ResponseEnvelope<T>>(&json);
         if let Err(e) = res {
             println!("{e}");
-            assert!(false, "Json deserialization failed for {path}")
+            panic!("Json deserialization failed for {path}")
         }
     }
 
@@ -967,6 +1391,8 @@ mod test {
     fn deserialize_examples() {
         check_example::<PingBody>("test-data/navidrome/ping.json");
         check_example::<GetLicenseBody>("test-data/navidrome/getLicense.json");
+        check_example::<GetMusicFoldersBody>("test-data/navidrome/getMusicFolder.json");
+        check_example::<GetIndexesBody>("test-data/navidrome/getIndexes.json");
         check_example::<GetMusicDirectoryBody>("test-data/navidrome/getMusicDirectory.json");
         check_example::<GetGenresBody>("test-data/navidrome/getGenres.json");
         check_example::<GetArtistsBody>("test-data/navidrome/getArtists.json");
@@ -974,5 +1400,546 @@ mod test {
         check_example::<GetAlbumBody>("test-data/navidrome/getAlbum.json");
         check_example::<GetArtistInfoBody>("test-data/navidrome/getArtistInfo.json");
         check_example::<GetArtistInfo2Body>("test-data/navidrome/getArtistInfo2.json");
+        check_example::<GetSimilarSongsBody>("test-data/navidrome/getSimilarSongs.json");
+        check_example::<GetSimilarSongs2Body>("test-data/navidrome/getSimilarSongs2.json");
+        check_example::<GetRandomSongsBody>("test-data/navidrome/getRandomSongs.json");
+        check_example::<GetTopSongsBody>("test-data/navidrome/getTopSongs.json");
+        check_example::<PingBody>("test-data/opensubsonic/ping.json");
+        check_example::<GetOpenSubsonicExtensionsBody>(
+            "test-data/opensubsonic/getOpenSubsonicExtensions.json",
+        );
+        check_example::<GetAlbumBody>("test-data/opensubsonic/getAlbum.json");
+    }
+
+    /// Collects everything written to it, for inspecting log output.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn credentials_stay_out_of_logs() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // Nothing listens here, so every request fails, after being logged.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = Client::builder(
+            SubsonicAuth::new("alice", "hunter2"),
+            &format!("http://127.0.0.1:{port}/rest"),
+        )
+        .retry(RetryPolicy::none())
+        .build()
+        .unwrap();
+
+        let err = client.ping().await.unwrap_err();
+        assert!(client.get_cover_art("cover", None).await.is_err());
+        assert!(client
+            .stream("track", None, None, None, None, None)
+            .await
+            .is_err());
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(logs.matches("Subsonic API Request").count(), 3, "{logs}");
+        for secret in ["alice", "hunter2"] {
+            assert!(!logs.contains(secret), "{secret} leaked: {logs}");
+        }
+        for param in ["?u=", "&t=", "&s="] {
+            for (i, _) in logs.match_indices(param) {
+                assert!(
+                    logs[i + param.len()..].starts_with("REDACTED"),
+                    "{param} leaked: {logs}"
+                );
+            }
+        }
+
+        // Errors get logged too, and reqwest's would carry the url.
+        assert!(!format!("{err:?}").contains("alice"));
+        assert!(!format!("{:?}", client.auth).contains("hunter2"));
+    }
+
+    /// What the scripted server does with a request
+    enum Reply {
+        Full {
+            status: u16,
+            headers: Vec<(&'static str, String)>,
+            body: Vec<u8>,
+        },
+        /// Promise `total` bytes of body, but hang up after `body`.
+        Truncated { body: Vec<u8>, total: usize },
+        /// Never answer
+        Hang,
+    }
+
+    impl Reply {
+        fn status(status: u16) -> Reply {
+            Reply::Full {
+                status,
+                headers: vec![],
+                body: vec![],
+            }
+        }
+
+        fn ok(body: impl Into<Vec<u8>>) -> Reply {
+            Reply::Full {
+                status: 200,
+                headers: vec![],
+                body: body.into(),
+            }
+        }
+    }
+
+    /// A local HTTP server that answers request number `n` (from 1)
+    /// with `reply(n, head)`, one connection per request. Returns the
+    /// base url, and the head of every request it has seen.
+    async fn scripted_server(
+        reply: impl Fn(usize, &str) -> Reply + Send + 'static,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/rest", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+
+        tokio::spawn(async move {
+            let mut hung = vec![];
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut head = vec![];
+                let mut buf = [0; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        n => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&head).into_owned();
+                let n = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(head.clone());
+                    seen.len()
+                };
+
+                let (status, headers, body, length) = match reply(n, &head) {
+                    Reply::Full {
+                        status,
+                        headers,
+                        body,
+                    } => {
+                        let length = body.len();
+                        (status, headers, body, length)
+                    }
+                    Reply::Truncated { body, total } => (200, vec![], body, total),
+                    Reply::Hang => {
+                        hung.push(socket);
+                        continue;
+                    }
+                };
+                let mut res = format!(
+                    "HTTP/1.1 {status} Whatever\r\nContent-Length: {length}\r\nConnection: close\r\n"
+                );
+                for (k, v) in headers {
+                    res += &format!("{k}: {v}\r\n");
+                }
+                res += "\r\n";
+                let _ = socket.write_all(res.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            }
+        });
+
+        (base_url, requests)
+    }
+
+    fn test_client(base_url: &str, max_attempts: u32) -> Client {
+        Client::builder(SubsonicAuth::new("alice", "hunter2"), base_url)
+            .read_timeout(Duration::from_millis(200))
+            .retry(RetryPolicy {
+                max_attempts,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(10),
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn ping_json() -> String {
+        std::fs::read_to_string("test-data/navidrome/ping.json").unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_server_recovers() {
+        let (url, requests) = scripted_server(|n, _| {
+            if n <= 2 {
+                Reply::status(503)
+            } else {
+                Reply::ok(ping_json())
+            }
+        })
+        .await;
+        let client = test_client(&url, 4);
+
+        client.ping().await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert_eq!(*client.connectivity().borrow(), Connectivity::Online);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (url, requests) = scripted_server(|_, _| Reply::status(503)).await;
+        let client = test_client(&url, 3);
+
+        let err = client.ping().await.unwrap_err();
+        assert!(err.is_retryable(), "{err:?}");
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert_eq!(*client.connectivity().borrow(), Connectivity::Offline);
+    }
+
+    #[tokio::test]
+    async fn api_errors_are_not_retried() {
+        let (url, requests) = scripted_server(|_, _| {
+            Reply::ok(
+                r#"{"subsonic-response":{"status":"failed","version":"1.16.1","type":"navidrome","serverVersion":"0.47.5 (86fe1e3b)","error":{"code":40,"message":"Wrong username or password"}}}"#,
+            )
+        })
+        .await;
+        let client = test_client(&url, 4);
+
+        let err = client.ping().await.unwrap_err();
+        assert!(
+            matches!(err, ApiError::WrongUsernameOrPassword(_)),
+            "{err:?}"
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(*client.connectivity().borrow(), Connectivity::Online);
+    }
+
+    #[tokio::test]
+    async fn slow_servers_time_out() {
+        let (url, _requests) = scripted_server(|_, _| Reply::Hang).await;
+        let client = test_client(&url, 1);
+
+        let err = client.ping().await.unwrap_err();
+        assert!(matches!(err, ApiError::Timeout), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn downloads_resume_where_they_left_off() {
+        let track = b"0123456789".to_vec();
+        let reply_track = track.clone();
+        let (url, requests) = scripted_server(move |n, _| match n {
+            1 => Reply::Truncated {
+                body: reply_track[..4].to_vec(),
+                total: reply_track.len(),
+            },
+            _ => Reply::Full {
+                status: 206,
+                headers: vec![("Content-Range", "bytes 4-9/10".to_string())],
+                body: reply_track[4..].to_vec(),
+            },
+        })
+        .await;
+        let client = test_client(&url, 2);
+
+        let data = client
+            .stream("track", None, None, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(data.as_ref(), track.as_slice());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(
+            !requests[0].to_lowercase().contains("range:"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[1].to_lowercase().contains("range: bytes=4-"),
+            "{}",
+            requests[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_wrong_part_means_starting_over() {
+        let track = b"0123456789".to_vec();
+        let reply_track = track.clone();
+        let (url, requests) = scripted_server(move |n, _| match n {
+            1 => Reply::Truncated {
+                body: reply_track[..4].to_vec(),
+                total: reply_track.len(),
+            },
+            2 => Reply::Full {
+                status: 206,
+                headers: vec![("Content-Range", "bytes 2-9/10".to_string())],
+                body: reply_track[2..].to_vec(),
+            },
+            _ => Reply::ok(reply_track.clone()),
+        })
+        .await;
+        let client = test_client(&url, 2);
+
+        let data = client.download("track").await.unwrap();
+        assert_eq!(data.as_ref(), track.as_slice());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(
+            !requests[2].to_lowercase().contains("range:"),
+            "{}",
+            requests[2]
+        );
+    }
+
+    #[tokio::test]
+    async fn transcoded_streams_are_not_resumed() {
+        let track = b"0123456789".to_vec();
+        let reply_track = track.clone();
+        let (url, requests) = scripted_server(move |n, _| match n {
+            1 => Reply::Truncated {
+                body: reply_track[..4].to_vec(),
+                total: reply_track.len(),
+            },
+            _ => Reply::ok(reply_track.clone()),
+        })
+        .await;
+        let client = test_client(&url, 2);
+
+        let data = client
+            .stream("track", None, Some("opus"), None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(data.as_ref(), track.as_slice());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(
+            !requests[1].to_lowercase().contains("range:"),
+            "{}",
+            requests[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_the_proxy() {
+        let (proxy_url, requests) = scripted_server(|_, _| Reply::ok(ping_json())).await;
+        let proxy_url = proxy_url.trim_end_matches("/rest");
+        let client = Client::builder(
+            SubsonicAuth::new("alice", "hunter2"),
+            "http://music.invalid/rest",
+        )
+        .proxy(Proxy::http(proxy_url).unwrap())
+        .user_agent("tinysonic-test")
+        .build()
+        .unwrap();
+
+        client.ping().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(
+            requests[0].starts_with("GET http://music.invalid/rest/ping"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[0]
+                .to_lowercase()
+                .contains("user-agent: tinysonic-test"),
+            "{}",
+            requests[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_password_auth() {
+        let (url, requests) = scripted_server(|_, head| {
+            if head.contains("&p=enc") {
+                Reply::ok(ping_json())
+            } else {
+                Reply::ok(
+                    r#"{"subsonic-response":{"status":"failed","version":"1.16.1","type":"navidrome","serverVersion":"0.47.5 (86fe1e3b)","error":{"code":41,"message":"Token authentication not supported for LDAP users."}}}"#,
+                )
+            }
+        })
+        .await;
+        let client = test_client(&url, 1);
+
+        client.ping().await.unwrap();
+        client.ping().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].contains("&t="), "{}", requests[0]);
+        assert!(!requests[2].contains("&t="), "{}", requests[2]);
+    }
+
+    #[test]
+    fn open_subsonic_fields() {
+        let json = std::fs::read_to_string("test-data/opensubsonic/getAlbum.json").unwrap();
+        let res = serde_json::from_str::<ResponseEnvelope<GetAlbumBody>>(&json).unwrap();
+        assert!(res.body.info.open_subsonic);
+
+        let album = res.body.payload.unwrap().inner;
+        assert_eq!(album.album_id3.is_compilation, Some(false));
+        assert_eq!(album.album_id3.release_date.unwrap().year, Some(1994));
+        assert_eq!(album.album_id3.moods.unwrap(), ["weird"]);
+
+        let songs = album.songs.unwrap();
+        let replay_gain = songs[0].replay_gain.as_ref().unwrap();
+        assert_eq!(replay_gain.track_gain, Some(-6.32));
+        assert_eq!(songs[0].sort_name.as_deref(), Some("frownland"));
+        assert_eq!(
+            songs[1].artists.as_ref().unwrap()[0].name,
+            "Captain Beefheart"
+        );
+        assert_eq!(
+            (
+                songs[1].sampling_rate,
+                songs[1].bit_depth,
+                songs[1].channel_count
+            ),
+            (Some(48000), Some(24), Some(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn capabilities_are_asked_for_once() {
+        let (url, requests) = scripted_server(|_, head| {
+            let fixture = if head.contains("/getOpenSubsonicExtensions?") {
+                "test-data/opensubsonic/getOpenSubsonicExtensions.json"
+            } else {
+                "test-data/opensubsonic/ping.json"
+            };
+            Reply::ok(std::fs::read_to_string(fixture).unwrap())
+        })
+        .await;
+        let client = test_client(&url, 1);
+
+        let capabilities = client.capabilities().await.unwrap();
+        assert!(capabilities.open_subsonic);
+        assert_eq!(capabilities.server_type.as_deref(), Some("navidrome"));
+        assert!(capabilities.supports("songLyrics"));
+        assert!(capabilities.supports_version("formPost", 1));
+        assert!(!capabilities.supports_version("formPost", 2));
+        assert!(!capabilities.supports("apiKeyAuthentication"));
+
+        client.capabilities().await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn plain_subsonic_has_no_extensions() {
+        let (url, requests) = scripted_server(|_, _| Reply::ok(ping_json())).await;
+        let client = test_client(&url, 1);
+
+        let capabilities = client.capabilities().await.unwrap();
+        assert!(!capabilities.open_subsonic);
+        assert!(capabilities.extensions.is_empty());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn servers_needn_t_say_what_they_are() {
+        let (url, _requests) = scripted_server(|_, _| {
+            Reply::ok(r#"{"subsonic-response":{"status":"ok","version":"1.16.1"}}"#)
+        })
+        .await;
+        let client = test_client(&url, 1);
+
+        let info = client.ping().await.unwrap();
+        assert_eq!(info.server_type, "");
+        assert_eq!(info.server_version, "");
+        let capabilities = client.capabilities().await.unwrap();
+        assert_eq!(capabilities.server_type, None);
+    }
+
+    #[tokio::test]
+    async fn browses_the_mock_library() {
+        let server = subsonic_mock::MockServer::start().await;
+        let client = test_client(&server.url(), 1);
+
+        let folders = client.get_music_folders().await.unwrap();
+        assert_eq!(folders.len(), server.library().music_folders.len());
+
+        let artists = client.get_artists(None).await.unwrap();
+        let artist_count: usize = artists.indexes.iter().map(|i| i.artist.len()).sum();
+        assert_eq!(artist_count, server.library().artists.len());
+
+        let albums = client
+            .get_album_list(AlbumListType::AlphabeticalByName, Some(500), None, None)
+            .await
+            .unwrap();
+        assert_eq!(albums.len(), server.library().albums.len());
+
+        let album = client.get_album("al-1").await.unwrap();
+        assert_eq!(album.album_id3.name, "A440");
+        let titles: Vec<_> = album.songs.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(titles, ["Concert Pitch", "Tuning Fork", "Dial Tone"]);
+
+        let directory = client.get_music_directory("al-2").await.unwrap();
+        assert_eq!(directory.children.len(), 2);
+
+        assert!(matches!(
+            client.get_album("al-404").await,
+            Err(ApiError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn streams_from_the_mock() {
+        let server = subsonic_mock::MockServer::start().await;
+        let client = test_client(&server.url(), 1);
+
+        let song = server.library().song("tr-2").unwrap();
+        let data = client
+            .stream("tr-2", None, None, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(&data[..], song.data());
+
+        let cover = client.get_cover_art("al-1", None).await.unwrap();
+        assert_eq!(&cover[..], subsonic_mock::COVER_PNG);
+    }
+
+    #[tokio::test]
+    async fn mock_checks_credentials() {
+        let server = subsonic_mock::MockServer::start_with(subsonic_mock::MockConfig {
+            reject_token_auth: true,
+            api_key: Some("k3y".to_owned()),
+            ..Default::default()
+        })
+        .await;
+
+        let client = test_client(&server.url(), 1);
+        client.ping().await.unwrap();
+        assert_eq!(server.requests().len(), 2);
+
+        let client = Client::new(SubsonicAuth::api_key("k3y"), &server.url()).unwrap();
+        client.ping().await.unwrap();
+
+        let client = Client::new(SubsonicAuth::new("alice", "wrong"), &server.url()).unwrap();
+        assert!(matches!(
+            client.ping().await,
+            Err(ApiError::WrongUsernameOrPassword(_))
+        ));
     }
 }
@@ -0,0 +1,79 @@
+//! Keeping credentials out of the logs
+
+use std::fmt;
+
+use url::Url;
+
+/// Query parameters which carry credentials
+const SECRET_PARAMS: &[&str] = &["u", "t", "s", "p", "apiKey"];
+
+const REDACTED: &str = "REDACTED";
+
+/// Displays a request url with the credentials censored, so it's safe
+/// to log.
+pub struct RedactedUrl<'a>(pub &'a str);
+
+impl fmt::Display for RedactedUrl<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let mut url = match Url::parse(self.0) {
+            Ok(url) => url,
+            // If we can't find the query we can't censor it.
+            Err(_) => return write!(f, "<unparseable url>"),
+        };
+
+        if url.query().is_some() {
+            let pairs = url
+                .query_pairs()
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect::<Vec<_>>();
+
+            let mut query_pairs = url.query_pairs_mut();
+            query_pairs.clear();
+            for (k, v) in pairs.iter() {
+                if SECRET_PARAMS.contains(&k.as_str()) {
+                    query_pairs.append_pair(k, REDACTED);
+                } else {
+                    query_pairs.append_pair(k, v);
+                }
+            }
+        }
+
+        write!(f, "{url}")
+    }
+}
+
+impl fmt::Debug for RedactedUrl<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn censors_credentials() {
+        let url = "https://music.example.com/rest/stream?u=alice&t=0123abcd&s=pepper&p=enc:736563726574&apiKey=k3y&v=1.16.1&id=42";
+        let redacted = RedactedUrl(url).to_string();
+
+        for secret in ["alice", "0123abcd", "pepper", "736563726574", "k3y"] {
+            assert!(!redacted.contains(secret), "{secret} leaked: {redacted}");
+        }
+        assert!(redacted.contains("v=1.16.1"));
+        assert!(redacted.contains("id=42"));
+        assert!(redacted.starts_with("https://music.example.com/rest/stream?"));
+    }
+
+    #[test]
+    fn unparseable_urls_are_hidden() {
+        assert_eq!(
+            RedactedUrl("not a url?t=secret").to_string(),
+            "<unparseable url>"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Retrying requests that fail for reasons which might go away, and
+//! keeping track of whether the server can be reached.
+
+use std::time::Duration;
+
+/// How failed requests are retried. Only failures which might go away
+/// on their own are retried: see [`ApiError::is_retryable`].
+///
+/// [`ApiError::is_retryable`]: crate::ApiError::is_retryable
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times to try, including the first. At least one.
+    pub max_attempts: u32,
+    /// How long to wait after the first failure. This doubles after
+    /// each one that follows...
+    pub initial_backoff: Duration,
+    /// ...up to this.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Try everything exactly once
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait after failed attempt number `attempt`, counting
+    /// from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Whether the server could be reached, as of the last request.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Connectivity {
+    /// Nothing has been requested yet.
+    #[default]
+    Unknown,
+    /// The server answered.
+    Online,
+    /// The server didn't answer, and the request is being retried.
+    /// `attempt` is the one that just failed.
+    Retrying { attempt: u32 },
+    /// The server didn't answer, and the request was given up on.
+    Offline,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+
+        let backoffs = (1..=5).map(|a| policy.backoff(a)).collect::<Vec<_>>();
+        assert_eq!(
+            backoffs,
+            [100, 200, 400, 500, 500].map(Duration::from_millis)
+        );
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(500));
+    }
+}
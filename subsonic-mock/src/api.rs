@@ -0,0 +1,659 @@
+//! Answering the Subsonic API from the fixture library
+
+use serde_json::{json, Value};
+
+use crate::{
+    library::{Album, Library, COVER_PNG},
+    MockConfig,
+};
+
+/// What an endpoint answers with
+pub(crate) enum Reply {
+    /// The payload of a successful `subsonic-response`
+    Ok(Value),
+    /// A failed `subsonic-response`
+    Failed(Failure),
+    Binary {
+        content_type: &'static str,
+        data: &'static [u8],
+    },
+    /// There's no such endpoint
+    NotFound,
+}
+
+pub(crate) struct Failure {
+    pub code: i32,
+    pub message: String,
+}
+
+impl Failure {
+    fn new(code: i32, message: impl Into<String>) -> Failure {
+        Failure {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn missing(param: &str) -> Failure {
+        Failure::new(10, format!("Required parameter is missing: {param}"))
+    }
+
+    fn not_found(what: &str) -> Failure {
+        Failure::new(70, format!("{what} not found"))
+    }
+}
+
+impl From<Failure> for Reply {
+    fn from(failure: Failure) -> Reply {
+        Reply::Failed(failure)
+    }
+}
+
+pub(crate) struct Params(pub Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn require(&self, name: &str) -> Result<&str, Failure> {
+        self.get(name).ok_or_else(|| Failure::missing(name))
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, Failure> {
+        self.get(name)
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| Failure::new(0, format!("Bad value for {name}: {v}")))
+            })
+            .transpose()
+    }
+
+    /// A page of `items`, from `offset` (default 0) for `count` (default
+    /// `default_count`) items.
+    fn page<T>(
+        &self,
+        items: Vec<T>,
+        count: &str,
+        offset: &str,
+        default_count: usize,
+    ) -> Result<Vec<T>, Failure> {
+        let count = self.number(count)?.unwrap_or(default_count);
+        let offset = self.number(offset)?.unwrap_or(0);
+        Ok(items.into_iter().skip(offset).take(count).collect())
+    }
+}
+
+/// Check the credentials, the way a server would
+pub(crate) fn authenticate(config: &MockConfig, params: &Params) -> Result<(), Failure> {
+    let wrong = || Failure::new(40, "Wrong username or password");
+
+    if let Some(key) = params.get("apiKey") {
+        return match &config.api_key {
+            None => Err(Failure::new(
+                42,
+                "Provided authentication mechanism not supported",
+            )),
+            Some(_) if params.get("u").is_some() => Err(Failure::new(
+                43,
+                "Multiple conflicting authentication mechanisms provided",
+            )),
+            Some(expected) if expected == key => Ok(()),
+            Some(_) => Err(Failure::new(44, "Invalid API key")),
+        };
+    }
+
+    if params.require("u")? != config.username {
+        return Err(wrong());
+    }
+    match (params.get("t"), params.get("s"), params.get("p")) {
+        (Some(_), Some(_), _) if config.reject_token_auth => Err(Failure::new(
+            41,
+            "Token authentication not supported for LDAP users",
+        )),
+        (Some(token), Some(salt), _) => {
+            let expected = format!("{:x}", md5::compute(format!("{}{salt}", config.password)));
+            (token == expected).then_some(()).ok_or_else(wrong)
+        }
+        (_, _, Some(password)) => (decode_password(password).as_deref()
+            == Some(config.password.as_str()))
+        .then_some(())
+        .ok_or_else(wrong),
+        _ => Err(Failure::missing("t")),
+    }
+}
+
+/// `p` is either plain, or `enc:` and hex
+fn decode_password(p: &str) -> Option<String> {
+    match p.strip_prefix("enc:") {
+        None => Some(p.to_owned()),
+        Some(hex) => {
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<_>>>()?;
+            String::from_utf8(bytes).ok()
+        }
+    }
+}
+
+pub(crate) fn respond(
+    library: &Library,
+    config: &MockConfig,
+    endpoint: &str,
+    params: &Params,
+) -> Reply {
+    let api = Api {
+        library,
+        config,
+        params,
+    };
+    let res = match endpoint {
+        "ping" => Ok(json!({})),
+        "getLicense" => Ok(json!({ "license": { "valid": true, "email": "mock@example.com" } })),
+        "getOpenSubsonicExtensions" if config.open_subsonic => Ok(api.extensions()),
+        "getMusicFolders" => Ok(api.music_folders()),
+        "getGenres" => Ok(api.genres()),
+        "getIndexes" => api.indexes("indexes", |l, a| l.artist_json(a)),
+        "getArtists" => api.indexes("artists", |l, a| l.artist_id3_json(a)),
+        "getMusicDirectory" => api.music_directory(),
+        "getArtist" => api.artist(),
+        "getAlbum" => api.album(),
+        "getSong" => api.song(),
+        "getAlbumList" => api.album_list("albumList", |l, a| l.album_child_json(a)),
+        "getAlbumList2" => api.album_list("albumList2", |l, a| l.album_id3_json(a)),
+        "search2" => api.search("searchResult2", false),
+        "search3" => api.search("searchResult3", true),
+        "getRandomSongs" => api.random_songs(),
+        "getSimilarSongs" => api.similar_songs("similarSongs"),
+        "getSimilarSongs2" => api.similar_songs("similarSongs2"),
+        "getTopSongs" => api.top_songs(),
+        "stream" | "download" => return api.stream(),
+        "getCoverArt" => {
+            return Reply::Binary {
+                content_type: "image/png",
+                data: COVER_PNG,
+            }
+        }
+        _ => return Reply::NotFound,
+    };
+
+    match res {
+        Ok(payload) => Reply::Ok(payload),
+        Err(failure) => Reply::Failed(failure),
+    }
+}
+
+struct Api<'a> {
+    library: &'a Library,
+    config: &'a MockConfig,
+    params: &'a Params,
+}
+
+impl Api<'_> {
+    fn extensions(&self) -> Value {
+        let mut extensions = vec![json!({ "name": "formPost", "versions": [1] })];
+        if self.config.api_key.is_some() {
+            extensions.push(json!({ "name": "apiKeyAuthentication", "versions": [1] }));
+        }
+        json!({ "openSubsonicExtensions": extensions })
+    }
+
+    fn music_folders(&self) -> Value {
+        let folders = self
+            .library
+            .music_folders
+            .iter()
+            .map(|f| json!({ "id": f.id, "name": f.name }))
+            .collect::<Vec<_>>();
+        json!({ "musicFolders": { "musicFolder": folders } })
+    }
+
+    fn genres(&self) -> Value {
+        let mut names = self
+            .library
+            .albums
+            .iter()
+            .map(|a| a.genre.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        let genres = names
+            .into_iter()
+            .map(|genre| {
+                let albums = self.library.albums.iter().filter(|a| a.genre == genre);
+                json!({
+                    "value": genre,
+                    "albumCount": albums.clone().count(),
+                    "songCount": albums.map(|a| self.library.songs_on(&a.id).count()).sum::<usize>(),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "genres": { "genre": genres } })
+    }
+
+    /// Only the albums in the `musicFolderId` folder, if there is one
+    fn in_folder(&self) -> Result<impl Fn(&Album) -> bool + '_, Failure> {
+        let folder: Option<i64> = self.params.number("musicFolderId")?;
+        Ok(move |album: &Album| folder.is_none_or(|f| self.library.folder_of(album) == f))
+    }
+
+    /// Artists by their first letter, ignoring any "The"
+    fn indexes(
+        &self,
+        key: &str,
+        artist_json: impl Fn(&Library, &crate::library::Artist) -> Value,
+    ) -> Result<Value, Failure> {
+        let folder: Option<i64> = self.params.number("musicFolderId")?;
+        let mut artists = self
+            .library
+            .artists
+            .iter()
+            .filter(|a| folder.is_none_or(|f| a.music_folder_id == f))
+            .map(|a| {
+                let sort_name = a.name.strip_prefix("The ").unwrap_or(&a.name);
+                (sort_name.to_uppercase(), a)
+            })
+            .collect::<Vec<_>>();
+        artists.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut indexes: Vec<(String, Vec<Value>)> = vec![];
+        for (sort_name, artist) in artists {
+            let letter = sort_name.chars().next().unwrap_or('#').to_string();
+            let entry = artist_json(self.library, artist);
+            match indexes.last_mut() {
+                Some((name, entries)) if *name == letter => entries.push(entry),
+                _ => indexes.push((letter, vec![entry])),
+            }
+        }
+
+        let indexes = indexes
+            .into_iter()
+            .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+            .collect::<Vec<_>>();
+        Ok(json!({
+            key: {
+                "lastModified": 0,
+                "ignoredArticles": "The",
+                "index": indexes,
+            }
+        }))
+    }
+
+    /// Artists contain albums, which contain songs
+    fn music_directory(&self) -> Result<Value, Failure> {
+        let id = self.params.require("id")?;
+        let library = self.library;
+
+        let directory = if let Some(artist) = library.artist(id) {
+            let albums = library
+                .albums_by(id)
+                .map(|a| library.album_child_json(a))
+                .collect::<Vec<_>>();
+            json!({
+                "id": artist.id,
+                "parent": artist.music_folder_id.to_string(),
+                "name": artist.name,
+                "child": albums,
+            })
+        } else if let Some(album) = library.album(id) {
+            let songs = library
+                .songs_on(id)
+                .map(|s| library.song_json(s, self.config.open_subsonic))
+                .collect::<Vec<_>>();
+            json!({
+                "id": album.id,
+                "parent": album.artist_id,
+                "name": album.name,
+                "child": songs,
+            })
+        } else {
+            return Err(Failure::not_found("Directory"));
+        };
+        Ok(json!({ "directory": directory }))
+    }
+
+    fn artist(&self) -> Result<Value, Failure> {
+        let id = self.params.require("id")?;
+        let artist = self
+            .library
+            .artist(id)
+            .ok_or_else(|| Failure::not_found("Artist"))?;
+
+        let mut json = self.library.artist_id3_json(artist);
+        json["album"] = self
+            .library
+            .albums_by(id)
+            .map(|a| self.library.album_id3_json(a))
+            .collect();
+        Ok(json!({ "artist": json }))
+    }
+
+    fn album(&self) -> Result<Value, Failure> {
+        let id = self.params.require("id")?;
+        let album = self
+            .library
+            .album(id)
+            .ok_or_else(|| Failure::not_found("Album"))?;
+
+        let mut json = self.library.album_id3_json(album);
+        json["song"] = self
+            .library
+            .songs_on(id)
+            .map(|s| self.library.song_json(s, self.config.open_subsonic))
+            .collect();
+        Ok(json!({ "album": json }))
+    }
+
+    fn song(&self) -> Result<Value, Failure> {
+        let id = self.params.require("id")?;
+        let song = self
+            .library
+            .song(id)
+            .ok_or_else(|| Failure::not_found("Song"))?;
+        Ok(json!({ "song": self.library.song_json(song, self.config.open_subsonic) }))
+    }
+
+    /// Lists are in fixture order, apart from the alphabetical ones.
+    /// Even "random" is, so tests can rely on it.
+    fn album_list(
+        &self,
+        key: &str,
+        album_json: impl Fn(&Library, &Album) -> Value,
+    ) -> Result<Value, Failure> {
+        let in_folder = self.in_folder()?;
+        let library = self.library;
+        let mut albums = library
+            .albums
+            .iter()
+            .filter(|a| in_folder(a))
+            .collect::<Vec<_>>();
+
+        match self.params.require("type")? {
+            "random" | "newest" | "highest" | "frequent" | "recent" => {}
+            "alphabeticalByName" => albums.sort_by(|a, b| a.name.cmp(&b.name)),
+            "alphabeticalByArtist" => albums.sort_by(|a, b| {
+                library
+                    .artist_of(a)
+                    .name
+                    .cmp(&library.artist_of(b).name)
+                    .then(a.name.cmp(&b.name))
+            }),
+            "starred" => albums.clear(),
+            "byYear" => {
+                let from: i32 = self
+                    .params
+                    .number("fromYear")?
+                    .ok_or_else(|| Failure::missing("fromYear"))?;
+                let to: i32 = self
+                    .params
+                    .number("toYear")?
+                    .ok_or_else(|| Failure::missing("toYear"))?;
+                albums.retain(|a| (from.min(to)..=from.max(to)).contains(&a.year));
+                albums.sort_by_key(|a| a.year);
+                if from > to {
+                    albums.reverse();
+                }
+            }
+            "byGenre" => {
+                let genre = self.params.require("genre")?;
+                albums.retain(|a| a.genre == genre);
+            }
+            other => return Err(Failure::new(0, format!("Unknown list type {other}"))),
+        }
+
+        let albums = self
+            .params
+            .page(albums, "size", "offset", 10)?
+            .into_iter()
+            .map(|a| album_json(library, a))
+            .collect::<Vec<_>>();
+        Ok(json!({ key: { "album": albums } }))
+    }
+
+    /// Case-insensitive substring search. An empty query matches
+    /// everything.
+    fn search(&self, key: &str, id3: bool) -> Result<Value, Failure> {
+        let query = self
+            .params
+            .require("query")?
+            .trim_matches('"')
+            .to_lowercase();
+        let matches = |s: &str| s.to_lowercase().contains(&query);
+        let in_folder = self.in_folder()?;
+        let library = self.library;
+
+        let artists = library
+            .artists
+            .iter()
+            .filter(|a| matches(&a.name))
+            .filter(|a| library.albums_by(&a.id).any(&in_folder))
+            .collect();
+        let albums = library
+            .albums
+            .iter()
+            .filter(|a| matches(&a.name) && in_folder(a))
+            .collect();
+        let songs = library
+            .songs
+            .iter()
+            .filter(|s| matches(&s.title) && in_folder(library.album_of(s)))
+            .collect();
+
+        let artists = self
+            .params
+            .page(artists, "artistCount", "artistOffset", 20)?;
+        let albums = self.params.page(albums, "albumCount", "albumOffset", 20)?;
+        let songs = self.params.page(songs, "songCount", "songOffset", 20)?;
+
+        let open_subsonic = self.config.open_subsonic;
+        Ok(json!({
+            key: {
+                "artist": artists.into_iter().map(|a| if id3 {
+                    library.artist_id3_json(a)
+                } else {
+                    library.artist_json(a)
+                }).collect::<Vec<_>>(),
+                "album": albums.into_iter().map(|a| if id3 {
+                    library.album_id3_json(a)
+                } else {
+                    library.album_child_json(a)
+                }).collect::<Vec<_>>(),
+                "song": songs.into_iter().map(|s| library.song_json(s, open_subsonic)).collect::<Vec<_>>(),
+            }
+        }))
+    }
+
+    /// Not random at all: the first songs that fit, in fixture order
+    fn random_songs(&self) -> Result<Value, Failure> {
+        let in_folder = self.in_folder()?;
+        let genre = self.params.get("genre");
+        let from_year: Option<i32> = self.params.number("fromYear")?;
+        let to_year: Option<i32> = self.params.number("toYear")?;
+        let size = self.params.number("size")?.unwrap_or(10);
+        let library = self.library;
+
+        let songs = library
+            .songs
+            .iter()
+            .filter(|s| {
+                let album = library.album_of(s);
+                in_folder(album)
+                    && genre.is_none_or(|g| album.genre == g)
+                    && from_year.is_none_or(|y| album.year >= y)
+                    && to_year.is_none_or(|y| album.year <= y)
+            })
+            .take(size)
+            .map(|s| library.song_json(s, self.config.open_subsonic))
+            .collect::<Vec<_>>();
+        Ok(json!({ "randomSongs": { "song": songs } }))
+    }
+
+    /// Every other song, the same artist's first
+    fn similar_songs(&self, key: &str) -> Result<Value, Failure> {
+        let id = self.params.require("id")?;
+        let count = self.params.number("count")?.unwrap_or(50);
+        let library = self.library;
+
+        let artist_id = if let Some(song) = library.song(id) {
+            library.album_of(song).artist_id.clone()
+        } else if let Some(album) = library.album(id) {
+            album.artist_id.clone()
+        } else if library.artist(id).is_some() {
+            id.to_owned()
+        } else {
+            return Err(Failure::not_found("Item"));
+        };
+
+        let mut songs = library
+            .songs
+            .iter()
+            .filter(|s| s.id != id && s.album_id != id)
+            .collect::<Vec<_>>();
+        songs.sort_by_key(|s| library.album_of(s).artist_id != artist_id);
+
+        let songs = songs
+            .into_iter()
+            .take(count)
+            .map(|s| library.song_json(s, self.config.open_subsonic))
+            .collect::<Vec<_>>();
+        Ok(json!({ key: { "song": songs } }))
+    }
+
+    /// The artist's songs, in fixture order
+    fn top_songs(&self) -> Result<Value, Failure> {
+        let artist = self.params.require("artist")?;
+        let count = self.params.number("count")?.unwrap_or(50);
+        let library = self.library;
+
+        let songs = library
+            .songs
+            .iter()
+            .filter(|s| library.artist_of(library.album_of(s)).name == artist)
+            .take(count)
+            .map(|s| library.song_json(s, self.config.open_subsonic))
+            .collect::<Vec<_>>();
+        Ok(json!({ "topSongs": { "song": songs } }))
+    }
+
+    fn stream(&self) -> Reply {
+        let id = match self.params.require("id") {
+            Ok(id) => id,
+            Err(failure) => return failure.into(),
+        };
+        match self.library.song(id) {
+            Some(song) => Reply::Binary {
+                content_type: "audio/flac",
+                data: song.data(),
+            },
+            None => Failure::not_found("Song").into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Params {
+        Params(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    fn code(res: Result<(), Failure>) -> Option<i32> {
+        res.err().map(|failure| failure.code)
+    }
+
+    #[test]
+    fn checks_tokens_and_passwords() {
+        let config = MockConfig::default();
+        let token = format!("{:x}", md5::compute("hunter2s4lt"));
+
+        for (pairs, expected) in [
+            (
+                vec![("u", "alice"), ("t", token.as_str()), ("s", "s4lt")],
+                None,
+            ),
+            (
+                vec![("u", "alice"), ("t", token.as_str()), ("s", "pepper")],
+                Some(40),
+            ),
+            (
+                vec![("u", "bob"), ("t", token.as_str()), ("s", "s4lt")],
+                Some(40),
+            ),
+            (vec![("u", "alice"), ("p", "hunter2")], None),
+            (vec![("u", "alice"), ("p", "enc:68756e74657232")], None),
+            (vec![("u", "alice"), ("p", "enc:6")], Some(40)),
+            (vec![("u", "alice")], Some(10)),
+            (vec![], Some(10)),
+        ] {
+            assert_eq!(
+                code(authenticate(&config, &params(&pairs))),
+                expected,
+                "{pairs:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_tokens_like_ldap() {
+        let config = MockConfig {
+            reject_token_auth: true,
+            ..Default::default()
+        };
+        let token = format!("{:x}", md5::compute("hunter2s4lt"));
+
+        let with_token = params(&[("u", "alice"), ("t", &token), ("s", "s4lt")]);
+        assert_eq!(code(authenticate(&config, &with_token)), Some(41));
+        let with_password = params(&[("u", "alice"), ("p", "hunter2")]);
+        assert_eq!(code(authenticate(&config, &with_password)), None);
+    }
+
+    #[test]
+    fn checks_api_keys() {
+        let without_keys = MockConfig::default();
+        let with_key = MockConfig {
+            api_key: Some("k3y".to_owned()),
+            ..Default::default()
+        };
+
+        let key = params(&[("apiKey", "k3y")]);
+        assert_eq!(code(authenticate(&without_keys, &key)), Some(42));
+        assert_eq!(code(authenticate(&with_key, &key)), None);
+        let wrong_key = params(&[("apiKey", "l0ck")]);
+        assert_eq!(code(authenticate(&with_key, &wrong_key)), Some(44));
+        let both = params(&[("apiKey", "k3y"), ("u", "alice"), ("p", "hunter2")]);
+        assert_eq!(code(authenticate(&with_key, &both)), Some(43));
+    }
+
+    #[test]
+    fn albums_list_their_songs() {
+        let library = Library::fixture();
+        let config = MockConfig::default();
+
+        let Reply::Ok(payload) = respond(&library, &config, "getAlbum", &params(&[("id", "al-1")]))
+        else {
+            panic!("getAlbum failed");
+        };
+        let songs = payload["album"]["song"].as_array().unwrap();
+        assert_eq!(songs.len(), library.songs_on("al-1").count());
+        assert!(songs.iter().all(|s| s["albumId"] == "al-1"));
+        assert!(songs[0].get("samplingRate").is_none());
+
+        let Reply::Failed(failure) =
+            respond(&library, &config, "getAlbum", &params(&[("id", "al-404")]))
+        else {
+            panic!("Found a missing album");
+        };
+        assert_eq!(failure.code, 70);
+    }
+}
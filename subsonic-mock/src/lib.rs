@@ -0,0 +1,345 @@
+//! A fake Subsonic server, for testing against without a real one.
+//!
+//! It serves the small library in `fixtures/library.json` on localhost.
+//! Songs stream as real (tiny) FLAC files, and everything has the same
+//! cover. Failures can be injected, and every request is recorded.
+//!
+//! ```no_run
+//! # async fn example() {
+//! let server = subsonic_mock::MockServer::start().await;
+//! // Point a client at server.url(), log in as USERNAME / PASSWORD...
+//! assert_eq!(server.requests()[0].endpoint, "ping");
+//! # }
+//! ```
+
+mod api;
+mod library;
+
+pub use library::{Album, Artist, Library, MusicFolder, Song, COVER_PNG};
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+use api::{Failure, Params, Reply};
+
+pub const USERNAME: &str = "alice";
+pub const PASSWORD: &str = "hunter2";
+
+/// How the server behaves
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    pub username: String,
+    pub password: String,
+    /// Answer token auth with error 41, like Subsonic does for LDAP users
+    pub reject_token_auth: bool,
+    /// Claim to be an OpenSubsonic server
+    pub open_subsonic: bool,
+    /// Accept this OpenSubsonic API key
+    pub api_key: Option<String>,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            username: USERNAME.to_owned(),
+            password: PASSWORD.to_owned(),
+            reject_token_auth: false,
+            open_subsonic: false,
+            api_key: None,
+        }
+    }
+}
+
+/// A request the server has seen
+#[derive(Clone, Debug)]
+pub struct SeenRequest {
+    /// Like `ping` or `getAlbum`
+    pub endpoint: String,
+    pub params: Vec<(String, String)>,
+    /// The `Range` header, if there was one
+    pub range: Option<String>,
+}
+
+impl SeenRequest {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct State {
+    config: MockConfig,
+    library: Library,
+    requests: Mutex<Vec<SeenRequest>>,
+    /// Statuses to answer the next requests with, instead of answering
+    failures: Mutex<VecDeque<StatusCode>>,
+}
+
+/// Runs until dropped
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<State>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// A server with the default config. Must be called from within a
+    /// tokio runtime.
+    pub async fn start() -> MockServer {
+        Self::start_with(MockConfig::default()).await
+    }
+
+    pub async fn start_with(config: MockConfig) -> MockServer {
+        let state = Arc::new(State {
+            config,
+            library: Library::fixture(),
+            requests: Mutex::new(vec![]),
+            failures: Mutex::new(VecDeque::new()),
+        });
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(&state, req)) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = shutdown_rx.await;
+        }));
+
+        MockServer {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    /// The server's address, which is what people usually configure.
+    /// The API is under `/rest`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn library(&self) -> &Library {
+        &self.state.library
+    }
+
+    /// Every request so far, oldest first
+    pub fn requests(&self) -> Vec<SeenRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Answer the next `count` requests with `status`, and nothing else
+    pub fn fail_next(&self, count: usize, status: u16) {
+        let status = StatusCode::from_u16(status).expect("Bad status code");
+        let mut failures = self.state.failures.lock().unwrap();
+        failures.extend(std::iter::repeat_n(status, count));
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+fn handle(state: &State, req: Request<Body>) -> Response<Body> {
+    let endpoint = match req.uri().path().strip_prefix("/rest/") {
+        Some(endpoint) => endpoint.trim_end_matches(".view").to_owned(),
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    let params = Params(
+        url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect(),
+    );
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|r| r.to_str().ok())
+        .map(str::to_owned);
+
+    state.requests.lock().unwrap().push(SeenRequest {
+        endpoint: endpoint.clone(),
+        params: params.0.clone(),
+        range: range.clone(),
+    });
+
+    if let Some(failure) = state.failures.lock().unwrap().pop_front() {
+        return status(failure);
+    }
+
+    if let Err(failure) = api::authenticate(&state.config, &params) {
+        return subsonic_response(&state.config, Err(failure));
+    }
+
+    match api::respond(&state.library, &state.config, &endpoint, &params) {
+        Reply::Ok(payload) => subsonic_response(&state.config, Ok(payload)),
+        Reply::Failed(failure) => subsonic_response(&state.config, Err(failure)),
+        Reply::Binary { content_type, data } => binary(content_type, data, range.as_deref()),
+        Reply::NotFound => status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn subsonic_response(config: &MockConfig, res: Result<Value, Failure>) -> Response<Body> {
+    let mut body = json!({
+        "status": "ok",
+        "version": "1.16.1",
+        "type": "subsonic-mock",
+        "serverVersion": env!("CARGO_PKG_VERSION"),
+    });
+    if config.open_subsonic {
+        body["openSubsonic"] = true.into();
+    }
+
+    match res {
+        Ok(Value::Object(payload)) => body.as_object_mut().unwrap().extend(payload),
+        Ok(_) => unreachable!("Payloads are objects"),
+        Err(failure) => {
+            body["status"] = "failed".into();
+            body["error"] = json!({ "code": failure.code, "message": failure.message });
+        }
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "subsonic-response": body }).to_string()))
+        .unwrap()
+}
+
+/// A file, or the part of it asked for with `Range: bytes=N-`
+fn binary(content_type: &str, data: &'static [u8], range: Option<&str>) -> Response<Body> {
+    let start = range
+        .and_then(|r| r.strip_prefix("bytes="))
+        .and_then(|r| r.strip_suffix('-'))
+        .and_then(|start| start.parse::<usize>().ok());
+
+    let res = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "bytes");
+    match start {
+        Some(start) if start < data.len() => res
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {start}-{}/{}", data.len() - 1, data.len()),
+            )
+            .body(Body::from(&data[start..])),
+        Some(_) => res
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .body(Body::empty()),
+        None => res.body(Body::from(data)),
+    }
+    .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn get(url: &str, range: Option<&str>) -> Response<hyper::body::Bytes> {
+        let mut req = Request::get(url);
+        if let Some(range) = range {
+            req = req.header(header::RANGE, range);
+        }
+        let res = hyper::Client::new()
+            .request(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        Response::from_parts(parts, hyper::body::to_bytes(body).await.unwrap())
+    }
+
+    fn rest(server: &MockServer, endpoint: &str, query: &str) -> String {
+        format!(
+            "{}/rest/{endpoint}?u={USERNAME}&p={PASSWORD}&v=1.16.1&c=test&f=json&{query}",
+            server.url()
+        )
+    }
+
+    #[tokio::test]
+    async fn answers_and_records_requests() {
+        let server = MockServer::start().await;
+
+        let res = get(&rest(&server, "ping", ""), None).await;
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["subsonic-response"]["status"], "ok");
+
+        let res = get(
+            &format!("{}/rest/ping.view?u=alice&p=wrong", server.url()),
+            None,
+        )
+        .await;
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["subsonic-response"]["error"]["code"], 40);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.endpoint == "ping"));
+        assert_eq!(requests[1].param("p"), Some("wrong"));
+    }
+
+    #[tokio::test]
+    async fn fails_when_told_to() {
+        let server = MockServer::start().await;
+        server.fail_next(2, 503);
+
+        for expected in [503, 503, 200] {
+            let res = get(&rest(&server, "ping", ""), None).await;
+            assert_eq!(res.status().as_u16(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_ranges() {
+        let server = MockServer::start().await;
+        let data = server.library().song("tr-1").unwrap().data();
+
+        let res = get(&rest(&server, "stream", "id=tr-1"), None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(&res.body()[..], data);
+
+        let res = get(&rest(&server, "stream", "id=tr-1"), Some("bytes=100-")).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&res.body()[..], &data[100..]);
+        assert_eq!(
+            res.headers()[header::CONTENT_RANGE],
+            format!("bytes 100-{}/{}", data.len() - 1, data.len())
+        );
+        assert_eq!(server.requests()[1].range.as_deref(), Some("bytes=100-"));
+
+        let res = get(&rest(&server, "stream", "id=tr-1"), Some("bytes=99999999-")).await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+}
@@ -0,0 +1,223 @@
+//! The library the mock server serves, from `fixtures/library.json`
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const LIBRARY_JSON: &str = include_str!("../fixtures/library.json");
+
+/// Every album, artist and song shares this cover
+pub const COVER_PNG: &[u8] = include_bytes!("../fixtures/cover.png");
+
+/// The audio files songs can point at, with their sample rate and
+/// channel count. Each is half a second of sine wave.
+const AUDIO_FILES: &[(&str, &[u8], u32, u32)] = &[
+    (
+        "tone-44100-mono.flac",
+        include_bytes!("../fixtures/tone-44100-mono.flac"),
+        44100,
+        1,
+    ),
+    (
+        "tone-48000-stereo.flac",
+        include_bytes!("../fixtures/tone-48000-stereo.flac"),
+        48000,
+        2,
+    ),
+];
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Library {
+    pub music_folders: Vec<MusicFolder>,
+    pub artists: Vec<Artist>,
+    pub albums: Vec<Album>,
+    pub songs: Vec<Song>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MusicFolder {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    pub music_folder_id: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    pub artist_id: String,
+    pub year: i32,
+    pub genre: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Song {
+    pub id: String,
+    pub album_id: String,
+    pub title: String,
+    pub track: i32,
+    /// One of the files in `fixtures/`
+    pub file: String,
+}
+
+impl Song {
+    /// The FLAC file this song streams as
+    pub fn data(&self) -> &'static [u8] {
+        audio_file(&self.file).1
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        audio_file(&self.file).2
+    }
+
+    pub fn channels(&self) -> u32 {
+        audio_file(&self.file).3
+    }
+}
+
+fn audio_file(name: &str) -> &'static (&'static str, &'static [u8], u32, u32) {
+    AUDIO_FILES
+        .iter()
+        .find(|(file, ..)| *file == name)
+        .unwrap_or_else(|| panic!("No fixture called {name}"))
+}
+
+impl Library {
+    pub fn fixture() -> Library {
+        serde_json::from_str(LIBRARY_JSON).expect("Bad fixtures/library.json")
+    }
+
+    pub fn artist(&self, id: &str) -> Option<&Artist> {
+        self.artists.iter().find(|a| a.id == id)
+    }
+
+    pub fn album(&self, id: &str) -> Option<&Album> {
+        self.albums.iter().find(|a| a.id == id)
+    }
+
+    pub fn song(&self, id: &str) -> Option<&Song> {
+        self.songs.iter().find(|s| s.id == id)
+    }
+
+    pub fn albums_by(&self, artist_id: &str) -> impl Iterator<Item = &Album> {
+        let artist_id = artist_id.to_owned();
+        self.albums.iter().filter(move |a| a.artist_id == artist_id)
+    }
+
+    pub fn songs_on(&self, album_id: &str) -> impl Iterator<Item = &Song> {
+        let album_id = album_id.to_owned();
+        self.songs.iter().filter(move |s| s.album_id == album_id)
+    }
+
+    pub fn album_of(&self, song: &Song) -> &Album {
+        self.album(&song.album_id).expect("Song on a missing album")
+    }
+
+    pub fn artist_of(&self, album: &Album) -> &Artist {
+        self.artist(&album.artist_id)
+            .expect("Album by a missing artist")
+    }
+
+    pub fn folder_of(&self, album: &Album) -> i64 {
+        self.artist_of(album).music_folder_id
+    }
+
+    // How things look in responses. Ids of the cover art are whatever
+    // they're the cover of.
+
+    /// An `artist` in indexes and search2 results
+    pub(crate) fn artist_json(&self, artist: &Artist) -> Value {
+        json!({
+            "id": artist.id,
+            "name": artist.name,
+        })
+    }
+
+    pub(crate) fn artist_id3_json(&self, artist: &Artist) -> Value {
+        json!({
+            "id": artist.id,
+            "name": artist.name,
+            "coverArt": artist.id,
+            "albumCount": self.albums_by(&artist.id).count(),
+        })
+    }
+
+    /// An album as a directory `Child`
+    pub(crate) fn album_child_json(&self, album: &Album) -> Value {
+        let artist = self.artist_of(album);
+        json!({
+            "id": album.id,
+            "parent": artist.id,
+            "isDir": true,
+            "title": album.name,
+            "album": album.name,
+            "artist": artist.name,
+            "artistId": artist.id,
+            "year": album.year,
+            "genre": album.genre,
+            "coverArt": album.id,
+        })
+    }
+
+    pub(crate) fn album_id3_json(&self, album: &Album) -> Value {
+        let artist = self.artist_of(album);
+        let songs = self.songs_on(&album.id).count();
+        json!({
+            "id": album.id,
+            "name": album.name,
+            "artist": artist.name,
+            "artistId": artist.id,
+            "coverArt": album.id,
+            "songCount": songs,
+            "duration": songs,
+            "created": "2024-01-01T00:00:00Z",
+            "year": album.year,
+            "genre": album.genre,
+        })
+    }
+
+    /// A song as a `Child`. OpenSubsonic servers also say what the
+    /// audio is like.
+    pub(crate) fn song_json(&self, song: &Song, open_subsonic: bool) -> Value {
+        let album = self.album_of(song);
+        let artist = self.artist_of(album);
+        let mut child = json!({
+            "id": song.id,
+            "parent": album.id,
+            "isDir": false,
+            "title": song.title,
+            "album": album.name,
+            "artist": artist.name,
+            "track": song.track,
+            "year": album.year,
+            "genre": album.genre,
+            "coverArt": album.id,
+            "size": song.data().len(),
+            "contentType": "audio/flac",
+            "suffix": "flac",
+            "duration": 1,
+            "bitRate": song.data().len() * 8 * 2 / 1000,
+            "path": format!("{}/{}/{:02} - {}.flac", artist.name, album.name, song.track, song.title),
+            "discNumber": 1,
+            "albumId": album.id,
+            "artistId": artist.id,
+            "type": "music",
+            "isVideo": false,
+        });
+        if open_subsonic {
+            child["samplingRate"] = song.sample_rate().into();
+            child["channelCount"] = song.channels().into();
+            child["bitDepth"] = 16.into();
+        }
+        child
+    }
+}
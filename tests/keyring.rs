@@ -0,0 +1,146 @@
+//! The keyring test runs in a binary of its own, since it points the
+//! session bus and the config and data directories somewhere private,
+//! for the whole process.
+
+use std::{
+    io::ErrorKind,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use secret_service::{EncryptionType, SecretService};
+
+#[allow(dead_code)]
+#[path = "../src/config.rs"]
+mod config;
+#[allow(dead_code)]
+#[path = "../src/keyring.rs"]
+mod keyring;
+
+use config::{Config, Profile};
+use keyring::{resolve_password, KeyringEntry};
+
+/// A private session bus with gnome-keyring's Secret Service
+/// implementation on it, torn down on drop.
+struct PrivateKeyring {
+    bus: Child,
+    keyring: Child,
+    _home: tempfile::TempDir,
+}
+
+impl PrivateKeyring {
+    /// None if dbus-daemon or gnome-keyring-daemon isn't installed
+    async fn start() -> Option<Self> {
+        let home = tempfile::tempdir().unwrap();
+        let mut bus = spawn(
+            Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped()),
+        )?;
+
+        // Which it prints once it's listening
+        let mut address = String::new();
+        {
+            use std::io::BufRead;
+            let stdout = bus.stdout.take().unwrap();
+            std::io::BufReader::new(stdout)
+                .read_line(&mut address)
+                .unwrap();
+        }
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+        std::env::set_var("XDG_CONFIG_HOME", home.path().join("config"));
+        std::env::set_var("XDG_DATA_HOME", home.path().join("data"));
+
+        // An empty password unlocks (creating, if necessary) the
+        // login keyring without a prompt.
+        let keyring = spawn(
+            Command::new("gnome-keyring-daemon")
+                .args(["--foreground", "--unlock", "--components=secrets"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null()),
+        );
+        let Some(mut keyring) = keyring else {
+            let _ = bus.kill();
+            return None;
+        };
+        drop(keyring.stdin.take());
+        let private = PrivateKeyring {
+            bus,
+            keyring,
+            _home: home,
+        };
+
+        // Until the daemon's taken its name on the bus
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while SecretService::connect(EncryptionType::Dh).await.is_err() {
+            assert!(Instant::now() < deadline, "The keyring never turned up");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        Some(private)
+    }
+}
+
+/// None if the program isn't installed
+fn spawn(command: &mut Command) -> Option<Child> {
+    match command.spawn() {
+        Ok(child) => Some(child),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("Skipped: {:?} isn't installed", command.get_program());
+            None
+        }
+        Err(e) => panic!("Couldn't start {:?}: {e}", command.get_program()),
+    }
+}
+
+impl Drop for PrivateKeyring {
+    fn drop(&mut self) {
+        let _ = self.keyring.kill();
+        let _ = self.bus.kill();
+    }
+}
+
+#[tokio::test]
+async fn plaintext_password_migrates_into_keyring() {
+    let Some(_keyring) = PrivateKeyring::start().await else {
+        return;
+    };
+
+    let mut profile = Profile {
+        name: "home".to_string(),
+        url: "https://music.example.com".to_string(),
+        username: "alice".to_string(),
+        auth: Default::default(),
+        password: Some("hunter2".to_string()),
+        keyring: None,
+        music_folder_ids: vec![],
+        album_sort: None,
+        album_years: None,
+        connection: Default::default(),
+    };
+
+    assert_eq!(resolve_password(&mut profile).await.unwrap(), "hunter2");
+    assert_eq!(profile.password, None);
+    assert_eq!(
+        profile.keyring,
+        Some(KeyringEntry {
+            server: "https://music.example.com".to_string(),
+            username: "alice".to_string(),
+        })
+    );
+
+    // The rewritten config file has no trace of the password
+    let saved = std::fs::read_to_string(Config::path()).unwrap();
+    assert!(!saved.contains("hunter2"), "{saved}");
+
+    // ...and reading it back resolves through the keyring
+    let mut reloaded = Config::load().unwrap().profile("home").unwrap().clone();
+    assert_eq!(resolve_password(&mut reloaded).await.unwrap(), "hunter2");
+
+    // A plaintext one that's crept back in goes again
+    reloaded.password = Some("hunter2".to_string());
+    reloaded.save().unwrap();
+    assert_eq!(resolve_password(&mut reloaded).await.unwrap(), "hunter2");
+    assert_eq!(reloaded.password, None);
+    let saved = std::fs::read_to_string(Config::path()).unwrap();
+    assert!(!saved.contains("hunter2"), "{saved}");
+}
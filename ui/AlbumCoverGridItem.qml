@@ -8,9 +8,11 @@ Item {
     property alias title: title.text
     property alias artist: artist.text
     property string artist_id
+    property bool pinned
 
     signal coverDoubleClicked
     signal artistClicked
+    signal pinClicked
 
     AlbumCover {
         id: cover
@@ -23,6 +25,22 @@ Item {
         onCoverDoubleClicked: root.coverDoubleClicked()
     }
 
+    Controls.ToolButton {
+        anchors {
+            top: cover.top
+            right: cover.right
+        }
+        icon.name: "emblem-downloads"
+        checked: root.pinned
+        // Shown all the time once it's pinned, so you can see what's
+        // there for listening offline
+        opacity: root.pinned || hovered ? 1 : 0.3
+        onClicked: root.pinClicked()
+
+        Controls.ToolTip.visible: hovered
+        Controls.ToolTip.text: root.pinned ? "Pinned for offline listening" : "Pin for offline listening"
+    }
+
     Controls.Label {
         id: title
 
@@ -0,0 +1,42 @@
+import QtQuick 2.9
+import QtQuick.Controls 2.2 as Controls
+import QtQuick.Layouts 1.3
+import org.kde.kirigami 2.12 as Kirigami
+
+Controls.Pane {
+    id: root
+
+    property string artistName
+
+    signal playTopSongs
+    signal shuffleAll
+    signal close
+
+    RowLayout {
+        anchors.fill: parent
+
+        Kirigami.Heading {
+            Layout.fillWidth: true
+            level: 2
+            text: root.artistName
+            elide: Text.ElideRight
+        }
+
+        Controls.Button {
+            icon.name: "media-playback-start"
+            text: "Play Top Songs"
+            onClicked: root.playTopSongs()
+        }
+
+        Controls.Button {
+            icon.name: "media-playlist-shuffle"
+            text: "Shuffle All"
+            onClicked: root.shuffleAll()
+        }
+
+        Controls.ToolButton {
+            icon.name: "window-close"
+            onClicked: root.close()
+        }
+    }
+}
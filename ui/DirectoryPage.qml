@@ -0,0 +1,66 @@
+import QtQuick 2.9
+import QtQuick.Controls 2.2 as Controls
+import QtQuick.Layouts 1.3
+import org.kde.kirigami 2.12 as Kirigami
+
+import io.github.mullr.tinysonic 1.0
+
+Kirigami.ScrollablePage {
+    id: root
+    title: Directories.title
+
+    actions.main: Kirigami.Action {
+        icon.name: "go-up"
+        text: "Up"
+        enabled: Directories.can_go_up
+        onTriggered: Directories.up()
+    }
+
+    ListView {
+        model: Directories
+
+        delegate: Kirigami.SwipeListItem {
+            id: item
+
+            onClicked: {
+                if (model.is_dir) {
+                    Directories.open(index)
+                }
+            }
+
+            RowLayout {
+                Kirigami.Icon {
+                    Layout.preferredHeight: Kirigami.Units.iconSizes.smallMedium
+                    Layout.preferredWidth: Kirigami.Units.iconSizes.smallMedium
+                    source: model.is_dir ? "folder" : "audio-x-generic"
+                }
+
+                Controls.Label {
+                    Layout.fillWidth: true
+                    text: model.name
+                    elide: Text.ElideRight
+                }
+
+                Controls.Label {
+                    visible: !model.is_dir
+                    text: model.artist
+                    elide: Text.ElideRight
+                    opacity: 0.7
+                }
+            }
+
+            actions: [
+                Kirigami.Action {
+                    icon.name: "media-playback-start"
+                    text: "Play"
+                    onTriggered: Directories.play(index)
+                },
+                Kirigami.Action {
+                    icon.name: "list-add"
+                    text: "Enqueue"
+                    onTriggered: Directories.enqueue(index)
+                }
+            ]
+        }
+    }
+}
@@ -11,6 +11,8 @@ Controls.Pane {
     property string currentArtist
     property string currentAlbum
     property string playState: "play"
+    // "bit_perfect", "converted", or "" before anything's played
+    property string outputPath
 
     signal play
     signal pause
@@ -63,6 +65,15 @@ Controls.Pane {
                 Layout.fillWidth: true
                 text: root.currentAlbum
             }
+
+            Controls.Label {
+                visible: root.outputPath !== ""
+                font: Kirigami.Theme.smallFont
+                color: root.outputPath === "bit_perfect"
+                    ? Kirigami.Theme.positiveTextColor
+                    : Kirigami.Theme.disabledTextColor
+                text: root.outputPath === "bit_perfect" ? "Bit-perfect" : "Not bit-perfect"
+            }
         }
 
         Controls.Button {
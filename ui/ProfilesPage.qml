@@ -0,0 +1,69 @@
+import QtQuick 2.9
+import QtQuick.Controls 2.2 as Controls
+import QtQuick.Layouts 1.3
+import org.kde.kirigami 2.12 as Kirigami
+
+import io.github.mullr.tinysonic 1.0
+
+Kirigami.ScrollablePage {
+    id: root
+    title: "Servers"
+
+    signal addProfile
+
+    actions.main: Kirigami.Action {
+        icon.name: "list-add"
+        text: "Add Server"
+        onTriggered: root.addProfile()
+    }
+
+    header: Kirigami.InlineMessage {
+        type: Kirigami.MessageType.Error
+        visible: Profiles.error !== ""
+        text: Profiles.error
+    }
+
+    ListView {
+        model: Profiles
+
+        delegate: Kirigami.SwipeListItem {
+            onClicked: Profiles.switch_to(index)
+
+            RowLayout {
+                Kirigami.Icon {
+                    Layout.preferredHeight: Kirigami.Units.iconSizes.smallMedium
+                    Layout.preferredWidth: Kirigami.Units.iconSizes.smallMedium
+                    source: model.is_current ? "media-playback-start" : "network-server"
+                }
+
+                Controls.Label {
+                    Layout.fillWidth: true
+                    text: model.is_default ? model.name + " (default)" : model.name
+                    font.bold: model.is_current
+                    elide: Text.ElideRight
+                }
+
+                Controls.Label {
+                    text: model.username + " @ " + model.url
+                    elide: Text.ElideRight
+                    opacity: 0.7
+                }
+            }
+
+            actions: [
+                Kirigami.Action {
+                    icon.name: "starred-symbolic"
+                    text: "Open at Startup"
+                    enabled: !model.is_default
+                    onTriggered: Profiles.make_default(index)
+                },
+                Kirigami.Action {
+                    icon.name: "list-remove"
+                    text: "Remove"
+                    enabled: !model.is_current
+                    onTriggered: Profiles.remove(index)
+                }
+            ]
+        }
+    }
+}
@@ -0,0 +1,80 @@
+import QtQuick 2.9
+import QtQuick.Controls 2.2 as Controls
+import QtQuick.Layouts 1.3
+import org.kde.kirigami 2.12 as Kirigami
+
+import io.github.mullr.tinysonic 1.0
+
+Kirigami.Page {
+    id: root
+    title: "Add a server"
+
+    ColumnLayout {
+        anchors.centerIn: parent
+        width: Math.min(parent.width, Kirigami.Units.gridUnit * 30)
+
+        Kirigami.InlineMessage {
+            Layout.fillWidth: true
+            type: Kirigami.MessageType.Error
+            visible: Setup.error !== ""
+            text: Setup.error
+        }
+
+        Kirigami.FormLayout {
+            Layout.fillWidth: true
+
+            Controls.TextField {
+                id: nameField
+                Kirigami.FormData.label: "Name:"
+                placeholderText: "Defaults to the server's host name"
+                text: Setup.name
+                onTextEdited: Setup.name = text
+                onAccepted: urlField.forceActiveFocus()
+            }
+
+            Controls.TextField {
+                id: urlField
+                Kirigami.FormData.label: "Server URL:"
+                placeholderText: "https://music.example.com"
+                inputMethodHints: Qt.ImhUrlCharactersOnly | Qt.ImhNoAutoUppercase
+                text: Setup.url
+                onTextEdited: Setup.url = text
+                onAccepted: usernameField.forceActiveFocus()
+            }
+
+            Controls.TextField {
+                id: usernameField
+                Kirigami.FormData.label: "Username:"
+                inputMethodHints: Qt.ImhNoAutoUppercase
+                text: Setup.username
+                onTextEdited: Setup.username = text
+                onAccepted: passwordField.forceActiveFocus()
+            }
+
+            Kirigami.PasswordField {
+                id: passwordField
+                Kirigami.FormData.label: "Password:"
+                text: Setup.password
+                onTextEdited: Setup.password = text
+                onAccepted: Setup.check_and_save()
+            }
+
+            RowLayout {
+                Controls.Button {
+                    text: "Connect"
+                    icon.name: "network-connect"
+                    enabled: !Setup.checking && urlField.text !== "" && usernameField.text !== ""
+                    onClicked: Setup.check_and_save()
+                }
+
+                Controls.BusyIndicator {
+                    Layout.preferredHeight: Kirigami.Units.iconSizes.medium
+                    running: Setup.checking
+                    visible: running
+                }
+            }
+        }
+    }
+
+    Component.onCompleted: nameField.forceActiveFocus()
+}
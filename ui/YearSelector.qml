@@ -0,0 +1,72 @@
+import QtQuick 2.9
+import QtQuick.Controls 2.2 as Controls
+import QtQuick.Layouts 1.3
+import org.kde.kirigami 2.12 as Kirigami
+
+Controls.Pane {
+    id: root
+
+    property int fromYear
+    property int toYear
+    property int earliestYear: 1900
+    readonly property int firstDecade: Math.floor(earliestYear / 10) * 10
+    property int lastYear: new Date().getFullYear()
+
+    signal rangeSelected(int fromYear, int toYear)
+
+    ColumnLayout {
+        anchors.fill: parent
+
+        RowLayout {
+            Layout.fillWidth: true
+
+            Repeater {
+                model: Math.floor((root.lastYear - root.firstDecade) / 10) + 1
+
+                Controls.Button {
+                    property int decade: root.firstDecade + index * 10
+
+                    text: decade + "s"
+                    flat: true
+                    checkable: true
+                    checked: root.fromYear === decade && root.toYear === decade + 9
+                    onClicked: root.rangeSelected(decade, decade + 9)
+                }
+            }
+        }
+
+        RowLayout {
+            Layout.fillWidth: true
+
+            Controls.Label {
+                text: range.first.value.toFixed(0)
+            }
+
+            Controls.RangeSlider {
+                id: range
+                Layout.fillWidth: true
+                from: root.firstDecade
+                to: root.lastYear
+                stepSize: 1
+                snapMode: Controls.RangeSlider.SnapAlways
+                first.value: root.fromYear
+                second.value: root.toYear
+
+                first.onPressedChanged: {
+                    if (!first.pressed) {
+                        root.rangeSelected(first.value, second.value)
+                    }
+                }
+                second.onPressedChanged: {
+                    if (!second.pressed) {
+                        root.rangeSelected(first.value, second.value)
+                    }
+                }
+            }
+
+            Controls.Label {
+                text: range.second.value.toFixed(0)
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 import QtQuick 2.9
+import QtQml 2.2
 import QtQuick.Controls 2.2 as Controls
 import QtQuick.Layouts 1.3
 import QtGraphicalEffects 1.0
@@ -9,26 +10,83 @@ import io.github.mullr.tinysonic 1.0
 Kirigami.ApplicationWindow {
     id: root
     title: "tinysonic"
-    pageStack.initialPage: albums_page
+    pageStack.initialPage: Profiles.current !== "" ? albums_page : setup_page
 
-    Component.onCompleted: {
+    function start() {
         Albums.fetch()
+        MusicFolders.fetch()
+    }
+
+    Component.onCompleted: {
+        if (Profiles.current !== "") {
+            start()
+            return
+        }
+        // There are servers, but the default one couldn't be opened
+        Profiles.fetch()
+        if (Profiles.rowCount() > 0) {
+            root.pageStack.clear()
+            root.pageStack.push(profiles_page)
+        }
+    }
+
+    // A new profile is in use; start over on its albums
+    Connections {
+        target: Profiles
+        function onCurrentChanged() {
+            albums_page.artistId = ""
+            root.pageStack.clear()
+            root.pageStack.push(albums_page)
+            root.start()
+        }
+    }
+
+    SetupPage {
+        id: setup_page
+        visible: false
+    }
+
+    ProfilesPage {
+        id: profiles_page
+        visible: false
+        onAddProfile: root.pageStack.push(setup_page)
+    }
+
+    DirectoryPage {
+        id: directory_page
+        visible: false
     }
 
     Kirigami.ScrollablePage {
         id: albums_page
         leftPadding: 0
 
+        // The artist being viewed, if the album list came from
+        // clicking on one
+        property string artistId
+        property string artistName
+
         titleDelegate: RowLayout {
             spacing: Kirigami.Units.smallSpacing
             Layout.fillHeight: true
             Layout.fillWidth: true
 
             Controls.ComboBox {
+                id: sortOrderBox
                 Layout.bottomMargin: 2
                 Layout.fillHeight: true
                 textRole: "text"
-                currentIndex: 2 // Random, aligns with model initial value
+                // Albums remembers it from last time
+                currentIndex: indexOf(Albums.sort_order)
+
+                function indexOf(value) {
+                    for (var i = 0; i < sortOrderItems.count; i++) {
+                        if (sortOrderItems.get(i).value === value) {
+                            return i
+                        }
+                    }
+                    return -1
+                }
 
                 model: ListModel {
                     id: sortOrderItems
@@ -38,10 +96,123 @@ Kirigami.ApplicationWindow {
                     ListElement { text: "Recently Played"; value: "recent"}
                     ListElement { text: "Recently Added"; value: "newest"}
                     ListElement { text: "Most Played"; value: "frequent"}
+                    ListElement { text: "By Year"; value: "by_year"}
+                }
+
+                onActivated: {
+                    Albums.sort_order = sortOrderItems.get(index).value
+                }
+
+                // Picking one unbinds currentIndex, and another
+                // profile can be sorted differently
+                Connections {
+                    target: Albums
+                    function onSort_orderChanged() {
+                        sortOrderBox.currentIndex = sortOrderBox.indexOf(Albums.sort_order)
+                    }
+                }
+            }
+
+            Controls.ToolButton {
+                // Only worth showing if there's something to choose from
+                visible: folderItems.count > 1
+                icon.name: "folder-sound"
+                text: "Folders"
+                onClicked: folderMenu.popup()
+
+                Controls.Menu {
+                    id: folderMenu
+
+                    Instantiator {
+                        id: folderItems
+                        model: MusicFolders
+                        delegate: Controls.MenuItem {
+                            checkable: true
+                            checked: model.selected
+                            text: model.name
+                            onToggled: {
+                                model.selected = checked
+                                Albums.fetch()
+                            }
+                        }
+                        onObjectAdded: folderMenu.insertItem(index, object)
+                        onObjectRemoved: folderMenu.removeItem(object)
+                    }
+                }
+            }
+
+            Controls.ToolButton {
+                icon.name: "audio-speakers-symbolic"
+                text: "Output"
+                onClicked: {
+                    OutputDevices.fetch()
+                    outputMenu.popup()
+                }
+
+                Controls.Menu {
+                    id: outputMenu
+
+                    Instantiator {
+                        model: OutputDevices
+                        delegate: Controls.MenuItem {
+                            checkable: true
+                            checked: model.is_current
+                            text: model.description
+                            onTriggered: OutputDevices.select(index)
+                        }
+                        onObjectAdded: outputMenu.insertItem(index, object)
+                        onObjectRemoved: outputMenu.removeItem(object)
+                    }
+                }
+            }
+
+            Controls.ToolButton {
+                icon.name: "radio"
+                text: "Radio"
+                highlighted: Player.radio !== ""
+                onClicked: radioMenu.popup()
+
+                Controls.Menu {
+                    id: radioMenu
+
+                    Controls.MenuItem {
+                        text: "Random"
+                        onTriggered: Player.start_random_radio("", 0, 0)
+                    }
+                    Controls.MenuItem {
+                        visible: Albums.sort_order === "by_year"
+                        height: visible ? implicitHeight : 0
+                        text: "Random from " + Albums.from_year + " to " + Albums.to_year
+                        onTriggered: Player.start_random_radio("", Albums.from_year, Albums.to_year)
+                    }
+                    Controls.MenuItem {
+                        enabled: Player.current_track_name !== ""
+                        text: "Similar to the current track"
+                        onTriggered: Player.start_similar_radio()
+                    }
+                    Controls.MenuItem {
+                        enabled: Player.radio !== ""
+                        text: "Stop radio"
+                        onTriggered: Player.stop_radio()
+                    }
                 }
+            }
+
+            Controls.ToolButton {
+                icon.name: "folder"
+                text: "Files"
+                onClicked: {
+                    Directories.fetch()
+                    root.pageStack.push(directory_page)
+                }
+            }
 
-                onCurrentIndexChanged: {
-                    Albums.sort_order = sortOrderItems.get(currentIndex).value
+            Controls.ToolButton {
+                icon.name: "network-server"
+                text: "Servers"
+                onClicked: {
+                    Profiles.fetch()
+                    root.pageStack.push(profiles_page)
                 }
             }
 
@@ -57,7 +228,70 @@ Kirigami.ApplicationWindow {
                 Layout.fillWidth: true
                 autoAccept: false
                 text: Albums.search
-                onAccepted: Albums.search = searchField.text
+                onAccepted: {
+                    albums_page.artistId = ""
+                    Albums.search = searchField.text
+                }
+            }
+        }
+
+        header: ColumnLayout {
+            spacing: 0
+
+            Kirigami.InlineMessage {
+                Layout.fillWidth: true
+                type: Kirigami.MessageType.Warning
+                visible: Player.connectivity !== ""
+                text: Player.connectivity === "retrying"
+                    ? "The server isn't answering. Retrying…"
+                    : "The server can't be reached."
+            }
+
+            Kirigami.InlineMessage {
+                Layout.fillWidth: true
+                type: Kirigami.MessageType.Error
+                visible: Albums.error !== ""
+                text: Albums.error
+                actions: Kirigami.Action {
+                    icon.name: "view-refresh"
+                    text: "Retry"
+                    onTriggered: Albums.fetch()
+                }
+            }
+
+            Kirigami.InlineMessage {
+                Layout.fillWidth: true
+                type: Kirigami.MessageType.Warning
+                visible: Player.error !== ""
+                text: Player.error
+                showCloseButton: true
+                onVisibleChanged: {
+                    // The close button only hides it
+                    if (!visible && Player.error !== "") {
+                        Player.dismiss_error()
+                    }
+                }
+            }
+
+            YearSelector {
+                Layout.fillWidth: true
+                visible: Albums.sort_order === "by_year"
+                fromYear: Albums.from_year
+                toYear: Albums.to_year
+                earliestYear: Albums.earliest_year
+                onRangeSelected: Albums.set_year_range(fromYear, toYear)
+            }
+
+            ArtistBar {
+                Layout.fillWidth: true
+                visible: albums_page.artistId !== ""
+                artistName: albums_page.artistName
+                onPlayTopSongs: Player.play_artist(albums_page.artistId, albums_page.artistName)
+                onShuffleAll: Player.shuffle_artist(albums_page.artistId)
+                onClose: {
+                    albums_page.artistId = ""
+                    Albums.search = ""
+                }
             }
         }
 
@@ -75,6 +309,7 @@ Kirigami.ApplicationWindow {
                 currentArtist: Player.current_artist
                 currentAlbum: Player.current_album
                 playState: Player.play_state
+                outputPath: Player.output_path
 
                 onPlay: Player.play()
                 onPause: Player.pause()
@@ -118,8 +353,15 @@ Kirigami.ApplicationWindow {
                         source: cover_url
                         title: model.name
                         artist: model.artist
+                        artist_id: model.artist_id
+                        pinned: model.pinned
                         onCoverDoubleClicked: Player.play_album(model.album_id)
-                        onArtistClicked: Albums.search = model.artist
+                        onPinClicked: model.pinned = !model.pinned
+                        onArtistClicked: {
+                            albums_page.artistId = model.artist_id
+                            albums_page.artistName = model.artist
+                            Albums.search = model.artist
+                        }
                     }
                 }
             }